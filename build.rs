@@ -1,4 +1,19 @@
+use std::path::PathBuf;
+
 fn main() {
-    tonic_build::compile_protos("proto/worker.proto")
+    tonic_build::configure()
+        .type_attribute("worker.Stats", "#[derive(serde::Serialize)]")
+        .compile(&["proto/worker.proto"], &["proto"])
+        .unwrap_or_else(|e| panic!("Failed to compile protos {:?}", e));
+
+    // The public API reuses the worker's messages. It is generated in its own
+    // directory, so that the code generated for its import of `worker.proto`
+    // does not overwrite the worker's
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap()).join("api");
+    std::fs::create_dir_all(&out_dir).unwrap();
+    tonic_build::configure()
+        .out_dir(out_dir)
+        .extern_path(".worker", "crate::rpc")
+        .compile(&["proto/autodep.proto"], &["proto"])
         .unwrap_or_else(|e| panic!("Failed to compile protos {:?}", e));
 }
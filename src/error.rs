@@ -0,0 +1,334 @@
+//! Errors that can occur while serving an inference request. The same error
+//! type is used by the model runner, the worker, the manager and the HTTP
+//! server, so that the cause of a failure survives the RPC hop from a worker
+//! to the manager, and can be reported to clients with a matching status code
+
+use crate::rpc;
+use std::time::Duration;
+use tonic::metadata::MetadataValue;
+use tonic::Code;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// gRPC metadata key carrying an error's machine-readable code from a worker
+/// to the manager
+const CODE_METADATA: &str = "autodep-error-code";
+
+#[derive(Debug)]
+pub enum Error {
+    /// The request was malformed
+    InvalidInput(String),
+
+    /// The request body is too large
+    PayloadTooLarge(String),
+
+    /// The input data is in a format that cannot be decoded
+    UnsupportedMediaType(String),
+
+    /// The model cannot compute the requested type of inference
+    UnsupportedInference(String),
+
+    /// The request lacks valid credentials
+    Unauthorized(String),
+
+    /// The request's credentials do not allow it
+    Forbidden(String),
+
+    /// The requested resource does not exist
+    NotFound(String),
+
+    /// The client has made too many requests. It can try again after the
+    /// given delay
+    RateLimited(String, Duration),
+
+    /// All workers are busy
+    Busy,
+
+    /// The request did not finish before its deadline
+    Timeout(Duration),
+
+    /// A worker failed to compute inference
+    Worker(Box<tonic::Status>),
+
+    /// The request still failed after being retried this many times
+    Retries(u32, Box<Error>),
+
+    /// Any other error
+    Other(anyhow::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidInput(msg) => write!(f, "invalid input: {msg}"),
+            Error::PayloadTooLarge(msg) => write!(f, "payload too large: {msg}"),
+            Error::UnsupportedMediaType(msg) => write!(f, "unsupported media type: {msg}"),
+            Error::UnsupportedInference(msg) => write!(f, "unsupported inference: {msg}"),
+            Error::Unauthorized(msg) => write!(f, "unauthorized: {msg}"),
+            Error::Forbidden(msg) => write!(f, "forbidden: {msg}"),
+            Error::NotFound(msg) => write!(f, "not found: {msg}"),
+            Error::RateLimited(msg, _) => write!(f, "rate limited: {msg}"),
+            Error::Busy => write!(f, "all workers are busy"),
+            Error::Timeout(t) => write!(f, "request timed out after {} ms", t.as_millis()),
+            Error::Worker(status) => write!(f, "worker error: {}", status.message()),
+            Error::Retries(n, err) => write!(f, "{err} (after {n} retries)"),
+            Error::Other(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    /// A machine-readable code for the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::InvalidInput(_) => "invalid_input",
+            Error::PayloadTooLarge(_) => "payload_too_large",
+            Error::UnsupportedMediaType(_) => "unsupported_media_type",
+            Error::UnsupportedInference(_) => "unsupported_inference",
+            Error::Unauthorized(_) => "unauthorized",
+            Error::Forbidden(_) => "forbidden",
+            Error::NotFound(_) => "not_found",
+            Error::RateLimited(..) => "rate_limited",
+            Error::Busy => "workers_busy",
+            Error::Timeout(_) => "timeout",
+            Error::Worker(status) if status.code() == Code::Unavailable => "worker_unavailable",
+            Error::Worker(_) => "worker_error",
+            Error::Retries(_, err) => err.code(),
+            Error::Other(_) => "internal",
+        }
+    }
+
+    /// Whether the request can safely be retried on another worker. Inference
+    /// is idempotent, so any failure to reach the worker (a refused or dropped
+    /// connection) is retryable, but errors computed by the worker are not.
+    /// Transport failures are raised by the client, so unlike statuses sent
+    /// by the worker, they carry the error they were made from
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Worker(status) => {
+                status.code() == Code::Unavailable || std::error::Error::source(&**status).is_some()
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the error was caused by the worker rather than by the request
+    pub fn is_worker_fault(&self) -> bool {
+        matches!(self, Error::Worker(_) | Error::Timeout(_))
+    }
+
+    /// The gRPC status code of the error. A request that failed after
+    /// retries has the status of its last failure
+    fn grpc_code(&self) -> Code {
+        match self {
+            Error::InvalidInput(_) | Error::PayloadTooLarge(_) | Error::UnsupportedMediaType(_) => {
+                Code::InvalidArgument
+            }
+            Error::UnsupportedInference(_) => Code::Unimplemented,
+            Error::Unauthorized(_) => Code::Unauthenticated,
+            Error::Forbidden(_) => Code::PermissionDenied,
+            Error::NotFound(_) => Code::NotFound,
+            Error::RateLimited(..) | Error::Busy => Code::ResourceExhausted,
+            Error::Timeout(_) => Code::DeadlineExceeded,
+            Error::Retries(_, err) => err.grpc_code(),
+            Error::Worker(_) | Error::Other(_) => Code::Internal,
+        }
+    }
+
+    /// Translate a status returned by a worker back into an error. An
+    /// `Unauthenticated` status without an error code comes from the worker
+    /// refusing the manager's token, so it is a worker error rather than a
+    /// `401` for the client
+    pub fn from_status(status: tonic::Status, timeout: Duration) -> Error {
+        let code = status
+            .metadata()
+            .get(CODE_METADATA)
+            .and_then(|code| code.to_str().ok());
+        let msg = status.message().to_string();
+
+        match (code, status.code()) {
+            (Some("invalid_input"), _) | (None, Code::InvalidArgument) => Error::InvalidInput(msg),
+            (Some("payload_too_large"), _) => Error::PayloadTooLarge(msg),
+            (Some("unsupported_media_type"), _) => Error::UnsupportedMediaType(msg),
+            (Some("unsupported_inference"), _) | (None, Code::Unimplemented) => {
+                Error::UnsupportedInference(msg)
+            }
+            (Some("unauthorized"), _) => Error::Unauthorized(msg),
+            (Some("forbidden"), _) | (None, Code::PermissionDenied) => Error::Forbidden(msg),
+            (Some("not_found"), _) | (None, Code::NotFound) => Error::NotFound(msg),
+            (Some("rate_limited"), _) => Error::RateLimited(msg, Duration::ZERO),
+            (Some("workers_busy"), _) => Error::Busy,
+            (_, Code::Cancelled | Code::DeadlineExceeded) => Error::Timeout(timeout),
+            _ => Error::Worker(Box::new(status)),
+        }
+    }
+}
+
+impl From<Error> for tonic::Status {
+    fn from(err: Error) -> tonic::Status {
+        let msg = match &err {
+            Error::InvalidInput(msg)
+            | Error::PayloadTooLarge(msg)
+            | Error::UnsupportedMediaType(msg)
+            | Error::UnsupportedInference(msg)
+            | Error::Unauthorized(msg)
+            | Error::Forbidden(msg)
+            | Error::NotFound(msg)
+            | Error::RateLimited(msg, _) => msg.clone(),
+            _ => err.to_string(),
+        };
+
+        let mut status = tonic::Status::new(err.grpc_code(), msg);
+        status
+            .metadata_mut()
+            .insert(CODE_METADATA, MetadataValue::from_static(err.code()));
+        status
+    }
+}
+
+/// A failed task of a batch is sent back as a status of its own, so that its
+/// error survives the RPC hop like that of a single request
+impl From<Error> for rpc::BatchError {
+    fn from(err: Error) -> rpc::BatchError {
+        let code = err.code().into();
+        let status = tonic::Status::from(err);
+        rpc::BatchError {
+            status: status.code() as i32,
+            code,
+            message: status.message().into(),
+        }
+    }
+}
+
+impl From<rpc::BatchError> for tonic::Status {
+    fn from(err: rpc::BatchError) -> tonic::Status {
+        let mut status = tonic::Status::new(Code::from_i32(err.status), err.message);
+        if let Ok(code) = err.code.parse() {
+            status.metadata_mut().insert(CODE_METADATA, code);
+        }
+        status
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(err: anyhow::Error) -> Error {
+        Error::Other(err)
+    }
+}
+
+impl From<config::ConfigError> for Error {
+    fn from(err: config::ConfigError) -> Error {
+        Error::Other(err.into())
+    }
+}
+
+impl From<tch::TchError> for Error {
+    fn from(err: tch::TchError) -> Error {
+        Error::Other(err.into())
+    }
+}
+
+impl From<base64::DecodeError> for Error {
+    fn from(err: base64::DecodeError) -> Error {
+        Error::InvalidInput(format!("invalid base 64: {err}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn worker(status: tonic::Status) -> Error {
+        Error::from_status(status, Duration::from_secs(1))
+    }
+
+    #[test]
+    fn test_retryable() {
+        // The worker could not be reached
+        assert!(worker(tonic::Status::unavailable("connection refused")).is_retryable());
+        let reset = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+        let dropped = tonic::Status::from_error(Box::new(reset));
+        assert_eq!(dropped.code(), Code::Unknown);
+        assert!(worker(dropped).is_retryable());
+
+        // The worker computed an error, which would fail again
+        assert!(!worker(tonic::Status::unknown("model error")).is_retryable());
+        assert!(!worker(tonic::Status::internal("model error")).is_retryable());
+        assert!(!worker(tonic::Status::aborted("aborted")).is_retryable());
+        assert!(!worker(tonic::Status::invalid_argument("bad image")).is_retryable());
+        assert!(!worker(tonic::Status::deadline_exceeded("late")).is_retryable());
+        assert!(!Error::Busy.is_retryable());
+    }
+
+    #[test]
+    fn test_status_round_trip() {
+        let timeout = Duration::from_millis(250);
+        let errors = [
+            (Error::InvalidInput("x".into()), Code::InvalidArgument),
+            (Error::PayloadTooLarge("x".into()), Code::InvalidArgument),
+            (
+                Error::UnsupportedMediaType("x".into()),
+                Code::InvalidArgument,
+            ),
+            (Error::UnsupportedInference("x".into()), Code::Unimplemented),
+            (Error::Unauthorized("x".into()), Code::Unauthenticated),
+            (Error::Forbidden("x".into()), Code::PermissionDenied),
+            (Error::NotFound("x".into()), Code::NotFound),
+            (
+                Error::RateLimited("x".into(), timeout),
+                Code::ResourceExhausted,
+            ),
+            (Error::Busy, Code::ResourceExhausted),
+            (Error::Timeout(timeout), Code::DeadlineExceeded),
+            (
+                Error::Retries(2, Box::new(Error::Busy)),
+                Code::ResourceExhausted,
+            ),
+        ];
+        for (err, code) in errors {
+            let name = err.code();
+            let status = tonic::Status::from(err);
+            assert_eq!(status.code(), code, "{name}");
+            assert_eq!(Error::from_status(status, timeout).code(), name);
+        }
+
+        // Failures of the worker itself are reported as worker errors
+        let err = Error::Other(anyhow::anyhow!("out of memory"));
+        let status = tonic::Status::from(err);
+        assert_eq!(status.code(), Code::Internal);
+        let err = Error::from_status(status, timeout);
+        assert!(matches!(err, Error::Worker(_)));
+        assert_eq!(err.code(), "worker_error");
+    }
+
+    #[test]
+    fn test_from_status() {
+        // Statuses without an error code, such as those of other gRPC
+        // servers, are mapped by their status code
+        let timeout = Duration::from_millis(250);
+        let err = |status| Error::from_status(status, timeout);
+        let bad = tonic::Status::invalid_argument("bad");
+        assert!(matches!(err(bad), Error::InvalidInput(_)));
+        let late = tonic::Status::deadline_exceeded("late");
+        assert!(matches!(err(late), Error::Timeout(t) if t == timeout));
+        let down = tonic::Status::unavailable("down");
+        assert_eq!(err(down).code(), "worker_unavailable");
+        assert_eq!(err(tonic::Status::internal("oops")).code(), "worker_error");
+
+        // A worker that refuses the manager's token is misconfigured, which
+        // is not the client's fault
+        let refused = err(tonic::Status::unauthenticated("invalid worker token"));
+        assert!(matches!(refused, Error::Worker(_)));
+        assert!(!refused.is_retryable());
+    }
+
+    #[test]
+    fn test_worker_fault() {
+        assert!(Error::Timeout(Duration::ZERO).is_worker_fault());
+        assert!(Error::Worker(Box::new(tonic::Status::internal("oops"))).is_worker_fault());
+        assert!(!Error::InvalidInput("x".into()).is_worker_fault());
+        assert!(!Error::Other(anyhow::anyhow!("config")).is_worker_fault());
+    }
+}
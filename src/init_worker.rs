@@ -1,7 +1,10 @@
 //! Entrypoint to start a worker locally
 
+use autodep::telemetry;
+use autodep::torch::Generation;
 use autodep::util::init_libtorch;
 use autodep::worker::Worker;
+use autodep::worker_auth::WorkerToken;
 use config::{Config, File};
 use std::{env, process};
 
@@ -29,12 +32,13 @@ fn get_args() -> (String, Config, u16) {
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let (model_file, config, port) = get_args();
+    let token = WorkerToken::from_env()?;
 
     init_libtorch(&config.get_string("worker.libtorch_path").unwrap());
-    std::env::set_var("RUST_LOG", config.get_string("manager.logging").unwrap());
-    tracing_subscriber::fmt::init();
+    telemetry::init(&config, "autodep-worker").unwrap();
 
-    let worker = Worker::new(&model_file, port).unwrap();
+    let generation = Generation::from_config(&config).unwrap();
+    let worker = Worker::new(&model_file, port, generation).unwrap();
 
-    worker.start().await
+    worker.start(token).await
 }
@@ -0,0 +1,533 @@
+//! Asynchronous inference jobs. A job is dispatched to the worker pool in the
+//! background, and clients poll for its result. Finished jobs are kept in a
+//! bounded in-memory store until they expire. When the store is full, the
+//! oldest finished jobs are evicted, and written to `jobs.spill_dir` if it is
+//! set, so that their results can still be fetched until they expire. A job
+//! submitted with a callback URL is also delivered to it once it finishes
+
+use crate::error::{Error, Result};
+use crate::manager::scheduler::Scheduling;
+use crate::manager::Manager;
+use crate::torch::{self, Inference};
+use crate::util;
+use crate::webhooks::Webhooks;
+use config::Config;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::task::AbortHandle;
+use tracing::*;
+use utoipa::ToSchema;
+
+/// The size and retention of the job store
+#[derive(Debug, Clone)]
+pub struct JobConfig {
+    /// Maximum number of jobs kept in memory
+    pub max_jobs: usize,
+
+    /// Time a finished job is kept for
+    pub ttl: Duration,
+
+    /// Directory evicted jobs are written to, if any
+    pub spill_dir: Option<PathBuf>,
+
+    /// Deadline of a job
+    pub timeout: Duration,
+}
+
+impl JobConfig {
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        let spill_dir = config.get_string("jobs.spill_dir")?;
+        Ok(JobConfig {
+            max_jobs: config.get_int("jobs.max_jobs")? as usize,
+            ttl: Duration::from_secs(config.get_int("jobs.ttl")? as u64),
+            spill_dir: (!spill_dir.is_empty()).then(|| spill_dir.into()),
+            timeout: Duration::from_millis(config.get_int("jobs.timeout")? as u64),
+        })
+    }
+}
+
+/// The status of a job
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, ToSchema)]
+pub enum JobStatus {
+    /// Waiting to be dispatched
+    Queued,
+
+    /// Dispatched to the worker pool
+    Running,
+
+    /// Finished with a result
+    Completed,
+
+    /// Finished with an error
+    Failed,
+
+    /// Cancelled by the client
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn is_finished(&self) -> bool {
+        !matches!(self, JobStatus::Queued | JobStatus::Running)
+    }
+}
+
+/// Why a job failed
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct JobError {
+    /// A machine-readable error code
+    pub code: String,
+    pub message: String,
+}
+
+/// An asynchronous inference job, as reported to clients
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Job {
+    pub id: String,
+    pub status: JobStatus,
+
+    /// Unix time the job was submitted at
+    pub created: u64,
+
+    /// Unix time the job finished at
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Inference>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<JobError>,
+
+    /// URL the job is POSTed to once it finishes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub callback_url: Option<String>,
+}
+
+struct Entry {
+    job: Job,
+
+    /// Aborts the job's task, while it is running
+    task: Option<AbortHandle>,
+
+    /// When the job finished
+    finished: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Jobs {
+    jobs: HashMap<String, Entry>,
+
+    /// IDs of the finished jobs in memory, oldest first
+    finished: VecDeque<String>,
+}
+
+/// Bounded store of jobs
+pub struct JobStore {
+    config: JobConfig,
+    jobs: Mutex<Jobs>,
+    webhooks: Arc<Webhooks>,
+}
+
+impl JobStore {
+    pub fn new(config: JobConfig, webhooks: Arc<Webhooks>) -> anyhow::Result<Self> {
+        if let Some(dir) = &config.spill_dir {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(JobStore {
+            config,
+            jobs: Mutex::new(Jobs::default()),
+            webhooks,
+        })
+    }
+
+    /// The webhooks finished jobs are delivered with
+    pub fn webhooks(&self) -> &Webhooks {
+        &self.webhooks
+    }
+
+    /// Dispatch a task to the worker pool in the background, and return its
+    /// job. Fails with `Error::Busy` if the store is full of unfinished jobs
+    pub fn submit(
+        self: &Arc<Self>,
+        manager: Arc<RwLock<Manager>>,
+        input: torch::InferenceTask,
+        request_id: String,
+        scheduling: Scheduling,
+        callback_url: Option<String>,
+    ) -> Result<Job> {
+        let job = Job {
+            id: util::random_id(),
+            status: JobStatus::Queued,
+            created: util::time(),
+            finished: None,
+            result: None,
+            error: None,
+            callback_url,
+        };
+
+        let mut jobs = self.jobs.lock().unwrap();
+        self.make_room(&mut jobs)?;
+
+        let store = self.clone();
+        let id = job.id.clone();
+        let timeout = self.config.timeout;
+        let task = tokio::spawn(async move {
+            store
+                .run(&manager, &id, input, &request_id, &scheduling, timeout)
+                .await
+        });
+        jobs.jobs.insert(
+            job.id.clone(),
+            Entry {
+                job: job.clone(),
+                task: Some(task.abort_handle()),
+                finished: None,
+            },
+        );
+        info!("submitted job {}", job.id);
+        Ok(job)
+    }
+
+    /// Make room for a new job, by evicting the oldest finished jobs
+    fn make_room(&self, jobs: &mut Jobs) -> Result<()> {
+        self.expire(jobs);
+        while jobs.jobs.len() >= self.config.max_jobs {
+            let Some(id) = jobs.finished.pop_front() else {
+                return Err(Error::Busy);
+            };
+            if let Some(entry) = jobs.jobs.remove(&id) {
+                self.spill(&entry.job);
+            }
+        }
+        Ok(())
+    }
+
+    /// Run a job on the worker pool. While all workers are busy, the job waits
+    /// for one in the queue, until its deadline
+    async fn run(
+        &self,
+        manager: &Arc<RwLock<Manager>>,
+        id: &str,
+        input: torch::InferenceTask,
+        request_id: &str,
+        scheduling: &Scheduling,
+        timeout: Duration,
+    ) {
+        if !self.update(id, |job| job.status = JobStatus::Running) {
+            return;
+        }
+
+        let res = Manager::dispatch(manager, input, request_id, scheduling, timeout).await;
+
+        match &res {
+            Ok(_) => info!("job {id} completed"),
+            Err(e) => warn!("job {id} failed: {e}"),
+        }
+        if let Some(job) = self.finish(id, res.map(|dispatched| dispatched.inference.0)) {
+            self.notify(job);
+        }
+    }
+
+    /// Update an unfinished job. Returns false if the job is gone or finished
+    fn update(&self, id: &str, f: impl FnOnce(&mut Job)) -> bool {
+        let mut jobs = self.jobs.lock().unwrap();
+        match jobs.jobs.get_mut(id) {
+            Some(entry) if !entry.job.status.is_finished() => {
+                f(&mut entry.job);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Record the outcome of a job, and return the finished job. Returns
+    /// `None` if the job is gone or had already finished
+    fn finish(&self, id: &str, res: Result<torch::Inference>) -> Option<Job> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let entry = jobs.jobs.get_mut(id)?;
+        if entry.job.status.is_finished() {
+            return None;
+        }
+
+        match res {
+            Ok(inference) => {
+                entry.job.status = JobStatus::Completed;
+                entry.job.result = Some(inference);
+            }
+            Err(e) => {
+                entry.job.status = JobStatus::Failed;
+                entry.job.error = Some(JobError {
+                    code: e.code().into(),
+                    message: e.to_string(),
+                });
+            }
+        }
+        Self::finished(entry);
+        let job = entry.job.clone();
+        jobs.finished.push_back(id.into());
+        Some(job)
+    }
+
+    /// Deliver a finished job to its callback URL in the background, if it
+    /// has one
+    fn notify(&self, job: Job) {
+        if let Some(url) = job.callback_url.clone() {
+            let webhooks = self.webhooks.clone();
+            tokio::spawn(async move { webhooks.deliver(url, job).await });
+        }
+    }
+
+    /// Mark a job's entry as finished
+    fn finished(entry: &mut Entry) {
+        entry.job.finished = Some(util::time());
+        entry.finished = Some(Instant::now());
+        entry.task = None;
+    }
+
+    /// Get a job, from memory or from the spill directory
+    pub fn get(&self, id: &str) -> Result<Job> {
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            self.expire(&mut jobs);
+            if let Some(entry) = jobs.jobs.get(id) {
+                return Ok(entry.job.clone());
+            }
+        }
+        self.unspill(id)
+            .ok_or_else(|| Error::NotFound(format!("no job with id {id}")))
+    }
+
+    /// Cancel an unfinished job, and return it. A finished job is deleted
+    /// instead, and `None` is returned
+    pub fn cancel(&self, id: &str) -> Result<Option<Job>> {
+        let cancelled = self.cancel_or_delete(id)?;
+        if let Some(job) = &cancelled {
+            self.notify(job.clone());
+        }
+        Ok(cancelled)
+    }
+
+    fn cancel_or_delete(&self, id: &str) -> Result<Option<Job>> {
+        let mut jobs = self.jobs.lock().unwrap();
+        match jobs.jobs.get_mut(id) {
+            Some(entry) if !entry.job.status.is_finished() => {
+                if let Some(task) = &entry.task {
+                    task.abort();
+                }
+                entry.job.status = JobStatus::Cancelled;
+                Self::finished(entry);
+                let job = entry.job.clone();
+                jobs.finished.push_back(id.into());
+                info!("cancelled job {id}");
+                Ok(Some(job))
+            }
+            Some(_) => {
+                jobs.jobs.remove(id);
+                jobs.finished.retain(|finished| finished != id);
+                Ok(None)
+            }
+            None => match self.spill_path(id).filter(|path| path.exists()) {
+                Some(path) => {
+                    fs::remove_file(path).map_err(anyhow::Error::from)?;
+                    Ok(None)
+                }
+                None => Err(Error::NotFound(format!("no job with id {id}"))),
+            },
+        }
+    }
+
+    /// Drop the finished jobs in memory that have expired
+    fn expire(&self, jobs: &mut Jobs) {
+        while let Some(id) = jobs.finished.front() {
+            let expired = jobs
+                .jobs
+                .get(id)
+                .and_then(|entry| entry.finished)
+                .map_or(true, |t| t.elapsed() >= self.config.ttl);
+            if !expired {
+                break;
+            }
+            let id = jobs.finished.pop_front().unwrap();
+            jobs.jobs.remove(&id);
+        }
+    }
+
+    /// Delete the spilled jobs that have expired
+    pub fn sweep(&self) {
+        self.expire(&mut self.jobs.lock().unwrap());
+
+        let Some(dir) = &self.config.spill_dir else {
+            return;
+        };
+        let Ok(files) = fs::read_dir(dir) else {
+            return;
+        };
+        for file in files.flatten() {
+            let expired = file
+                .metadata()
+                .and_then(|meta| meta.modified())
+                .map(|modified| self.is_expired(modified))
+                .unwrap_or(false);
+            if expired {
+                if let Err(e) = fs::remove_file(file.path()) {
+                    warn!("failed to remove expired job {:?}: {e}", file.path());
+                }
+            }
+        }
+    }
+
+    fn is_expired(&self, finished: SystemTime) -> bool {
+        finished.elapsed().is_ok_and(|age| age >= self.config.ttl)
+    }
+
+    /// The file a job is spilled to. IDs are only ever generated by the store,
+    /// so any other ID is rejected rather than turned into a path
+    fn spill_path(&self, id: &str) -> Option<PathBuf> {
+        let dir = self.config.spill_dir.as_ref()?;
+        id.chars()
+            .all(|c| c.is_ascii_hexdigit())
+            .then(|| dir.join(format!("{id}.json")))
+    }
+
+    /// Write an evicted job to the spill directory, if there is one
+    fn spill(&self, job: &Job) {
+        let Some(path) = self.spill_path(&job.id) else {
+            return;
+        };
+        let res = serde_json::to_vec(job)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| Ok(fs::write(&path, json)?));
+        if let Err(e) = res {
+            warn!("failed to spill job {} to {path:?}: {e}", job.id);
+        }
+    }
+
+    /// Read a job from the spill directory, unless it has expired
+    fn unspill(&self, id: &str) -> Option<Job> {
+        let path = self.spill_path(id)?;
+        let modified = fs::metadata(&path).and_then(|meta| meta.modified()).ok()?;
+        if self.is_expired(modified) {
+            let _ = fs::remove_file(&path);
+            return None;
+        }
+        serde_json::from_slice(&fs::read(&path).ok()?).ok()
+    }
+}
+
+/// Periodically delete expired jobs, forever
+pub async fn run_sweeper(store: Arc<JobStore>) {
+    let interval = (store.config.ttl / 10).max(Duration::from_secs(1));
+    loop {
+        tokio::time::sleep(interval).await;
+        store.sweep();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webhooks::WebhookConfig;
+
+    fn webhooks() -> Arc<Webhooks> {
+        let config = WebhookConfig {
+            secret: String::new(),
+            max_attempts: 1,
+            initial_backoff: Duration::ZERO,
+            max_backoff: Duration::ZERO,
+            timeout: Duration::from_secs(1),
+            log_size: 10,
+            allowed_hosts: vec![],
+        };
+        Arc::new(Webhooks::new(config).unwrap())
+    }
+
+    #[test]
+    fn test_spill_and_cancel() {
+        let dir = std::env::temp_dir().join(format!("autodep_jobs_{}", std::process::id()));
+        let store = JobStore::new(
+            JobConfig {
+                max_jobs: 1,
+                ttl: Duration::from_secs(60),
+                spill_dir: Some(dir.clone()),
+                timeout: Duration::from_secs(1),
+            },
+            webhooks(),
+        )
+        .unwrap();
+
+        let first = util::random_id();
+        let job = Job {
+            id: first.clone(),
+            status: JobStatus::Completed,
+            created: util::time(),
+            finished: Some(util::time()),
+            result: Some(torch::Inference::Text("done".into())),
+            error: None,
+            callback_url: None,
+        };
+        let entry = Entry {
+            job,
+            task: None,
+            finished: Some(Instant::now()),
+        };
+        {
+            let mut jobs = store.jobs.lock().unwrap();
+            jobs.jobs.insert(first.clone(), entry);
+            jobs.finished.push_back(first.clone());
+        }
+        store.make_room(&mut store.jobs.lock().unwrap()).unwrap();
+        assert!(store.jobs.lock().unwrap().jobs.is_empty());
+
+        let job = store.get(&first).unwrap();
+        assert_eq!(job.status, JobStatus::Completed);
+        assert!(matches!(job.result, Some(torch::Inference::Text(ref t)) if t == "done"));
+
+        assert!(store.cancel(&first).unwrap().is_none());
+        assert!(matches!(store.get(&first), Err(Error::NotFound(_))));
+        assert!(matches!(
+            store.get("../etc/passwd"),
+            Err(Error::NotFound(_))
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_expiry() {
+        let store = JobStore::new(
+            JobConfig {
+                max_jobs: 10,
+                ttl: Duration::ZERO,
+                spill_dir: None,
+                timeout: Duration::from_secs(1),
+            },
+            webhooks(),
+        )
+        .unwrap();
+
+        let id = util::random_id();
+        let job = Job {
+            id: id.clone(),
+            status: JobStatus::Failed,
+            created: util::time(),
+            finished: Some(util::time()),
+            result: None,
+            error: None,
+            callback_url: None,
+        };
+        let entry = Entry {
+            job,
+            task: None,
+            finished: Some(Instant::now()),
+        };
+        {
+            let mut jobs = store.jobs.lock().unwrap();
+            jobs.jobs.insert(id.clone(), entry);
+            jobs.finished.push_back(id.clone());
+        }
+        assert!(matches!(store.get(&id), Err(Error::NotFound(_))));
+    }
+}
@@ -0,0 +1,549 @@
+//! API keys, which clients of the inference API authenticate with. Only the
+//! SHA-256 digests of the keys are stored, in `[[auth.keys]]` entries of the
+//! config file or in `auth.keys_file`. Each key has its own limits: a rate of
+//! requests per second, a number of concurrent requests, a daily quota, and
+//! the models and inference types it may use
+
+use crate::error::{Error, Result};
+use crate::manager::scheduler::Priority;
+use crate::ratelimit::TokenBucket;
+use crate::torch::InferenceType;
+use crate::util;
+use config::{Config, ConfigError, File, FileFormat};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// How long a client that has too many concurrent requests is asked to wait
+const CONCURRENCY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// An API key, as configured. A limit of 0 or an empty list means unlimited
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct KeyConfig {
+    /// The name of the key's owner, to report its usage under
+    pub name: String,
+
+    /// The SHA-256 digest of the key, in hex
+    pub hash: String,
+
+    /// Sustained number of requests per second
+    #[serde(default)]
+    pub requests_per_second: f64,
+
+    /// Number of requests that can be made at once before the rate applies.
+    /// Defaults to one second's worth of requests
+    pub burst: Option<u32>,
+
+    /// Number of requests that can be served at once
+    #[serde(default)]
+    pub max_concurrent: usize,
+
+    /// Number of requests per UTC day
+    #[serde(default)]
+    pub daily_quota: u64,
+
+    /// The names of the models the key can be used with
+    #[serde(default)]
+    pub models: Vec<String>,
+
+    /// The inference types the key can request, such as `"TextToText"`
+    #[serde(default)]
+    pub inference_types: Vec<String>,
+
+    /// The key's share of the workers when requests are queued, relative to
+    /// other keys. Defaults to 1
+    pub weight: Option<u32>,
+
+    /// The most urgent priority the key's requests can be queued at. Requests
+    /// that ask for a more urgent one get this one instead. Defaults to
+    /// `interactive`
+    pub max_priority: Option<Priority>,
+}
+
+/// The usage of an API key, as reported by `/admin/keys`
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct KeyUsage {
+    pub name: String,
+
+    /// Requests admitted since the server started
+    pub requests: u64,
+
+    /// Requests refused by the key's limits since the server started
+    pub rejected: u64,
+
+    /// Requests admitted today, counted against the daily quota
+    pub today: u64,
+    pub daily_quota: u64,
+
+    /// Requests being served
+    pub in_flight: usize,
+}
+
+/// The usage counters of a key
+#[derive(Debug, Default)]
+struct Counters {
+    requests: u64,
+    rejected: u64,
+    today: u64,
+
+    /// The UTC day `today` counts requests of, in days since the epoch
+    day: u64,
+}
+
+/// An API key and the state of its limits
+#[derive(Debug)]
+pub struct ApiKey {
+    pub config: KeyConfig,
+    bucket: Option<Mutex<TokenBucket>>,
+    in_flight: AtomicUsize,
+    counters: Mutex<Counters>,
+}
+
+impl ApiKey {
+    fn new(config: KeyConfig) -> ApiKey {
+        let bucket = (config.requests_per_second > 0.0).then(|| {
+            let burst = config
+                .burst
+                .map(f64::from)
+                .unwrap_or(config.requests_per_second.ceil());
+            Mutex::new(TokenBucket::new(config.requests_per_second, burst))
+        });
+        ApiKey {
+            config,
+            bucket,
+            in_flight: AtomicUsize::new(0),
+            counters: Mutex::new(Counters::default()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    /// The weight of the key's requests in the scheduler's queue
+    pub fn weight(&self) -> u32 {
+        self.config.weight.unwrap_or(1).max(1)
+    }
+
+    /// The priority a request of the key asking for `priority` is queued at
+    pub fn priority(&self, priority: Priority) -> Priority {
+        priority.max(self.config.max_priority.unwrap_or(Priority::Interactive))
+    }
+
+    /// Check that the key can request this type of inference
+    pub fn allow_inference(&self, ty: &InferenceType) -> Result<()> {
+        let allowed = &self.config.inference_types;
+        if allowed.is_empty() || allowed.iter().any(|name| name == ty.name()) {
+            Ok(())
+        } else {
+            Err(Error::Forbidden(format!(
+                "the API key of {} cannot request {} inference",
+                self.config.name,
+                ty.name()
+            )))
+        }
+    }
+
+    fn allow_model(&self, model: &str) -> Result<()> {
+        let allowed = &self.config.models;
+        if allowed.is_empty() || allowed.iter().any(|name| name == model) {
+            Ok(())
+        } else {
+            Err(Error::Forbidden(format!(
+                "the API key of {} cannot use model {model}",
+                self.config.name
+            )))
+        }
+    }
+
+    /// Admit a request under the key's limits, counting it against them
+    fn admit(self: &Arc<Self>, now: Instant, unix_time: u64) -> Result<Permit> {
+        self.count(1, true, now, unix_time)?;
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        Ok(Permit { key: self.clone() })
+    }
+
+    /// Count `n` more requests against the key's rate and daily quota, but
+    /// not its concurrency limit. This charges the tasks of a batch and the
+    /// frames of a WebSocket, whose request was admitted only once
+    pub fn charge(&self, n: u64) -> Result<()> {
+        self.charge_at(n, Instant::now(), util::time())
+    }
+
+    fn charge_at(&self, n: u64, now: Instant, unix_time: u64) -> Result<()> {
+        if n == 0 {
+            return Ok(());
+        }
+        self.count(n, false, now, unix_time)
+    }
+
+    /// Count `n` requests against the key's limits, checking its concurrency
+    /// limit as well if they are `concurrent` with the requests in flight
+    fn count(&self, n: u64, concurrent: bool, now: Instant, unix_time: u64) -> Result<()> {
+        let mut counters = self.counters.lock().unwrap();
+        let day = unix_time / SECS_PER_DAY;
+        if counters.day != day {
+            counters.day = day;
+            counters.today = 0;
+        }
+
+        if let Err(e) = self.check_limits(counters.today, n, concurrent, now, unix_time) {
+            counters.rejected += n;
+            return Err(e);
+        }
+        counters.requests += n;
+        counters.today += n;
+        Ok(())
+    }
+
+    fn check_limits(
+        &self,
+        today: u64,
+        n: u64,
+        concurrent: bool,
+        now: Instant,
+        unix_time: u64,
+    ) -> Result<()> {
+        let name = &self.config.name;
+        if self.config.daily_quota > 0 && today + n > self.config.daily_quota {
+            let midnight = SECS_PER_DAY - unix_time % SECS_PER_DAY;
+            return Err(Error::RateLimited(
+                format!("the daily quota of {name} is used up"),
+                Duration::from_secs(midnight),
+            ));
+        }
+
+        let in_flight = self.in_flight.load(Ordering::SeqCst);
+        if concurrent && self.config.max_concurrent > 0 && in_flight >= self.config.max_concurrent {
+            return Err(Error::RateLimited(
+                format!("{name} already has {in_flight} requests in flight"),
+                CONCURRENCY_BACKOFF,
+            ));
+        }
+
+        if let Some(bucket) = &self.bucket {
+            let mut bucket = bucket.lock().unwrap();
+            // A batch larger than the burst would never get its tokens
+            if n as f64 > bucket.burst() {
+                let msg = format!(
+                    "{name} can make at most {} requests at once, not {n}",
+                    bucket.burst()
+                );
+                return Err(Error::InvalidInput(msg));
+            }
+            bucket.try_take_n(now, n as f64).map_err(|wait| {
+                Error::RateLimited(
+                    format!(
+                        "{name} is limited to {} requests per second",
+                        self.config.requests_per_second
+                    ),
+                    wait,
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    fn usage(&self) -> KeyUsage {
+        let counters = self.counters.lock().unwrap();
+        let today = if counters.day == util::time() / SECS_PER_DAY {
+            counters.today
+        } else {
+            0
+        };
+        KeyUsage {
+            name: self.config.name.clone(),
+            requests: counters.requests,
+            rejected: counters.rejected,
+            today,
+            daily_quota: self.config.daily_quota,
+            in_flight: self.in_flight.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// A request admitted under the limits of its key. It counts as in flight
+/// until the permit is dropped
+#[derive(Debug)]
+pub struct Permit {
+    key: Arc<ApiKey>,
+}
+
+impl Permit {
+    pub fn key(&self) -> &Arc<ApiKey> {
+        &self.key
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.key.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The configured API keys, by digest
+#[derive(Debug, Default)]
+pub struct KeyStore {
+    /// Whether requests must carry a key
+    enabled: bool,
+    keys: HashMap<String, Arc<ApiKey>>,
+}
+
+impl KeyStore {
+    pub fn from_config(config: &Config) -> anyhow::Result<KeyStore> {
+        let mut keys = optional_keys(config.get("auth.keys"))?;
+        let keys_file = config.get_string("auth.keys_file")?;
+        if !keys_file.is_empty() {
+            let file = Config::builder()
+                .add_source(File::new(&keys_file, FileFormat::Toml))
+                .build()?;
+            keys.extend(optional_keys(file.get("keys"))?);
+        }
+        KeyStore::new(config.get_bool("auth.enabled")?, keys)
+    }
+
+    pub fn new(enabled: bool, keys: Vec<KeyConfig>) -> anyhow::Result<KeyStore> {
+        let mut store = KeyStore {
+            enabled,
+            keys: HashMap::new(),
+        };
+        for key in keys {
+            let hash = key.hash.to_ascii_lowercase();
+            if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                anyhow::bail!(
+                    "the hash of the API key of {} is not a SHA-256 digest",
+                    key.name
+                );
+            }
+            if store
+                .keys
+                .values()
+                .any(|other| other.config.name == key.name)
+            {
+                anyhow::bail!("there are several API keys named {}", key.name);
+            }
+            if key.burst == Some(0) {
+                anyhow::bail!(
+                    "the burst of the API key of {} must be at least 1",
+                    key.name
+                );
+            }
+            if let Some(other) = store.keys.get(&hash) {
+                anyhow::bail!(
+                    "the API keys of {} and {} are the same",
+                    other.config.name,
+                    key.name
+                );
+            }
+            store.keys.insert(hash, Arc::new(ApiKey::new(key)));
+        }
+        if enabled && store.keys.is_empty() {
+            anyhow::bail!("authentication is enabled, but no API keys are configured");
+        }
+        Ok(store)
+    }
+
+    /// Whether requests must carry an API key
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Authenticate a request by its API key, and admit it if the key allows
+    /// it to use `model` and is within its limits. Returns `None` when
+    /// authentication is disabled
+    pub fn admit(&self, key: Option<&str>, model: &str) -> Result<Option<Permit>> {
+        if !self.enabled {
+            return Ok(None);
+        }
+        let key = key.ok_or_else(|| Error::Unauthorized("an API key is required".into()))?;
+        let key = self
+            .keys
+            .get(&hash(key))
+            .ok_or_else(|| Error::Unauthorized("invalid API key".into()))?;
+        key.allow_model(model)?;
+        key.admit(Instant::now(), util::time()).map(Some)
+    }
+
+    /// The usage of every key, by name
+    pub fn usage(&self) -> Vec<KeyUsage> {
+        let mut usage = self
+            .keys
+            .values()
+            .map(|key| key.usage())
+            .collect::<Vec<_>>();
+        usage.sort_by(|a, b| a.name.cmp(&b.name));
+        usage
+    }
+}
+
+/// Read a list of keys, which is empty if it is not set
+fn optional_keys(
+    keys: std::result::Result<Vec<KeyConfig>, ConfigError>,
+) -> anyhow::Result<Vec<KeyConfig>> {
+    match keys {
+        Err(ConfigError::NotFound(_)) => Ok(vec![]),
+        keys => Ok(keys?),
+    }
+}
+
+/// The SHA-256 digest of an API key, in hex
+fn hash(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .fold(String::new(), |mut hex, b| {
+            let _ = write!(hex, "{b:02x}");
+            hex
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &str, secret: &str) -> KeyConfig {
+        KeyConfig {
+            name: name.into(),
+            hash: hash(secret),
+            requests_per_second: 0.0,
+            burst: None,
+            max_concurrent: 0,
+            daily_quota: 0,
+            models: vec![],
+            inference_types: vec![],
+            weight: None,
+            max_priority: None,
+        }
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let store = KeyStore::new(true, vec![key("alice", "secret")]).unwrap();
+        let permit = store.admit(Some("secret"), "resnet18").unwrap().unwrap();
+        assert_eq!(permit.key().name(), "alice");
+        assert!(matches!(
+            store.admit(Some("guess"), "resnet18"),
+            Err(Error::Unauthorized(_))
+        ));
+        assert!(matches!(
+            store.admit(None, "resnet18"),
+            Err(Error::Unauthorized(_))
+        ));
+
+        // Nothing is checked when authentication is disabled
+        let store = KeyStore::new(false, vec![]).unwrap();
+        assert!(store.admit(None, "resnet18").unwrap().is_none());
+
+        assert!(KeyStore::new(true, vec![]).is_err());
+        let mut bad = key("bob", "secret");
+        bad.hash = "secret".into();
+        assert!(KeyStore::new(true, vec![bad]).is_err());
+        let mut bad = key("bob", "secret");
+        bad.burst = Some(0);
+        assert!(KeyStore::new(true, vec![bad]).is_err());
+
+        // Two owners cannot share a key
+        let keys = vec![key("alice", "secret"), key("bob", "SECRET")];
+        assert!(KeyStore::new(true, keys).is_ok());
+        let keys = vec![key("alice", "secret"), key("bob", "secret")];
+        assert!(KeyStore::new(true, keys).is_err());
+    }
+
+    #[test]
+    fn test_allowed() {
+        let mut config = key("alice", "secret");
+        config.models = vec!["resnet18".into()];
+        config.inference_types = vec!["ImageClassification".into()];
+        let store = KeyStore::new(true, vec![config]).unwrap();
+
+        assert!(matches!(
+            store.admit(Some("secret"), "bert"),
+            Err(Error::Forbidden(_))
+        ));
+        let permit = store.admit(Some("secret"), "resnet18").unwrap().unwrap();
+        let key = permit.key();
+        assert!(key
+            .allow_inference(&InferenceType::ImageClassification { top_n: 5 })
+            .is_ok());
+        assert!(matches!(
+            key.allow_inference(&InferenceType::TextToText),
+            Err(Error::Forbidden(_))
+        ));
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut config = key("alice", "secret");
+        config.max_concurrent = 2;
+        config.daily_quota = 3;
+        let key = Arc::new(ApiKey::new(config));
+        let now = Instant::now();
+        let day = 1_700_000_000;
+
+        // Concurrent requests are limited until one finishes
+        let first = key.admit(now, day).unwrap();
+        let _second = key.admit(now, day).unwrap();
+        assert!(matches!(key.admit(now, day), Err(Error::RateLimited(..))));
+        drop(first);
+
+        // The daily quota runs out, and is reset the next day
+        let third = key.admit(now, day).unwrap();
+        let err = key.admit(now, day).unwrap_err();
+        assert!(matches!(err, Error::RateLimited(_, wait) if wait.as_secs() <= SECS_PER_DAY));
+        drop(third);
+        assert!(key.admit(now, day + SECS_PER_DAY).is_ok());
+
+        let counters = key.counters.lock().unwrap();
+        assert_eq!((counters.requests, counters.rejected), (4, 2));
+    }
+
+    #[test]
+    fn test_rate() {
+        let mut config = key("alice", "secret");
+        config.requests_per_second = 1.0;
+        config.burst = Some(2);
+        let key = Arc::new(ApiKey::new(config));
+        let now = Instant::now();
+
+        assert!(key.admit(now, 0).is_ok());
+        assert!(key.admit(now, 0).is_ok());
+        let err = key.admit(now, 0).unwrap_err();
+        assert!(matches!(err, Error::RateLimited(_, wait) if wait <= Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_charge() {
+        let mut config = key("alice", "secret");
+        config.requests_per_second = 1.0;
+        config.burst = Some(4);
+        config.max_concurrent = 1;
+        config.daily_quota = 6;
+        let key = Arc::new(ApiKey::new(config));
+        let now = Instant::now();
+
+        // The tasks of a batch count against the rate and quota, but not the
+        // concurrency limit
+        let _permit = key.admit(now, 0).unwrap();
+        assert!(matches!(
+            key.charge_at(5, now, 0),
+            Err(Error::InvalidInput(_))
+        ));
+        assert!(key.charge_at(3, now, 0).is_ok());
+        assert!(matches!(
+            key.charge_at(1, now, 0),
+            Err(Error::RateLimited(..))
+        ));
+
+        // Only 2 requests are left in the quota
+        let later = now + Duration::from_secs(4);
+        let err = key.charge_at(3, later, 0).unwrap_err();
+        assert!(matches!(err, Error::RateLimited(_, wait) if wait > Duration::from_secs(60)));
+        assert!(key.charge_at(2, later, 0).is_ok());
+        assert_eq!(key.usage().in_flight, 1);
+    }
+}
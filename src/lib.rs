@@ -1,13 +1,27 @@
+pub mod error;
+pub mod jobs;
+pub mod keys;
+pub mod logs;
 pub mod manager;
+pub mod metrics;
+pub mod ratelimit;
 pub mod server;
+pub mod telemetry;
 pub mod torch;
+pub mod webhooks;
 pub mod worker;
+pub mod worker_auth;
 
 /// The worker's RPC server
 pub mod rpc {
     tonic::include_proto!("worker");
 }
 
+/// The client-facing gRPC API
+pub mod api {
+    include!(concat!(env!("OUT_DIR"), "/api/autodep.rs"));
+}
+
 /// Autodep configuration -- can eventually be lazy_static parsed from a config
 /// file
 pub mod config {}
@@ -16,11 +30,6 @@ pub mod config {}
 pub mod util {
     use std::time::{SystemTime, UNIX_EPOCH};
 
-    pub fn init_logging(log: &str) {
-        std::env::set_var("RUST_LOG", log);
-        tracing_subscriber::fmt::init();
-    }
-
     pub fn init_libtorch(libtorch_path: &str) {
         let ld_lib_path = std::env::var("LD_LIBRARY_PATH").unwrap();
         std::env::set_var(
@@ -40,6 +49,40 @@ pub mod util {
             .as_secs()
     }
 
+    /// Generate a random 128 bit ID, in hex
+    pub fn random_id() -> String {
+        format!("{:032x}", rand::random::<u128>())
+    }
+
+    /// Compare two secrets in constant time, so that timing does not reveal
+    /// how much of a guess is correct
+    pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+    }
+
+    /// Get the resident set size of a process, in bytes, as reported by
+    /// `/proc/<pid>/status`
+    pub fn rss(pid: u32) -> anyhow::Result<u64> {
+        Ok(proc_status(pid, "VmRSS")? * 1024)
+    }
+
+    /// Get the number of threads of a process, as reported by
+    /// `/proc/<pid>/status`
+    pub fn threads(pid: u32) -> anyhow::Result<u64> {
+        proc_status(pid, "Threads")
+    }
+
+    /// Read a numeric field of `/proc/<pid>/status`
+    fn proc_status(pid: u32, field: &str) -> anyhow::Result<u64> {
+        let status = std::fs::read_to_string(format!("/proc/{pid}/status"))?;
+        Ok(status
+            .lines()
+            .find_map(|line| line.strip_prefix(field)?.strip_prefix(':'))
+            .and_then(|line| line.split_whitespace().next())
+            .ok_or_else(|| anyhow::anyhow!("no {field} entry for process {pid}"))?
+            .parse::<u64>()?)
+    }
+
     /// Functions for testing purposes
     pub mod test {
         use std::io::Read;
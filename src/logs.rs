@@ -0,0 +1,200 @@
+//! Log files of worker processes. A worker's stdout and stderr are written to
+//! files in `logging.dir`, which are rotated once they grow past
+//! `logging.max_size_mb` or get older than `logging.max_age` seconds. Rotated
+//! files are numbered, `.1` being the most recent, and only the last
+//! `logging.max_files` of them are kept
+
+use config::Config;
+use serde::Serialize;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::*;
+
+/// Where log files are written, and when they are rotated
+#[derive(Debug, Clone)]
+pub struct LogConfig {
+    pub dir: PathBuf,
+
+    /// Maximum size of a log file, in bytes (0 = unlimited)
+    max_size: u64,
+
+    /// Maximum age of a log file (0 = unlimited)
+    max_age: Duration,
+
+    /// Number of rotated files kept per log (0 = unlimited)
+    max_files: usize,
+}
+
+impl LogConfig {
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        Ok(LogConfig {
+            dir: config.get_string("logging.dir")?.into(),
+            max_size: config.get_int("logging.max_size_mb")? as u64 * 1024 * 1024,
+            max_age: Duration::from_secs(config.get_int("logging.max_age")? as u64),
+            max_files: config.get_int("logging.max_files")? as usize,
+        })
+    }
+}
+
+/// The log files of a worker
+#[derive(Debug, Clone, Serialize)]
+pub struct WorkerLogs {
+    pub stdout: PathBuf,
+    pub stderr: PathBuf,
+}
+
+impl WorkerLogs {
+    /// Delete the logs, including their rotated files
+    pub fn remove(&self) {
+        for path in [&self.stdout, &self.stderr] {
+            let rotated = (1..).map(|n| rotated(path, n)).take_while(|p| p.exists());
+            for file in std::iter::once(path.clone())
+                .filter(|p| p.exists())
+                .chain(rotated)
+            {
+                if let Err(e) = fs::remove_file(&file) {
+                    warn!("failed to remove log file {}: {e}", file.display());
+                }
+            }
+        }
+    }
+}
+
+/// A log file that is rotated once it grows too large or too old
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened: Instant,
+    config: LogConfig,
+}
+
+impl RotatingFile {
+    /// Create a log file, creating its directory if needed
+    pub fn create(path: &Path, config: LogConfig) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(RotatingFile {
+            path: path.into(),
+            file: File::create(path)?,
+            size: 0,
+            opened: Instant::now(),
+            config,
+        })
+    }
+
+    fn needs_rotation(&self, len: usize) -> bool {
+        let too_large = self.config.max_size > 0 && self.size + len as u64 > self.config.max_size;
+        let too_old =
+            !self.config.max_age.is_zero() && self.opened.elapsed() >= self.config.max_age;
+        self.size > 0 && (too_large || too_old)
+    }
+
+    /// Shift the rotated files up by one, dropping the oldest one if there are
+    /// already `max_files` of them, then move the current file to `.1`
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        let mut n = 1;
+        while rotated(&self.path, n).exists()
+            && (self.config.max_files == 0 || n < self.config.max_files)
+        {
+            n += 1;
+        }
+        if self.config.max_files > 0 && rotated(&self.path, n).exists() {
+            fs::remove_file(rotated(&self.path, n))?;
+        }
+        for i in (1..n).rev() {
+            fs::rename(rotated(&self.path, i), rotated(&self.path, i + 1))?;
+        }
+
+        fs::rename(&self.path, rotated(&self.path, 1))?;
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.needs_rotation(buf.len()) {
+            self.rotate()?;
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// The path of the `n`th most recent rotated file of a log
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    name.into()
+}
+
+/// Copy the output of a process to a log file, line by line so that lines are
+/// never split across rotated files, until the output is closed
+pub fn capture(output: impl Read + Send + 'static, mut file: RotatingFile) {
+    std::thread::spawn(move || {
+        for line in BufReader::new(output).split(b'\n') {
+            let res = line.and_then(|mut line| {
+                line.push(b'\n');
+                file.write_all(&line)
+            });
+            if let Err(e) = res {
+                error!("failed to write to log {}: {e}", file.path.display());
+                break;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("autodep_{name}_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = test_dir("rotation");
+        let config = LogConfig {
+            dir: dir.clone(),
+            max_size: 10,
+            max_age: Duration::ZERO,
+            max_files: 2,
+        };
+        let path = dir.join("worker.out");
+        let mut file = RotatingFile::create(&path, config).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(fs::read_to_string(rotated(&path, 1)).unwrap(), "third\n");
+        assert_eq!(fs::read_to_string(rotated(&path, 2)).unwrap(), "second\n");
+        assert!(!rotated(&path, 3).exists());
+
+        let logs = WorkerLogs {
+            stdout: path.clone(),
+            stderr: dir.join("worker.err"),
+        };
+        logs.remove();
+        assert!(!path.exists() && !rotated(&path, 1).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -3,7 +3,7 @@ use config::Config;
 use config::File;
 use std::{env, io, process};
 
-use autodep::util;
+use autodep::telemetry;
 
 const USAGE: &str = "usage: ./autodep <config file> <model file>";
 
@@ -28,7 +28,9 @@ fn get_args() -> (String, Config) {
 #[actix_web::main]
 async fn main() -> io::Result<()> {
     let (model, config) = get_args();
-    util::init_logging(&config.get_string("manager.logging").unwrap());
+    telemetry::init(&config, "autodep").unwrap();
 
-    Server::new(&model, config).await
+    let res = Server::new(&model, config).await;
+    telemetry::shutdown();
+    res
 }
@@ -0,0 +1,253 @@
+//! A per-worker circuit breaker. A worker that keeps failing is ejected from
+//! rotation (the circuit is opened). After a while, it is probed with a single
+//! request (the circuit is half-open), and put back into rotation if the probe
+//! succeeds (the circuit is closed again)
+
+use anyhow::Result;
+use config::Config;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// The state of a circuit breaker
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+pub enum CircuitState {
+    /// The worker is in rotation
+    Closed,
+
+    /// The worker is out of rotation
+    Open,
+
+    /// The worker is being probed with a single request
+    HalfOpen,
+}
+
+/// When to open a circuit, and for how long
+#[derive(Debug, Clone)]
+pub struct BreakerConfig {
+    /// Open the circuit after this many consecutive failures
+    pub max_consecutive_failures: u32,
+
+    /// Open the circuit once this fraction of recent requests failed
+    pub max_failure_ratio: f64,
+
+    /// Number of recent requests the failure ratio is computed over
+    pub window: usize,
+
+    /// Minimum number of recent requests before the failure ratio is considered
+    pub min_requests: usize,
+
+    /// How long the circuit stays open before the worker is probed
+    pub open_time: Duration,
+}
+
+impl BreakerConfig {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        Ok(BreakerConfig {
+            max_consecutive_failures: config.get_int("breaker.max_consecutive_failures")? as u32,
+            max_failure_ratio: config.get_float("breaker.max_failure_ratio")?,
+            window: config.get_int("breaker.window")? as usize,
+            min_requests: config.get_int("breaker.min_requests")? as usize,
+            open_time: Duration::from_millis(config.get_int("breaker.open_time")? as u64),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    state: CircuitState,
+
+    /// When the circuit was last opened
+    opened_at: Instant,
+
+    /// Whether the half-open probe request has been sent
+    probing: bool,
+
+    consecutive_failures: u32,
+
+    /// Outcomes of the most recent requests, `true` for failures
+    recent: VecDeque<bool>,
+
+    /// Incremented on every change of state. Requests are tagged with the
+    /// generation they were sent in, so that the outcome of a request sent
+    /// before the circuit last changed cannot affect its new state
+    generation: u64,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> Self {
+        CircuitBreaker {
+            recent: VecDeque::with_capacity(config.window),
+            config,
+            state: CircuitState::Closed,
+            opened_at: Instant::now(),
+            probing: false,
+            consecutive_failures: 0,
+            generation: 0,
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.state
+    }
+
+    /// Whether a request may be sent to the worker, returning the generation
+    /// to record its outcome with if so. Once an open circuit has waited out
+    /// its `open_time`, exactly one probe request is allowed
+    pub fn try_acquire(&mut self) -> Option<u64> {
+        match self.state {
+            CircuitState::Closed => Some(self.generation),
+            CircuitState::Open if self.opened_at.elapsed() >= self.config.open_time => {
+                self.transition(CircuitState::HalfOpen);
+                self.probing = true;
+                Some(self.generation)
+            }
+            CircuitState::Open => None,
+            CircuitState::HalfOpen if !self.probing => {
+                self.probing = true;
+                Some(self.generation)
+            }
+            CircuitState::HalfOpen => None,
+        }
+    }
+
+    /// Record that a request sent to the worker was abandoned before its
+    /// outcome was known. A probe is given up, so that another one can be sent
+    pub fn abandon(&mut self, generation: u64) {
+        if generation == self.generation {
+            self.probing = false;
+        }
+    }
+
+    /// Record the outcome of a request sent to the worker in `generation`.
+    /// Outcomes of requests sent before the last change of state are ignored
+    pub fn record(&mut self, generation: u64, success: bool) {
+        if generation != self.generation {
+            return;
+        }
+        if self.state == CircuitState::HalfOpen {
+            self.probing = false;
+            if success {
+                self.close();
+            } else {
+                self.open();
+            }
+            return;
+        }
+
+        if self.recent.len() >= self.config.window {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(!success);
+
+        if success {
+            self.consecutive_failures = 0;
+            return;
+        }
+        self.consecutive_failures += 1;
+
+        let failures = self.recent.iter().filter(|&&failed| failed).count();
+        let ratio = failures as f64 / self.recent.len() as f64;
+        if self.consecutive_failures >= self.config.max_consecutive_failures
+            || (self.recent.len() >= self.config.min_requests
+                && ratio >= self.config.max_failure_ratio)
+        {
+            self.open();
+        }
+    }
+
+    fn open(&mut self) {
+        self.transition(CircuitState::Open);
+        self.opened_at = Instant::now();
+    }
+
+    fn close(&mut self) {
+        self.transition(CircuitState::Closed);
+        self.consecutive_failures = 0;
+        self.recent.clear();
+    }
+
+    fn transition(&mut self, state: CircuitState) {
+        self.state = state;
+        self.generation += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(open_time: Duration) -> CircuitBreaker {
+        CircuitBreaker::new(BreakerConfig {
+            max_consecutive_failures: 3,
+            max_failure_ratio: 0.5,
+            window: 10,
+            min_requests: 6,
+            open_time,
+        })
+    }
+
+    #[test]
+    fn test_opens_after_consecutive_failures() {
+        let mut b = breaker(Duration::from_secs(60));
+        b.record(b.generation, false);
+        b.record(b.generation, false);
+        assert_eq!(b.state(), CircuitState::Closed);
+        b.record(b.generation, false);
+        assert_eq!(b.state(), CircuitState::Open);
+        assert!(b.try_acquire().is_none());
+    }
+
+    #[test]
+    fn test_opens_after_failure_ratio() {
+        let mut b = breaker(Duration::from_secs(60));
+        for success in [true, false, true, false, true] {
+            b.record(0, success);
+        }
+        assert_eq!(b.state(), CircuitState::Closed);
+        b.record(b.generation, false);
+        assert_eq!(b.state(), CircuitState::Open);
+    }
+
+    #[test]
+    fn test_half_open_probe() {
+        let mut b = breaker(Duration::ZERO);
+        (0..3).for_each(|_| b.record(b.generation, false));
+        assert!(b.try_acquire().is_some());
+        assert_eq!(b.state(), CircuitState::HalfOpen);
+        assert!(b.try_acquire().is_none());
+
+        b.record(b.generation, false);
+        assert_eq!(b.state(), CircuitState::Open);
+
+        assert!(b.try_acquire().is_some());
+        b.record(b.generation, true);
+        assert_eq!(b.state(), CircuitState::Closed);
+        assert!(b.try_acquire().is_some());
+    }
+
+    #[test]
+    fn test_stale_outcomes() {
+        let mut b = breaker(Duration::ZERO);
+        let stale = b.try_acquire().unwrap();
+        (0..3).for_each(|_| b.record(b.generation, false));
+        assert_eq!(b.state(), CircuitState::Open);
+
+        // A request sent while the circuit was closed does not close it again
+        b.record(stale, true);
+        assert_eq!(b.state(), CircuitState::Open);
+        let opened_at = b.opened_at;
+        b.record(stale, false);
+        assert_eq!(b.opened_at, opened_at);
+
+        // Nor does it settle the probe
+        let probe = b.try_acquire().unwrap();
+        b.record(stale, true);
+        b.abandon(stale);
+        assert_eq!(b.state(), CircuitState::HalfOpen);
+        assert!(b.try_acquire().is_none());
+        b.record(probe, true);
+        assert_eq!(b.state(), CircuitState::Closed);
+    }
+}
@@ -0,0 +1,821 @@
+//! Dispatching inference requests to workers
+
+use super::model::ModelInfo;
+use super::scheduler::{Scheduling, Ticket};
+use super::{Handle, Manager, PartialHandle};
+use crate::error::{Error, Result};
+use crate::metrics;
+use crate::rpc;
+use crate::rpc::batch_result;
+use crate::rpc::inference_event::Event;
+use crate::rpc::worker_client::WorkerClient;
+use crate::telemetry;
+use crate::torch;
+use crate::worker::WorkerStatus;
+use crate::worker_auth::WorkerChannel;
+use anyhow::anyhow;
+use serde::{Serialize, Serializer};
+use std::future::Future;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinSet;
+use tonic::Request;
+use tracing::*;
+use utoipa::ToSchema;
+
+/// How long a queued request waits before checking again whether it can take a
+/// worker, if it is not woken up before
+const BUSY_BACKOFF: time::Duration = time::Duration::from_millis(100);
+
+/// Number of events of a streamed inference buffered for the client
+const STREAM_BUFFER: usize = 32;
+
+/// How long past its deadline a request waits for its worker to respond,
+/// before it is cancelled
+const RPC_GRACE: time::Duration = time::Duration::from_secs(1);
+
+/// The result of a dispatched inference request
+#[derive(Debug)]
+pub struct Dispatched {
+    pub inference: torch::TimedInference,
+
+    /// The worker that computed the inference
+    pub worker: PartialHandle,
+
+    /// The model the worker was serving
+    pub model: Arc<ModelInfo>,
+
+    /// Number of times the request was retried on another worker
+    pub retries: u32,
+
+    /// How long the request spent in each stage
+    pub timings: Timings,
+}
+
+impl Dispatched {
+    fn new(served: Served<torch::TimedInference>) -> Dispatched {
+        let timings = Timings {
+            queue: time::Duration::ZERO,
+            rpc: served.rpc,
+            inference: served.output.1,
+        };
+        Dispatched {
+            inference: served.output,
+            worker: served.worker.partial(),
+            model: served.worker.model.clone(),
+            retries: served.retries,
+            timings,
+        }
+    }
+}
+
+/// How long a request spent in each stage, in millis
+#[derive(Debug, Clone, Copy, Default, Serialize, ToSchema)]
+pub struct Timings {
+    /// Waiting for a worker, including failed attempts on other workers
+    #[serde(rename = "queue_ms", serialize_with = "as_millis")]
+    #[schema(value_type = f64)]
+    pub queue: time::Duration,
+
+    /// The round trip to the worker that served the request
+    #[serde(rename = "rpc_ms", serialize_with = "as_millis")]
+    #[schema(value_type = f64)]
+    pub rpc: time::Duration,
+
+    /// Running the model, as measured by the worker
+    #[serde(rename = "inference_ms", serialize_with = "as_millis")]
+    #[schema(value_type = f64)]
+    pub inference: time::Duration,
+}
+
+fn as_millis<S>(duration: &time::Duration, serializer: S) -> std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_f64(duration.as_secs_f64() * 1000.0)
+}
+
+/// A response from a worker
+struct Served<T> {
+    output: T,
+
+    /// The worker that sent the response
+    worker: Handle,
+
+    /// How long the successful request to the worker took
+    rpc: time::Duration,
+
+    /// Number of times the request was retried on another worker
+    retries: u32,
+}
+
+impl Manager {
+    /// Run inference on an idle worker. While all workers are busy, the
+    /// request waits for one in the scheduler's queue, in its place given by
+    /// `scheduling`. Requests that fail to reach their worker are retried on
+    /// another worker, up to `manager.max_retries` times. If no worker
+    /// responds within `timeout`, the request fails with `Error::Timeout`, or
+    /// with `Error::Busy` if it never got a worker. `request_id` is sent to
+    /// the worker along with the request
+    pub async fn dispatch(
+        manager: &Arc<RwLock<Manager>>,
+        input: torch::InferenceTask,
+        request_id: &str,
+        scheduling: &Scheduling,
+        timeout: time::Duration,
+    ) -> Result<Dispatched> {
+        let ty = input.inference_type.clone();
+        let res = Self::dispatch_task(manager, &input, request_id, scheduling, timeout);
+        Self::measure(&ty, res).await
+    }
+
+    /// Run inference on an idle worker in the background, and stream its
+    /// progress and the text it generates as it runs. The last event is the
+    /// result of the inference, or the error it failed with. The inference is
+    /// abandoned once the receiver is dropped
+    pub fn dispatch_stream(
+        manager: &Arc<RwLock<Manager>>,
+        input: torch::InferenceTask,
+        request_id: &str,
+        scheduling: &Scheduling,
+        timeout: time::Duration,
+    ) -> mpsc::Receiver<Result<torch::InferenceEvent>> {
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        let manager = manager.clone();
+        let request_id = request_id.to_string();
+        let scheduling = scheduling.clone();
+        let task = async move {
+            let ty = input.inference_type.clone();
+            let events = tx.clone();
+            let dispatch = async {
+                let call = |channel, timeout| {
+                    let events = events.clone();
+                    Self::run_stream(channel, input.clone(), request_id.clone(), timeout, events)
+                };
+                let served =
+                    Self::dispatch_with_retries(&manager, &scheduling, timeout, call).await?;
+                Ok(Dispatched::new(served))
+            };
+
+            let res = tokio::select! {
+                res = Self::measure(&ty, dispatch) => res,
+                _ = tx.closed() => {
+                    debug!("streaming request was abandoned");
+                    return;
+                }
+            };
+            let _ = tx
+                .send(res.map(|dispatched| torch::InferenceEvent::Done(dispatched.inference)))
+                .await;
+        };
+        tokio::spawn(task.instrument(Span::current()));
+        rx
+    }
+
+    /// Run inference on a batch of tasks, and return the outcome of each task
+    /// in order. Image classifications are sent to workers in groups of up to
+    /// `batch.max_forward_size`, which they run in a single forward pass.
+    /// Groups and other tasks are spread across the workers in parallel, and
+    /// are each queued like a request of their own
+    pub async fn dispatch_batch(
+        manager: &Arc<RwLock<Manager>>,
+        tasks: Vec<torch::InferenceTask>,
+        request_id: &str,
+        scheduling: &Scheduling,
+        timeout: time::Duration,
+    ) -> Result<Vec<Result<torch::TimedInference>>> {
+        let deadline = time::Instant::now() + timeout;
+        let (max_forward_size, parallelism) = {
+            let m = manager.read().unwrap();
+            let max_forward_size = m.config.get_int("batch.max_forward_size")?.max(1) as usize;
+            (max_forward_size, m.workers.len().max(1))
+        };
+
+        let n = tasks.len();
+        let groups = group_tasks(tasks, max_forward_size);
+        debug!("dispatching {n} tasks in {} groups", groups.len());
+
+        // Run at most one group per worker at a time. Dropping the join set
+        // aborts the groups still running, if the request is abandoned
+        let permits = Arc::new(Semaphore::new(parallelism));
+        let mut running = JoinSet::new();
+        for group in groups {
+            let (manager, permits) = (manager.clone(), permits.clone());
+            let (request_id, scheduling) = (request_id.to_string(), scheduling.clone());
+            let task = async move {
+                let _permit = permits.acquire_owned().await;
+                let remaining = deadline.saturating_duration_since(time::Instant::now());
+                let (indices, tasks): (Vec<_>, Vec<_>) = group.into_iter().unzip();
+                let outputs =
+                    Self::dispatch_group(&manager, tasks, &request_id, &scheduling, remaining)
+                        .await;
+                indices.into_iter().zip(outputs).collect::<Vec<_>>()
+            };
+            running.spawn(task.instrument(Span::current()));
+        }
+
+        let mut outputs = vec![];
+        while let Some(group) = running.join_next().await {
+            outputs.push(group.map_err(anyhow::Error::from)?);
+        }
+        Ok(ungroup(n, outputs))
+    }
+
+    /// Run a group of tasks on a worker, in a single request. A single task is
+    /// dispatched on its own
+    async fn dispatch_group(
+        manager: &Arc<RwLock<Manager>>,
+        mut tasks: Vec<torch::InferenceTask>,
+        request_id: &str,
+        scheduling: &Scheduling,
+        timeout: time::Duration,
+    ) -> Vec<Result<torch::TimedInference>> {
+        if tasks.len() == 1 {
+            let task = tasks.remove(0);
+            let res = Self::dispatch(manager, task, request_id, scheduling, timeout).await;
+            return vec![res.map(|dispatched| dispatched.inference)];
+        }
+
+        let types = tasks
+            .iter()
+            .map(|task| task.inference_type.clone())
+            .collect::<Vec<_>>();
+        let start = time::Instant::now();
+        let res = Self::dispatch_with_retries(manager, scheduling, timeout, |channel, timeout| {
+            Self::run_batch(channel, tasks.clone(), request_id.to_string(), timeout)
+        })
+        .await;
+
+        // If the whole group failed, every task failed with the same error
+        let outputs = match res {
+            Ok(served) => served.output,
+            Err(e) => {
+                let status = tonic::Status::from(e);
+                types
+                    .iter()
+                    .map(|_| Err(Error::from_status(status.clone(), timeout)))
+                    .collect()
+            }
+        };
+        for (ty, output) in types.iter().zip(&outputs) {
+            let model_time = output.as_ref().map(|inference| inference.1);
+            metrics::observe_request(ty, model_time, start.elapsed());
+        }
+        outputs
+    }
+
+    /// Record the outcome of a request in the request metrics. The time the
+    /// request spent waiting for a worker is whatever was not spent on its
+    /// successful RPC
+    async fn measure(
+        ty: &torch::InferenceType,
+        dispatch: impl Future<Output = Result<Dispatched>>,
+    ) -> Result<Dispatched> {
+        let start = time::Instant::now();
+        let res = dispatch.await;
+
+        let model_time = res.as_ref().map(|d| d.inference.1);
+        metrics::observe_request(ty, model_time, start.elapsed());
+        res.map(|mut dispatched| {
+            dispatched.timings.queue = start.elapsed().saturating_sub(dispatched.timings.rpc);
+            dispatched
+        })
+    }
+
+    /// Queue a request for an idle worker, and wait until the scheduler gives
+    /// it one, marked as busy, along with the generation of its circuit
+    /// breaker. A `retry` is not charged to its tenant again. Fails with
+    /// `Error::Busy` if the queue is full, or if the request gets no worker
+    /// within `timeout` or the `max_wait` of its scheduling
+    async fn acquire_worker(
+        manager: &Arc<RwLock<Manager>>,
+        scheduling: &Scheduling,
+        timeout: time::Duration,
+        retry: bool,
+    ) -> Result<(Handle, u64)> {
+        let timeout = scheduling
+            .max_wait
+            .map_or(timeout, |wait| wait.min(timeout));
+        let deadline = time::Instant::now() + timeout;
+        let (ticket, notify) = {
+            let mut m = manager.write().unwrap();
+            let ticket = match retry {
+                false => m.scheduler.enqueue(scheduling)?,
+                true => m.scheduler.requeue(scheduling)?,
+            };
+            let notify = m.scheduler.notifier(&ticket).expect("the ticket is queued");
+            (ticket, notify)
+        };
+        let _queued = Queued { manager, ticket };
+
+        loop {
+            // Wake-ups while the worker is being checked for are kept until
+            // the request waits for them, so none is missed
+            let worker = manager.write().unwrap().take_worker(&ticket)?;
+            if let Some(worker) = worker {
+                return Ok(worker);
+            }
+            let remaining = deadline.saturating_duration_since(time::Instant::now());
+            if remaining.is_zero() {
+                warn!("all workers are busy");
+                return Err(Error::Busy);
+            }
+            tokio::select! {
+                _ = notify.notified() => {}
+                _ = tokio::time::sleep(BUSY_BACKOFF.min(remaining)) => {}
+            }
+        }
+    }
+
+    /// Run inference on a single task, with retries
+    async fn dispatch_task(
+        manager: &Arc<RwLock<Manager>>,
+        input: &torch::InferenceTask,
+        request_id: &str,
+        scheduling: &Scheduling,
+        timeout: time::Duration,
+    ) -> Result<Dispatched> {
+        let served =
+            Self::dispatch_with_retries(manager, scheduling, timeout, |channel, timeout| {
+                Self::run_inference(channel, input.clone(), request_id.to_string(), timeout)
+            })
+            .await?;
+        Ok(Dispatched::new(served))
+    }
+
+    /// Make a request to an idle worker with `call`, retrying it on another
+    /// worker if it fails to reach its worker
+    async fn dispatch_with_retries<T, F, Fut>(
+        manager: &Arc<RwLock<Manager>>,
+        scheduling: &Scheduling,
+        timeout: time::Duration,
+        call: F,
+    ) -> Result<Served<T>>
+    where
+        T: Send + 'static,
+        F: Fn(WorkerChannel, time::Duration) -> Fut,
+        Fut: Future<Output = Result<T>> + Send + 'static,
+    {
+        let deadline = time::Instant::now() + timeout;
+        let max_retries = manager
+            .read()
+            .unwrap()
+            .config
+            .get_int("manager.max_retries")? as u32;
+
+        let mut retries = 0;
+        loop {
+            let remaining = deadline.saturating_duration_since(time::Instant::now());
+            let once = Self::dispatch_once(manager, scheduling, remaining, retries > 0, &call);
+            let err = match once.await {
+                Ok(served) => return Ok(Served { retries, ..served }),
+                Err(err) => err,
+            };
+
+            if !err.is_retryable() || retries >= max_retries {
+                return Err(match retries {
+                    0 => err,
+                    n => Error::Retries(n, Box::new(err)),
+                });
+            }
+            retries += 1;
+            warn!("retrying request on another worker ({retries}/{max_retries}): {err}");
+        }
+    }
+
+    /// Make a request to an idle worker with `call`, once, after waiting for
+    /// the worker in the queue, as a `retry` if it failed on another worker
+    /// before. `call` gets the time left until the deadline.
+    /// The request runs in a task of its own, so that the worker stays busy
+    /// until it responds, even if the request is abandoned. A worker that
+    /// could not be reached is marked as `Error` until the monitor has checked
+    /// its health. A worker that overruns `manager.max_overruns` requests in a
+    /// row is replaced. Every outcome is recorded by the worker's circuit
+    /// breaker
+    async fn dispatch_once<T, F, Fut>(
+        manager: &Arc<RwLock<Manager>>,
+        scheduling: &Scheduling,
+        timeout: time::Duration,
+        retry: bool,
+        call: F,
+    ) -> Result<Served<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce(WorkerChannel, time::Duration) -> Fut,
+        Fut: Future<Output = Result<T>> + Send + 'static,
+    {
+        let max_overruns = manager
+            .read()
+            .unwrap()
+            .config
+            .get_int("manager.max_overruns")? as u32;
+
+        // Wait for an idle worker, which is marked as busy
+        let deadline = time::Instant::now() + timeout;
+        let (worker, generation) = Self::acquire_worker(manager, scheduling, timeout, retry)
+            .instrument(info_span!("queue", priority = scheduling.priority.name()))
+            .await?;
+        let timeout = deadline.saturating_duration_since(time::Instant::now());
+
+        // Send the inference request to the worker via RPC. The worker gives
+        // up at the deadline, so the request is only cancelled on our side if
+        // the worker does not respond at all
+        debug!("sending inference request to worker {}", worker.pid);
+        let in_flight = InFlight::start(manager.clone(), worker.clone(), generation);
+        let rpc = call(worker.channel.clone(), timeout);
+        let (manager, pid) = (manager.clone(), worker.pid);
+        let task = async move {
+            let start = time::Instant::now();
+            // A request cancelled past its grace period may still be
+            // running on the worker
+            let (output, abandoned) = match tokio::time::timeout(timeout + RPC_GRACE, rpc).await {
+                Ok(output) => (output, false),
+                Err(_) => (Err(Error::Timeout(timeout)), true),
+            };
+            let rpc = start.elapsed();
+            let worker = in_flight.finish();
+
+            // Mark the worker as Idle again, or as needing a health check
+            {
+                let mut m = manager.write().unwrap();
+                if abandoned {
+                    warn!(
+                        "worker {} did not respond in time, marking it for a health check",
+                        worker.pid
+                    );
+                    m.set_worker_status(worker.pid, WorkerStatus::Error);
+                } else if matches!(output, Err(ref e) if e.is_retryable()) {
+                    warn!(
+                        "worker {} failed, marking it for a health check",
+                        worker.pid
+                    );
+                    m.set_worker_status(worker.pid, WorkerStatus::Error);
+                } else {
+                    m.release_worker(worker.pid);
+                }
+            }
+
+            if matches!(output, Err(Error::Timeout(_))) {
+                Self::overran(&manager, &worker, max_overruns);
+            } else {
+                worker.overruns.store(0, Ordering::SeqCst);
+            }
+
+            let failed = matches!(output, Err(ref e) if e.is_worker_fault());
+            worker.breaker.lock().unwrap().record(generation, !failed);
+
+            output.map(|output| Served {
+                output,
+                worker,
+                rpc,
+                retries: 0,
+            })
+        };
+        tokio::spawn(task.instrument(info_span!("rpc", worker = pid)))
+            .await
+            .map_err(anyhow::Error::from)?
+    }
+
+    /// Record that a request to a worker overran its deadline, and replace
+    /// the worker in the background if it has done so too many times in a row
+    fn overran(manager: &Arc<RwLock<Manager>>, worker: &Handle, max_overruns: u32) {
+        if count_overrun(&worker.overruns, max_overruns, worker.pid) {
+            warn!("replacing unresponsive worker {}", worker.pid);
+            let manager = manager.clone();
+            let pid = worker.pid;
+            tokio::spawn(async move {
+                if let Err(e) = Manager::replace_worker(&manager, pid).await {
+                    error!("failed to replace worker {pid}: {e}");
+                }
+            });
+        }
+    }
+
+    /// Run inference on a worker given an RPC channel to the worker. `timeout`
+    /// is sent to the worker as the gRPC deadline, and `request_id` and the
+    /// current trace context in the request's metadata
+    pub async fn run_inference(
+        channel: WorkerChannel,
+        input: torch::InferenceTask,
+        request_id: String,
+        timeout: time::Duration,
+    ) -> Result<torch::TimedInference> {
+        let mut worker_client = WorkerClient::new(channel);
+        let ty = input.inference_type.clone();
+        let mut req = Request::new(input.into());
+        req.set_timeout(timeout);
+        telemetry::inject(&request_id, req.metadata_mut());
+
+        let rpc_output: rpc::Inference = worker_client
+            .compute_inference(req)
+            .await
+            .map_err(|status| Error::from_status(status, timeout))?
+            .into_inner();
+        parse_inference(&ty, rpc_output)
+    }
+
+    /// Run inference on a worker, forwarding the progress of the inference and
+    /// the text it generates to `events` as it runs, and return its result
+    async fn run_stream(
+        channel: WorkerChannel,
+        input: torch::InferenceTask,
+        request_id: String,
+        timeout: time::Duration,
+        events: mpsc::Sender<Result<torch::InferenceEvent>>,
+    ) -> Result<torch::TimedInference> {
+        let mut worker_client = WorkerClient::new(channel);
+        let ty = input.inference_type.clone();
+        let mut req = Request::new(input.into());
+        req.set_timeout(timeout);
+        telemetry::inject(&request_id, req.metadata_mut());
+
+        let mut stream = worker_client
+            .compute_inference_stream(req)
+            .await
+            .map_err(|status| Error::from_status(status, timeout))?
+            .into_inner();
+
+        let mut forwarded = false;
+        loop {
+            let event = match stream.message().await {
+                Ok(Some(event)) => event,
+                Ok(None) => return Err(Error::Other(anyhow!("worker ended the stream early"))),
+                Err(status) => {
+                    // Once events were forwarded, retrying the request on
+                    // another worker would repeat them
+                    let err = Error::from_status(status, timeout);
+                    return Err(match err {
+                        err if forwarded && err.is_retryable() => {
+                            Error::Worker(Box::new(tonic::Status::internal(err.to_string())))
+                        }
+                        err => err,
+                    });
+                }
+            };
+
+            let event = match event.event {
+                Some(Event::Progress(stage)) => torch::InferenceEvent::Progress(stage),
+                Some(Event::Token(token)) => torch::InferenceEvent::Token(token),
+                Some(Event::Inference(output)) => return parse_inference(&ty, output),
+                None => continue,
+            };
+            events
+                .send(Ok(event))
+                .await
+                .map_err(|_| Error::Other(anyhow!("the stream was closed")))?;
+            forwarded = true;
+        }
+    }
+
+    /// Run inference on a batch of tasks on a worker, in a single request.
+    /// Returns the outcome of each task, in order
+    pub async fn run_batch(
+        channel: WorkerChannel,
+        tasks: Vec<torch::InferenceTask>,
+        request_id: String,
+        timeout: time::Duration,
+    ) -> Result<Vec<Result<torch::TimedInference>>> {
+        let mut worker_client = WorkerClient::new(channel);
+        let types = tasks
+            .iter()
+            .map(|task| task.inference_type.clone())
+            .collect::<Vec<_>>();
+        let tasks = tasks.into_iter().map(Into::into).collect();
+        let mut req = Request::new(rpc::InferenceBatch { tasks });
+        req.set_timeout(timeout);
+        telemetry::inject(&request_id, req.metadata_mut());
+
+        let results = worker_client
+            .compute_batch(req)
+            .await
+            .map_err(|status| Error::from_status(status, timeout))?
+            .into_inner()
+            .results;
+        if results.len() != types.len() {
+            return Err(Error::Other(anyhow!(
+                "worker returned {} results for {} tasks",
+                results.len(),
+                types.len()
+            )));
+        }
+
+        let outputs = types
+            .iter()
+            .zip(results)
+            .map(|(ty, res)| match res.result {
+                Some(batch_result::Result::Inference(output)) => parse_inference(ty, output),
+                Some(batch_result::Result::Error(e)) => Err(Error::from_status(e.into(), timeout)),
+                None => Err(Error::Other(anyhow!("worker returned no result"))),
+            })
+            .collect();
+        Ok(outputs)
+    }
+}
+
+/// Split the tasks of a batch into groups that are each sent to a worker in a
+/// single request, keeping the index of each task. Image classifications are
+/// grouped by up to `max_forward_size`, and other tasks are left on their own
+fn group_tasks(
+    tasks: Vec<torch::InferenceTask>,
+    max_forward_size: usize,
+) -> Vec<Vec<(usize, torch::InferenceTask)>> {
+    let mut groups = vec![];
+    let mut classifications = vec![];
+    for (i, task) in tasks.into_iter().enumerate() {
+        match task.inference_type {
+            torch::InferenceType::ImageClassification { .. } => classifications.push((i, task)),
+            _ => groups.push(vec![(i, task)]),
+        }
+    }
+    while !classifications.is_empty() {
+        let rest = classifications.split_off(classifications.len().min(max_forward_size));
+        groups.push(std::mem::replace(&mut classifications, rest));
+    }
+    groups
+}
+
+/// Put the outcomes of the groups of a batch of `n` tasks back in the order of
+/// the tasks, by their index
+fn ungroup<T>(n: usize, groups: Vec<Vec<(usize, T)>>) -> Vec<T> {
+    let mut results: Vec<Option<T>> = (0..n).map(|_| None).collect();
+    for (i, output) in groups.into_iter().flatten() {
+        results[i] = Some(output);
+    }
+    results
+        .into_iter()
+        .map(|res| res.expect("every task has a result"))
+        .collect()
+}
+
+/// Parse the output of a worker for a type of inference
+fn parse_inference(
+    ty: &torch::InferenceType,
+    rpc_output: rpc::Inference,
+) -> Result<torch::TimedInference> {
+    let missing = |what| Error::Other(anyhow!("worker returned no {what}"));
+    let output = match ty {
+        torch::InferenceType::ImageClassification { .. } => {
+            let classes = rpc_output
+                .classification
+                .ok_or_else(|| missing("classification"))?;
+            torch::Inference::Classification(classes.into())
+        }
+        torch::InferenceType::ImageToImage => {
+            torch::Inference::B64Image(rpc_output.image.ok_or_else(|| missing("image"))?.into())
+        }
+        torch::InferenceType::TextToText => {
+            torch::Inference::Text(rpc_output.text.ok_or_else(|| missing("text"))?)
+        }
+    };
+
+    Ok((output, time::Duration::from_secs_f32(rpc_output.duration)))
+}
+
+/// A request waiting in the scheduler's queue. It leaves the queue when it is
+/// dropped, if it has not already by getting a worker
+struct Queued<'a> {
+    manager: &'a RwLock<Manager>,
+    ticket: Ticket,
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut m) = self.manager.write() {
+            m.scheduler.dequeue(&self.ticket, false);
+        }
+    }
+}
+
+/// A request in flight on a worker. If its task is dropped before the request
+/// finishes, because it panicked or the runtime is shutting down, the worker is
+/// put back into rotation
+struct InFlight {
+    manager: Arc<RwLock<Manager>>,
+    worker: Option<Handle>,
+
+    /// The generation of the worker's circuit breaker the request was sent in
+    generation: u64,
+}
+
+impl InFlight {
+    fn start(manager: Arc<RwLock<Manager>>, worker: Handle, generation: u64) -> Self {
+        worker.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight {
+            manager,
+            worker: Some(worker),
+            generation,
+        }
+    }
+
+    /// Mark the request as finished, and return the worker that served it
+    fn finish(mut self) -> Handle {
+        let worker = self.worker.take().unwrap();
+        worker.in_flight.fetch_sub(1, Ordering::SeqCst);
+        worker
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            worker.in_flight.fetch_sub(1, Ordering::SeqCst);
+            debug!("request to worker {} was abandoned", worker.pid);
+            if let Ok(mut m) = self.manager.write() {
+                m.release_worker(worker.pid);
+            }
+            worker.breaker.lock().unwrap().abandon(self.generation);
+        }
+    }
+}
+
+/// Count an overrun of a worker, returning whether it has now overrun
+/// `max_overruns` requests in a row (0 = never), and should be replaced
+fn count_overrun(overruns: &AtomicU32, max_overruns: u32, pid: u32) -> bool {
+    let overruns = overruns.fetch_add(1, Ordering::SeqCst) + 1;
+    warn!("worker {pid} overran its deadline ({overruns} in a row)");
+    max_overruns > 0 && overruns == max_overruns
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torch::{InferenceTask, InferenceType, InputData};
+
+    fn task(inference_type: InferenceType) -> InferenceTask {
+        InferenceTask {
+            data: InputData::Text(String::new()),
+            inference_type,
+        }
+    }
+
+    #[test]
+    fn test_group_tasks() {
+        let classification = InferenceType::ImageClassification { top_n: 1 };
+        let tasks = vec![
+            task(classification.clone()),
+            task(InferenceType::TextToText),
+            task(classification.clone()),
+            task(classification.clone()),
+            task(InferenceType::ImageToImage),
+        ];
+
+        let groups = group_tasks(tasks, 2)
+            .into_iter()
+            .map(|group| group.into_iter().map(|(i, _)| i).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(groups, vec![vec![1], vec![4], vec![0, 2], vec![3]]);
+    }
+
+    #[test]
+    fn test_batch_order() {
+        // Each task's output is its own input, whatever order its group
+        // finishes in
+        let tasks = (0..7)
+            .map(|i| {
+                let ty = match i % 3 {
+                    0 => InferenceType::TextToText,
+                    _ => InferenceType::ImageClassification { top_n: 1 },
+                };
+                InferenceTask {
+                    data: InputData::Text(i.to_string()),
+                    inference_type: ty,
+                }
+            })
+            .collect::<Vec<_>>();
+        let outputs = group_tasks(tasks, 2)
+            .into_iter()
+            .rev()
+            .map(|group| {
+                group
+                    .into_iter()
+                    .map(|(i, task)| match task.data {
+                        InputData::Text(text) => (i, text),
+                        _ => unreachable!(),
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let expected = (0..7).map(|i| i.to_string()).collect::<Vec<_>>();
+        assert_eq!(ungroup(7, outputs), expected);
+    }
+
+    #[test]
+    fn test_count_overrun() {
+        // A worker is replaced once, when it reaches the limit
+        let overruns = AtomicU32::new(0);
+        let replaced = (0..4)
+            .map(|_| count_overrun(&overruns, 3, 1))
+            .collect::<Vec<_>>();
+        assert_eq!(replaced, vec![false, false, true, false]);
+
+        // A request served in time resets the count
+        overruns.store(0, Ordering::SeqCst);
+        assert!(!count_overrun(&overruns, 3, 1));
+
+        let overruns = AtomicU32::new(0);
+        assert!((0..10).all(|_| !count_overrun(&overruns, 0, 1)));
+    }
+}
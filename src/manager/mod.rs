@@ -0,0 +1,619 @@
+//! The manager (worker manager, resource allocator, allocator) is responsible for
+//! interfacing with a set of workers. The manager starts and stops workers, and
+//! forwards inference requests
+
+use crate::logs::{self, LogConfig, RotatingFile, WorkerLogs};
+use crate::metrics;
+use crate::rpc;
+use crate::rpc::worker_client::WorkerClient;
+use crate::util;
+use crate::worker::WorkerStatus;
+use crate::worker_auth::{WorkerChannel, WorkerToken, TOKEN_ENV};
+use anyhow::anyhow;
+use anyhow::Result;
+use config::Config;
+use rand::seq::SliceRandom;
+
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use serde::ser::Serialize;
+use serde::Serialize as DeriveSerialize;
+use std::collections::{HashMap, VecDeque};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use std::time;
+
+use tokio::task::JoinSet;
+use tokio_stream::StreamExt;
+use tonic::transport::Endpoint;
+use tonic::transport::Uri;
+use tonic::Request;
+use tracing::*;
+
+pub mod breaker;
+pub mod dispatch;
+pub mod model;
+pub mod monitor;
+pub mod pool;
+pub mod scheduler;
+
+use breaker::{BreakerConfig, CircuitBreaker, CircuitState};
+use model::ModelInfo;
+use scheduler::{Scheduler, Ticket};
+
+/// A handle to a worker
+#[derive(Clone)]
+pub struct Handle {
+    pub port: u16,
+    pub pid: u32,
+    pub channel: WorkerChannel,
+
+    /// When the worker process was started
+    pub started: time::Instant,
+
+    /// Number of requests currently being served by the worker
+    pub in_flight: Arc<AtomicUsize>,
+
+    /// Number of consecutive requests that overran their deadline
+    pub overruns: Arc<AtomicU32>,
+
+    /// Tracks the worker's recent failures, to take it out of rotation
+    pub breaker: Arc<Mutex<CircuitBreaker>>,
+
+    /// Where the worker's output is logged
+    pub logs: WorkerLogs,
+
+    /// The model the worker was started with
+    pub model: Arc<ModelInfo>,
+}
+
+impl Handle {
+    pub fn partial(&self) -> PartialHandle {
+        PartialHandle {
+            pid: self.pid,
+            port: self.port,
+        }
+    }
+}
+
+/// The state of a worker, as reported by `/workers/_status`
+#[derive(Debug, Clone, DeriveSerialize)]
+pub struct WorkerState {
+    pub port: u16,
+    pub status: WorkerStatus,
+    pub circuit: CircuitState,
+    pub logs: WorkerLogs,
+}
+
+/// The statistics of a worker, as reported by `/workers/_info`
+#[derive(Debug, Clone, DeriveSerialize)]
+pub struct WorkerInfo {
+    pub port: u16,
+
+    #[serde(flatten)]
+    pub stats: rpc::Stats,
+}
+
+/// A (pid, port) tuple
+#[derive(Clone, Debug, DeriveSerialize, Eq, PartialEq, Hash)]
+pub struct PartialHandle {
+    pub port: u16,
+    pub pid: u32,
+}
+
+impl Serialize for Handle {
+    fn serialize<S>(&self, serializer: S) -> std::prelude::v1::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let s = format!("{:?}", self);
+        serializer.serialize_str(&s)
+    }
+}
+
+/// The worker manager. Right now, assumes that all workers
+/// are on the same host
+#[derive(Debug)]
+pub struct Manager {
+    /// Map from PID to `Handle`s of current workers
+    workers: HashMap<u32, (Handle, WorkerStatus)>,
+
+    /// The most recently stopped workers, oldest first, whose logs are kept
+    /// around for inspection
+    dead: VecDeque<Handle>,
+
+    /// The TorchScript model file served by new workers
+    model: Arc<ModelInfo>,
+
+    /// The queue of requests waiting for a worker
+    scheduler: Scheduler,
+
+    /// The secret workers require in requests, so that only this manager
+    /// can use them
+    token: WorkerToken,
+
+    /// System configuration
+    pub config: Config,
+}
+
+impl Manager {
+    /// Start a new manager and start `NUM_INIT_WORKERS` new worker processes
+    pub async fn new(model_file: &str, config: Config) -> Result<Self> {
+        let model = ModelInfo::load_blocking(model_file).await?;
+        let mut m = Manager {
+            workers: HashMap::new(),
+            dead: VecDeque::new(),
+            model: Arc::new(model),
+            scheduler: Scheduler::from_config(&config)?,
+            token: WorkerToken::generate(),
+            config: config.clone(),
+        };
+
+        m.start_new_workers(config.get_int("manager.num_init_workers")? as u16)
+            .await?;
+        Ok(m)
+    }
+
+    /// Start a new worker process on the local machine and connect to it
+    #[tracing::instrument(skip(self))]
+    async fn start_new_worker(&mut self) -> Result<Handle> {
+        if self.workers.len() >= self.config.get_int("manager.max_workers")? as usize {
+            return Err(anyhow!(
+                "maximum number of workers exceeded. cannot allocate any more",
+            ));
+        }
+
+        let handle =
+            Self::spawn_worker(self.model.clone(), self.config.clone(), self.token.clone()).await?;
+        self.workers
+            .insert(handle.pid, (handle.clone(), WorkerStatus::Idle));
+        Ok(handle)
+    }
+
+    /// Spawn a new worker process on the local machine and connect to it,
+    /// without registering it with the manager
+    async fn spawn_worker(
+        model: Arc<ModelInfo>,
+        cfg: Config,
+        token: WorkerToken,
+    ) -> Result<Handle> {
+        // Find an open port
+        let port = util::get_available_port().unwrap(); // Use ok_or here
+        debug!("found free port {port}");
+
+        let breaker = CircuitBreaker::new(BreakerConfig::from_config(&cfg)?);
+
+        // Forward worker's logs to rotating files
+        let log_config = LogConfig::from_config(&cfg)?;
+        let t = util::time();
+        let logs = WorkerLogs {
+            stdout: log_config.dir.join(format!("worker_{port}_{t}.out")),
+            stderr: log_config.dir.join(format!("worker_{port}_{t}.err")),
+        };
+        let out_log = RotatingFile::create(&logs.stdout, log_config.clone())?;
+        let err_log = RotatingFile::create(&logs.stderr, log_config)?;
+
+        // Start a new thread to spawn a new process
+        let model_file = model.path.clone();
+        let secret = token.secret().to_string();
+        let (pid, ch) = tokio::task::spawn(async move {
+            // Spawn the new worker process
+            let config_file = std::env::args().collect::<Vec<String>>();
+            let config_file = config_file.get(1).unwrap();
+            let command = format!("{} {} {}", port, config_file, model_file);
+            let args = command.split(' ').map(|n| n.to_string());
+            let mut child = Command::new(cfg.get_string("worker.binary")?)
+                .env("RUST_LOG", cfg.get_string("manager.logging")?)
+                .env(TOKEN_ENV, secret)
+                .args(args)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()?;
+            let pid = child.id();
+            logs::capture(child.stdout.take().unwrap(), out_log);
+            logs::capture(child.stderr.take().unwrap(), err_log);
+
+            // Reap the process once it exits, and count it as a crash unless
+            // it exited cleanly
+            std::thread::spawn(move || match child.wait() {
+                Ok(status) if is_clean_exit(status) => {
+                    info!("worker process {pid} exited with {status}")
+                }
+                Ok(status) => {
+                    error!("worker process {pid} crashed: {status}");
+                    metrics::CRASHES.inc();
+                }
+                Err(e) => error!("failed to wait on worker process {pid}: {e}"),
+            });
+
+            info!("manager started new worker process {pid}");
+
+            // Build the rpc endpoint
+            let endpoint =
+                Endpoint::new(format!("http://[::1]:{port}").parse::<Uri>().unwrap()).unwrap();
+
+            // Spin until the connection succeeds
+            let now = time::Instant::now();
+            loop {
+                match endpoint.connect().await {
+                    Ok(channel) => {
+                        return Ok((pid, channel));
+                    }
+                    Err(_) => {
+                        if now.elapsed().as_millis()
+                            >= cfg.get_int("manager.worker_timeout")? as u128
+                        {
+                            return Err(anyhow!("timeout connecting to new worker process"));
+                        }
+                        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                    }
+                }
+            }
+        })
+        .await
+        .unwrap()?;
+
+        info!("manager successfully connected to new worker (port = {port}, pid = {pid})",);
+        metrics::SPAWNS.inc();
+
+        Ok(Handle {
+            port,
+            pid,
+            channel: token.channel(ch),
+            started: time::Instant::now(),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            overruns: Arc::new(AtomicU32::new(0)),
+            breaker: Arc::new(Mutex::new(breaker)),
+            logs,
+            model,
+        })
+    }
+
+    /// Stop a worker process and remove it from the manager. Its logs are
+    /// kept until `logging.max_dead_workers` more workers have been stopped
+    pub fn stop_worker(&mut self, pid: u32) -> Result<()> {
+        let (handle, _) = self
+            .workers
+            .remove(&pid)
+            .ok_or_else(|| anyhow!("no worker with pid {pid}"))?;
+        signal::kill(Pid::from_raw(pid as i32), Signal::SIGTERM)?;
+        info!("manager stopped worker process {pid}");
+
+        self.dead.push_back(handle);
+        let max_dead = self.config.get_int("logging.max_dead_workers")? as usize;
+        while self.dead.len() > max_dead {
+            if let Some(handle) = self.dead.pop_front() {
+                handle.logs.remove();
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop sending requests to a worker, wait for its in-flight requests to
+    /// finish (up to `manager.drain_timeout` millis), then stop it
+    pub async fn drain_worker(manager: &RwLock<Manager>, pid: u32) -> Result<()> {
+        let (handle, timeout) = {
+            let mut m = manager.write().unwrap();
+            let timeout = m.config.get_int("manager.drain_timeout")? as u64;
+            let (handle, status) = m
+                .workers
+                .get_mut(&pid)
+                .ok_or_else(|| anyhow!("no worker with pid {pid}"))?;
+            *status = WorkerStatus::ShuttingDown;
+            (handle.clone(), time::Duration::from_millis(timeout))
+        };
+        debug!("draining worker {pid}");
+
+        let now = time::Instant::now();
+        loop {
+            tokio::time::sleep(time::Duration::from_millis(50)).await;
+            if handle.in_flight.load(Ordering::SeqCst) == 0 {
+                break;
+            }
+            if now.elapsed() >= timeout {
+                warn!("worker {pid} did not drain in time, stopping it anyway");
+                break;
+            }
+        }
+
+        manager.write().unwrap().stop_worker(pid)
+    }
+
+    /// Drain a worker and replace it with a newly spawned one
+    pub async fn replace_worker(manager: &RwLock<Manager>, pid: u32) -> Result<Handle> {
+        Self::drain_worker(manager, pid).await?;
+
+        let (model, cfg, token) = {
+            let m = manager.read().unwrap();
+            (m.model.clone(), m.config.clone(), m.token.clone())
+        };
+        let handle = Self::spawn_worker(model, cfg, token).await?;
+
+        manager
+            .write()
+            .unwrap()
+            .workers
+            .insert(handle.pid, (handle.clone(), WorkerStatus::Idle));
+        info!("replaced worker {pid} with worker {}", handle.pid);
+        Ok(handle)
+    }
+
+    // ----- Interface ----- //
+
+    /// Set the status of a worker. A worker that is shutting down keeps its
+    /// status, so that it is never put back into rotation
+    pub fn set_worker_status(&mut self, pid: u32, status: WorkerStatus) {
+        if let Some((_, s)) = self.workers.get_mut(&pid) {
+            if *s != WorkerStatus::ShuttingDown {
+                *s = status;
+            }
+        }
+        self.scheduler.wake();
+    }
+
+    /// Mark a worker as idle once it has finished a request. Only workers
+    /// marked as `Working` are changed, so a worker that was marked as
+    /// failing in the meantime stays out of rotation
+    pub fn release_worker(&mut self, pid: u32) {
+        if let Some((_, s)) = self.workers.get_mut(&pid) {
+            if *s == WorkerStatus::Working {
+                *s = WorkerStatus::Idle;
+            }
+        }
+        self.scheduler.wake();
+    }
+
+    /// Give the request holding `ticket` an idle worker whose circuit breaker
+    /// allows a request, if the scheduler lets it take one now, along with the
+    /// breaker's generation to record the outcome with. The worker is marked
+    /// as busy, and the request leaves the queue
+    pub fn take_worker(&mut self, ticket: &Ticket) -> Result<Option<(Handle, u64)>> {
+        let idle = self
+            .workers
+            .values()
+            .filter(|&(_, s)| *s == WorkerStatus::Idle)
+            .map(|(handle, _)| handle)
+            .collect::<Vec<_>>();
+        if !self.scheduler.may_start(ticket, idle.len()) {
+            return Ok(None);
+        }
+        let Some((worker, generation)) = idle.into_iter().find_map(|handle| {
+            let generation = handle.breaker.lock().unwrap().try_acquire()?;
+            Some((handle.clone(), generation))
+        }) else {
+            return Ok(None);
+        };
+
+        if !self.config.get_bool("manager.fast_workers")? {
+            self.set_worker_status(worker.pid, WorkerStatus::Working);
+            debug!("set idle worker to busy");
+        }
+        self.scheduler.dequeue(ticket, true);
+        Ok(Some((worker, generation)))
+    }
+
+    /// Get the statuses of all workers
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub fn all_status(&self) -> Result<HashMap<Handle, WorkerStatus>> {
+        Ok(self
+            .workers
+            .values()
+            .map(|(handle, status)| (handle.clone(), status.clone()))
+            .collect())
+    }
+
+    /// Get the statuses, circuit breaker states and logs of all workers by
+    /// PID, including the most recently stopped ones
+    pub fn all_states(&self) -> HashMap<u32, WorkerState> {
+        let live = self
+            .workers
+            .values()
+            .map(|(handle, status)| (handle, status.clone()));
+        self.dead
+            .iter()
+            .map(|handle| (handle, WorkerStatus::Dead))
+            .chain(live)
+            .map(|(handle, status)| {
+                let state = WorkerState {
+                    port: handle.port,
+                    status,
+                    circuit: handle.breaker.lock().unwrap().state(),
+                    logs: handle.logs.clone(),
+                };
+                (handle.pid, state)
+            })
+            .collect()
+    }
+
+    /// Get the states of the workers currently computing inference, by PID
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub fn all_workers(&self) -> HashMap<u32, WorkerState> {
+        self.all_states()
+            .into_iter()
+            .filter(|(_, state)| state.status == WorkerStatus::Working)
+            .collect()
+    }
+
+    /// The model served by new workers. Workers started before a reload may
+    /// still be serving the previous one
+    pub fn model(&self) -> Arc<ModelInfo> {
+        self.model.clone()
+    }
+
+    /// Return all the workers, without their status
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub fn workers(&self) -> Vec<PartialHandle> {
+        self.workers
+            .values()
+            .map(|(w, _)| PartialHandle {
+                pid: w.pid,
+                port: w.port,
+            })
+            .collect()
+    }
+
+    /// Get statistics of all workers, keyed by PID. Workers are queried
+    /// concurrently, and workers that fail to respond are left out
+    #[tracing::instrument(skip(manager))]
+    pub async fn all_stats(manager: &RwLock<Manager>) -> HashMap<u32, WorkerInfo> {
+        let handles = manager
+            .read()
+            .unwrap()
+            .workers
+            .values()
+            .map(|(handle, _)| handle.clone())
+            .collect::<Vec<_>>();
+
+        let mut requests = JoinSet::new();
+        for handle in handles {
+            requests.spawn(async move {
+                debug!("getting stats of worker pid {}", handle.pid);
+                let stats = Self::get_stats(handle.channel.clone()).await;
+                (handle, stats)
+            });
+        }
+
+        let mut map = HashMap::new();
+        while let Some(res) = requests.join_next().await {
+            match res {
+                Ok((handle, Ok(stats))) => {
+                    let info = WorkerInfo {
+                        port: handle.port,
+                        stats,
+                    };
+                    map.insert(handle.pid, info);
+                }
+                Ok((handle, Err(e))) => warn!("failed to get stats of worker {}: {e}", handle.pid),
+                Err(e) => error!("failed to join stats request: {e}"),
+            }
+        }
+
+        map
+    }
+
+    /// Count the workers in rotation that respond to a health check, within
+    /// `manager.worker_timeout` millis, with their model loaded
+    #[tracing::instrument(skip(manager))]
+    pub async fn healthy_workers(manager: &RwLock<Manager>) -> Result<usize> {
+        let (handles, timeout) = {
+            let m = manager.read().unwrap();
+            let handles = m
+                .workers
+                .values()
+                .filter(|(_, s)| matches!(s, WorkerStatus::Idle | WorkerStatus::Working))
+                .map(|(handle, _)| handle.clone())
+                .collect::<Vec<_>>();
+            let timeout = m.config.get_int("manager.worker_timeout")? as u64;
+            (handles, time::Duration::from_millis(timeout))
+        };
+
+        let mut checks = JoinSet::new();
+        for handle in handles {
+            checks.spawn(tokio::time::timeout(
+                timeout,
+                Self::get_health(handle.channel.clone()),
+            ));
+        }
+
+        let mut healthy = 0;
+        while let Some(res) = checks.join_next().await {
+            if let Ok(Ok(Ok(health))) = res {
+                healthy += health.model_loaded as usize;
+            }
+        }
+        Ok(healthy)
+    }
+
+    /// Get the statistics of a single worker given an RPC channel to the worker
+    pub async fn get_stats(channel: WorkerChannel) -> Result<rpc::Stats> {
+        let req = Request::new(rpc::Empty {});
+        let mut worker_client = WorkerClient::new(channel);
+        Ok(worker_client.get_stats(req).await?.into_inner())
+    }
+
+    /// Get the health of a single worker given an RPC channel to the worker
+    pub async fn get_health(channel: WorkerChannel) -> Result<rpc::Health> {
+        let req = Request::new(rpc::Empty {});
+        let mut worker_client = WorkerClient::new(channel);
+        Ok(worker_client.get_health(req).await?.into_inner())
+    }
+
+    /// Start a new worker process on the local machine and connect to it
+    #[tracing::instrument(skip(self))]
+    pub async fn start_new_workers(&mut self, n: u16) -> Result<()> {
+        let mut stream = tokio_stream::iter(0..n);
+        while let Some(_) = stream.next().await {
+            self.start_new_worker().await?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Handle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Handle {{ port: {}, pid: {} }}", self.port, self.pid)
+    }
+}
+
+impl std::hash::Hash for Handle {
+    fn hash<H>(&self, state: &mut H)
+    where
+        H: std::hash::Hasher,
+    {
+        // Skip hashing the channel
+        (&self.port, &self.pid).hash(state);
+    }
+}
+impl PartialEq for Handle {
+    fn eq(&self, other: &Self) -> bool {
+        self.port == other.port && self.pid == other.pid
+    }
+}
+impl Eq for Handle {}
+
+#[cfg(test)]
+impl Manager {
+    /// A manager without workers, for tests. It never starts any, as its
+    /// model file does not exist
+    pub(crate) fn without_workers(config: Config) -> Manager {
+        Manager {
+            workers: HashMap::new(),
+            dead: VecDeque::new(),
+            model: Arc::new(ModelInfo {
+                name: "model".into(),
+                version: "0123456789ab".into(),
+                path: "model.pt".into(),
+            }),
+            scheduler: Scheduler::new(0, 0),
+            token: WorkerToken::generate(),
+            config,
+        }
+    }
+}
+
+/// Whether a worker process exited cleanly: on its own with code 0, or when
+/// stopped by the manager (SIGTERM) or interrupted along with it (SIGINT)
+fn is_clean_exit(status: ExitStatus) -> bool {
+    let stopped = [Signal::SIGTERM, Signal::SIGINT].map(|signal| Some(signal as i32));
+    status.success() || stopped.contains(&status.signal())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clean_exit() {
+        // Raw wait statuses: the exit code is in the second byte, and the
+        // signal that killed the process in the first
+        assert!(is_clean_exit(ExitStatus::from_raw(0)));
+        assert!(is_clean_exit(ExitStatus::from_raw(Signal::SIGTERM as i32)));
+        assert!(is_clean_exit(ExitStatus::from_raw(Signal::SIGINT as i32)));
+        assert!(!is_clean_exit(ExitStatus::from_raw(1 << 8)));
+        assert!(!is_clean_exit(ExitStatus::from_raw(Signal::SIGSEGV as i32)));
+        assert!(!is_clean_exit(ExitStatus::from_raw(Signal::SIGKILL as i32)));
+    }
+}
@@ -0,0 +1,90 @@
+//! The identity of the model served by the workers, reported with every
+//! inference so that clients can tell which model produced it
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use std::fs::File;
+use std::path::Path;
+
+/// Number of hex digits of the model file's digest used as its version
+const VERSION_LEN: usize = 12;
+
+/// A model file, identified by its name and a digest of its contents
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ModelInfo {
+    /// The file name of the model, without its extension
+    pub name: String,
+
+    /// The first hex digits of the SHA-256 digest of the model file, which
+    /// change whenever the model does
+    pub version: String,
+
+    /// The path to the TorchScript file
+    pub path: String,
+}
+
+impl ModelInfo {
+    /// Identify the model file at `path`. The whole file is read, so this is
+    /// only done when a model is loaded
+    pub fn load(path: &str) -> Result<ModelInfo> {
+        let mut file = File::open(path).with_context(|| format!("cannot open model {path}"))?;
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut file, &mut hasher)?;
+        let version = hasher.finalize().iter().fold(String::new(), |mut hex, b| {
+            let _ = write!(hex, "{b:02x}");
+            hex
+        });
+
+        let name = Path::new(path)
+            .file_stem()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        Ok(ModelInfo {
+            name,
+            version: version[..VERSION_LEN].to_string(),
+            path: path.to_string(),
+        })
+    }
+
+    /// Identify the model file at `path` on a blocking thread, so that reading
+    /// a large model does not hold up the async runtime
+    pub async fn load_blocking(path: &str) -> Result<ModelInfo> {
+        let path = path.to_string();
+        tokio::task::spawn_blocking(move || ModelInfo::load(&path)).await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_load_blocking() {
+        let path = std::env::temp_dir().join(format!("autodep_blocking_{}.pt", std::process::id()));
+        std::fs::write(&path, b"model").unwrap();
+        let path = path.to_str().unwrap();
+        let model = ModelInfo::load_blocking(path).await.unwrap();
+        assert_eq!(model, ModelInfo::load(path).unwrap());
+        std::fs::remove_file(path).unwrap();
+        assert!(ModelInfo::load_blocking(path).await.is_err());
+    }
+
+    #[test]
+    fn test_load() {
+        let path = std::env::temp_dir().join(format!("autodep_model_{}.pt", std::process::id()));
+        std::fs::write(&path, b"model").unwrap();
+        let model = ModelInfo::load(path.to_str().unwrap()).unwrap();
+        assert_eq!(model.name, format!("autodep_model_{}", std::process::id()));
+        assert_eq!(model.version.len(), VERSION_LEN);
+
+        // The version changes with the contents of the file
+        std::fs::write(&path, b"another model").unwrap();
+        let other = ModelInfo::load(path.to_str().unwrap()).unwrap();
+        assert_ne!(model.version, other.version);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(ModelInfo::load("/nonexistent/model.pt").is_err());
+    }
+}
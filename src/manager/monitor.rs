@@ -0,0 +1,230 @@
+//! Background monitoring of worker processes. Workers that failed a request
+//! are health checked, and put back into rotation or replaced. Long-running
+//! workers slowly grow their memory usage, so each worker is also periodically
+//! checked against the configured limits and recycled once it crosses one of
+//! them. Finally, the pool is scaled to stay within its bounds
+
+use super::Manager;
+use crate::util;
+use crate::worker::WorkerStatus;
+use anyhow::Result;
+use config::Config;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::*;
+
+/// Per-worker limits. A limit of zero means unlimited
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// Maximum number of requests served
+    pub max_requests: u64,
+
+    /// Maximum resident set size, in bytes
+    pub max_rss: u64,
+
+    /// Maximum age of the worker process
+    pub max_age: Duration,
+}
+
+impl Limits {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        Ok(Limits {
+            max_requests: config.get_int("worker.max_requests")? as u64,
+            max_rss: config.get_int("worker.max_rss_mb")? as u64 * 1024 * 1024,
+            max_age: Duration::from_secs(config.get_int("worker.max_age")? as u64),
+        })
+    }
+
+    /// Return the reason a worker should be recycled, if it has exceeded any limit
+    pub fn exceeded(&self, reqs_served: u64, rss: u64, age: Duration) -> Option<String> {
+        if self.max_requests > 0 && reqs_served >= self.max_requests {
+            return Some(format!("served {reqs_served} requests"));
+        }
+        if self.max_rss > 0 && rss >= self.max_rss {
+            return Some(format!("rss of {} MB", rss / 1024 / 1024));
+        }
+        if !self.max_age.is_zero() && age >= self.max_age {
+            return Some(format!("age of {}s", age.as_secs()));
+        }
+        None
+    }
+}
+
+/// Periodically check all workers, forever
+pub async fn run(manager: Arc<RwLock<Manager>>) {
+    loop {
+        let interval = {
+            let m = manager.read().unwrap();
+            m.config.get_int("manager.monitor_interval").unwrap_or(5000) as u64
+        };
+        tokio::time::sleep(Duration::from_millis(interval)).await;
+
+        if let Err(e) = check_health(&manager).await {
+            error!("failed to check worker health: {e}");
+        }
+        if let Err(e) = recycle(&manager).await {
+            error!("failed to recycle workers: {e}");
+        }
+        if let Err(e) = scale(&manager).await {
+            error!("failed to scale the worker pool: {e}");
+        }
+    }
+}
+
+/// Check the health of every worker marked as `Error`. Healthy workers are put
+/// back into rotation, and unhealthy ones are replaced. A worker that cannot
+/// be replaced is left for the next check, and the others are still checked
+async fn check_health(manager: &RwLock<Manager>) -> Result<()> {
+    let handles = manager
+        .read()
+        .unwrap()
+        .all_status()?
+        .into_iter()
+        .filter(|(_, s)| *s == WorkerStatus::Error)
+        .map(|(h, _)| h)
+        .collect::<Vec<_>>();
+
+    for handle in handles {
+        let pid = handle.pid;
+        match Manager::get_health(handle.channel.clone()).await {
+            Ok(health) if health.model_loaded => {
+                info!("worker {pid} is healthy again");
+                manager
+                    .write()
+                    .unwrap()
+                    .set_worker_status(pid, WorkerStatus::Idle);
+            }
+            _ => {
+                warn!("worker {pid} failed its health check, replacing it");
+                if let Err(e) = Manager::replace_worker(manager, pid).await {
+                    error!("failed to replace worker {pid}: {e}");
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Replace every worker that has exceeded its limits
+async fn recycle(manager: &RwLock<Manager>) -> Result<()> {
+    let (handles, limits) = {
+        let m = manager.read().unwrap();
+        let handles = m
+            .all_status()?
+            .into_iter()
+            .filter(|(_, s)| *s != WorkerStatus::ShuttingDown)
+            .map(|(h, _)| h)
+            .collect::<Vec<_>>();
+        (handles, Limits::from_config(&m.config)?)
+    };
+
+    for handle in handles {
+        // A limit that cannot be read is counted as 0, which never exceeds
+        // it, so that the other limits, and the age in particular, are still
+        // enforced on a worker that fails to report its stats
+        let pid = handle.pid;
+        let reqs_served = match Manager::get_stats(handle.channel.clone()).await {
+            Ok(stats) => stats.reqs_served,
+            Err(e) => {
+                warn!("failed to get stats of worker {pid}: {e}");
+                0
+            }
+        };
+        let rss = match util::rss(pid) {
+            Ok(rss) => rss,
+            Err(e) => {
+                warn!("failed to get rss of worker {pid}: {e}");
+                0
+            }
+        };
+
+        if let Some(reason) = limits.exceeded(reqs_served, rss, handle.started.elapsed()) {
+            info!("recycling worker {pid} after {reason}");
+            if let Err(e) = Manager::replace_worker(manager, pid).await {
+                error!("failed to recycle worker {pid}: {e}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Keep the number of workers between `manager.min_workers` and
+/// `manager.max_workers`. With `manager.auto_scale`, a worker is also started
+/// when no worker is idle, and an idle worker is stopped when more than half
+/// of the workers are idle
+async fn scale(manager: &RwLock<Manager>) -> Result<()> {
+    let (live, idle, settings) = {
+        let m = manager.read().unwrap();
+        let idle = m
+            .all_status()?
+            .into_iter()
+            .filter(|(_, s)| *s == WorkerStatus::Idle)
+            .map(|(h, _)| h.pid)
+            .collect::<Vec<_>>();
+        (m.live_workers(), idle, m.pool_settings()?)
+    };
+    let (min, max) = (settings.min_workers as usize, settings.max_workers as usize);
+
+    let mut target = live;
+    if settings.auto_scale {
+        if idle.is_empty() {
+            target += 1;
+        } else if idle.len() * 2 > live {
+            target -= 1;
+        }
+    }
+    let target = target.clamp(min, max);
+
+    if target > live {
+        info!("scaling the worker pool up from {live} to {target} workers");
+        Manager::add_workers(manager, target - live).await?;
+    } else if target < live {
+        info!("scaling the worker pool down from {live} to {target} workers");
+        for &pid in idle.iter().take(live - target) {
+            Manager::drain_worker(manager, pid).await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits {
+            max_requests: 100,
+            max_rss: 1024 * 1024 * 1024,
+            max_age: Duration::from_secs(3600),
+        };
+        let young = Duration::from_secs(60);
+        assert_eq!(limits.exceeded(99, 0, young), None);
+        assert_eq!(
+            limits.exceeded(100, 0, young).unwrap(),
+            "served 100 requests"
+        );
+        assert_eq!(
+            limits.exceeded(0, 2 * 1024 * 1024 * 1024, young).unwrap(),
+            "rss of 2048 MB"
+        );
+
+        // The age is checked even when the stats could not be read
+        let old = Duration::from_secs(7200);
+        assert_eq!(limits.exceeded(0, 0, old).unwrap(), "age of 7200s");
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let limits = Limits {
+            max_requests: 0,
+            max_rss: 0,
+            max_age: Duration::ZERO,
+        };
+        let age = Duration::from_secs(u32::MAX as u64);
+        assert_eq!(limits.exceeded(u64::MAX, u64::MAX, age), None);
+    }
+}
@@ -0,0 +1,265 @@
+//! Changing the worker pool at runtime: adding and removing workers, changing
+//! the pool settings, and reloading the model
+
+use super::model::ModelInfo;
+use super::{Manager, PartialHandle};
+use crate::error::{Error, Result};
+use crate::worker::WorkerStatus;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use tracing::*;
+
+/// The settings of the worker pool that can be changed at runtime
+#[derive(Debug, Clone, Serialize)]
+pub struct PoolSettings {
+    pub max_workers: u32,
+    pub min_workers: u32,
+    pub auto_scale: bool,
+    pub fast_workers: bool,
+}
+
+/// A change to the pool settings. Settings that are left out are unchanged
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PoolUpdate {
+    pub max_workers: Option<u32>,
+    pub min_workers: Option<u32>,
+    pub auto_scale: Option<bool>,
+    pub fast_workers: Option<bool>,
+}
+
+impl Manager {
+    /// Get the current pool settings
+    pub fn pool_settings(&self) -> Result<PoolSettings> {
+        Ok(PoolSettings {
+            max_workers: self.config.get_int("manager.max_workers")? as u32,
+            min_workers: self.config.get_int("manager.min_workers")? as u32,
+            auto_scale: self.config.get_bool("manager.auto_scale")?,
+            fast_workers: self.config.get_bool("manager.fast_workers")?,
+        })
+    }
+
+    /// Change the pool settings. Workers are not started or stopped here: the
+    /// monitor brings the pool within its new bounds
+    pub fn update_pool(&mut self, update: PoolUpdate) -> Result<PoolSettings> {
+        let current = self.pool_settings()?;
+        let settings = PoolSettings {
+            max_workers: update.max_workers.unwrap_or(current.max_workers),
+            min_workers: update.min_workers.unwrap_or(current.min_workers),
+            auto_scale: update.auto_scale.unwrap_or(current.auto_scale),
+            fast_workers: update.fast_workers.unwrap_or(current.fast_workers),
+        };
+        if settings.max_workers == 0 {
+            return Err(Error::InvalidInput("max_workers must be at least 1".into()));
+        }
+        if settings.min_workers > settings.max_workers {
+            return Err(Error::InvalidInput(format!(
+                "min_workers ({}) cannot exceed max_workers ({})",
+                settings.min_workers, settings.max_workers
+            )));
+        }
+
+        self.config = config::Config::builder()
+            .add_source(self.config.clone())
+            .set_override("manager.max_workers", settings.max_workers)?
+            .set_override("manager.min_workers", settings.min_workers)?
+            .set_override("manager.auto_scale", settings.auto_scale)?
+            .set_override("manager.fast_workers", settings.fast_workers)?
+            .build()?;
+        info!("updated pool settings: {settings:?}");
+        Ok(settings)
+    }
+
+    /// The number of workers that are not shutting down. This is what counts
+    /// toward `manager.max_workers` and `manager.min_workers`
+    pub fn live_workers(&self) -> usize {
+        self.workers
+            .values()
+            .filter(|(_, s)| *s != WorkerStatus::ShuttingDown)
+            .count()
+    }
+
+    /// Start `n` new workers, without exceeding `manager.max_workers`
+    pub async fn add_workers(manager: &RwLock<Manager>, n: usize) -> Result<Vec<PartialHandle>> {
+        let mut added = vec![];
+        for _ in 0..n {
+            let (model, cfg, token) = {
+                let m = manager.read().unwrap();
+                let max_workers = m.config.get_int("manager.max_workers")? as usize;
+                if m.live_workers() >= max_workers {
+                    return Err(Error::InvalidInput(format!(
+                        "cannot add workers beyond max_workers ({max_workers}), {} were added",
+                        added.len()
+                    )));
+                }
+                (m.model.clone(), m.config.clone(), m.token.clone())
+            };
+
+            let handle = Self::spawn_worker(model, cfg, token).await?;
+            manager
+                .write()
+                .unwrap()
+                .workers
+                .insert(handle.pid, (handle.clone(), WorkerStatus::Idle));
+            added.push(handle.partial());
+        }
+        Ok(added)
+    }
+
+    /// Drain a worker and stop it
+    pub async fn remove_worker(manager: &RwLock<Manager>, pid: u32) -> Result<()> {
+        if !manager.read().unwrap().workers.contains_key(&pid) {
+            return Err(Error::NotFound(format!("no worker with pid {pid}")));
+        }
+        Ok(Self::drain_worker(manager, pid).await?)
+    }
+
+    /// Serve a new model file, or reload the current one if `model_file` is
+    /// `None`. A first worker is started on the new model, so that a model
+    /// that fails to load is reported and leaves the pool untouched. If the
+    /// pool is full, an old worker is stopped first to make room for it. The
+    /// remaining workers are then replaced one at a time in the background
+    pub async fn reload_model(
+        manager: &Arc<RwLock<Manager>>,
+        model_file: Option<String>,
+    ) -> Result<PartialHandle> {
+        let model_file = match model_file {
+            Some(model_file) => model_file,
+            None => manager.read().unwrap().model.path.clone(),
+        };
+        if !Path::new(&model_file).is_file() {
+            let msg = format!("no model file at {model_file}");
+            return Err(Error::InvalidInput(msg));
+        }
+        let model = Arc::new(ModelInfo::load_blocking(&model_file).await?);
+
+        let (old_model, old_pids, at_capacity, cfg, token) = {
+            let mut m = manager.write().unwrap();
+            let at_capacity = m.live_workers() >= m.config.get_int("manager.max_workers")? as usize;
+            let old_pids = m
+                .workers
+                .iter()
+                .filter(|(_, (_, s))| *s != WorkerStatus::ShuttingDown)
+                .map(|(&pid, _)| pid)
+                .collect::<Vec<_>>();
+            let old_model = std::mem::replace(&mut m.model, model.clone());
+            let (cfg, token) = (m.config.clone(), m.token.clone());
+            (old_model, old_pids, at_capacity, cfg, token)
+        };
+        info!(
+            "reloading {} workers with model {model_file} (version {})",
+            old_pids.len(),
+            model.version
+        );
+
+        let mut old_pids = old_pids.into_iter();
+        let made_room = if at_capacity { old_pids.next() } else { None };
+        if let Some(pid) = made_room {
+            if let Err(e) = Self::drain_worker(manager, pid).await {
+                manager.write().unwrap().model = old_model;
+                return Err(Error::Other(
+                    e.context("failed to make room for a new worker"),
+                ));
+            }
+        }
+
+        let first = match Self::spawn_worker(model, cfg, token).await {
+            Ok(handle) => handle,
+            Err(e) => {
+                manager.write().unwrap().model = old_model;
+                if made_room.is_some() {
+                    if let Err(e) = Self::add_workers(manager, 1).await {
+                        warn!("failed to restart the worker stopped for the reload: {e}");
+                    }
+                }
+                return Err(Error::Other(
+                    e.context("failed to start a worker on the new model"),
+                ));
+            }
+        };
+        manager
+            .write()
+            .unwrap()
+            .workers
+            .insert(first.pid, (first.clone(), WorkerStatus::Idle));
+
+        let manager = manager.clone();
+        tokio::spawn(async move {
+            // The new worker replaces the first old one, unless it was
+            // already stopped to make room
+            if let Some(pid) = old_pids.next().filter(|_| made_room.is_none()) {
+                if let Err(e) = Manager::drain_worker(&manager, pid).await {
+                    warn!("failed to stop worker {pid}: {e}");
+                }
+            }
+            for pid in old_pids {
+                if let Err(e) = Manager::replace_worker(&manager, pid).await {
+                    warn!("failed to reload worker {pid}: {e}");
+                }
+            }
+            info!("finished reloading the model");
+        });
+
+        Ok(first.partial())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A manager without workers
+    fn manager(max_workers: i64) -> Manager {
+        let config = config::Config::builder()
+            .set_default("manager.max_workers", max_workers)
+            .unwrap()
+            .set_default("manager.min_workers", 1)
+            .unwrap()
+            .set_default("manager.auto_scale", false)
+            .unwrap()
+            .set_default("manager.fast_workers", false)
+            .unwrap()
+            .build()
+            .unwrap();
+        Manager::without_workers(config)
+    }
+
+    #[test]
+    fn test_update_pool() {
+        let mut m = manager(4);
+        let settings = m
+            .update_pool(PoolUpdate {
+                max_workers: Some(8),
+                auto_scale: Some(true),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(settings.max_workers, 8);
+        assert_eq!(settings.min_workers, 1);
+        assert!(settings.auto_scale);
+        assert_eq!(m.pool_settings().unwrap().max_workers, 8);
+
+        let invalid = [
+            PoolUpdate {
+                max_workers: Some(0),
+                ..Default::default()
+            },
+            PoolUpdate {
+                min_workers: Some(9),
+                ..Default::default()
+            },
+        ];
+        for update in invalid {
+            assert!(matches!(m.update_pool(update), Err(Error::InvalidInput(_))));
+        }
+        assert_eq!(m.pool_settings().unwrap().max_workers, 8);
+    }
+
+    #[tokio::test]
+    async fn test_add_workers_limit() {
+        // No worker is spawned past the limit
+        let m = RwLock::new(manager(0));
+        let err = Manager::add_workers(&m, 1).await.unwrap_err();
+        assert!(matches!(err, Error::InvalidInput(_)));
+    }
+}
@@ -0,0 +1,397 @@
+//! The queue of requests waiting for a worker. Requests are served by
+//! priority class first: interactive requests before batch requests. Within a
+//! class, tenants are served in weighted fair order (start-time fair queuing),
+//! so that a tenant sending many requests only delays its own. A number of
+//! workers can be reserved for interactive requests, so that batch traffic
+//! never takes the whole pool
+
+use crate::error::{Error, Result};
+use crate::metrics;
+use config::Config;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use utoipa::ToSchema;
+
+/// Virtual cost of a request of a tenant of weight 1. A tenant of weight `w`
+/// is charged `COST / w` per request
+const COST: u64 = 1_000_000;
+
+/// How urgent a request is. Interactive requests are served first
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, ToSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// A client is waiting for the result
+    Interactive,
+
+    /// Throughput matters more than latency
+    Batch,
+}
+
+impl Priority {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Priority::Interactive => "interactive",
+            Priority::Batch => "batch",
+        }
+    }
+}
+
+impl FromStr for Priority {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Priority> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "interactive" => Ok(Priority::Interactive),
+            "batch" => Ok(Priority::Batch),
+            _ => Err(Error::InvalidInput(format!(
+                "unknown priority {s:?}, expected \"interactive\" or \"batch\""
+            ))),
+        }
+    }
+}
+
+/// How a request is queued: its priority, and the tenant it is fairly shared
+/// with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scheduling {
+    pub priority: Priority,
+
+    /// The client the request is made for, such as the owner of its API key
+    pub tenant: String,
+
+    /// The tenant's share of the workers, relative to other tenants
+    pub weight: u32,
+
+    /// How long the request can wait for a worker, if less than its deadline
+    pub max_wait: Option<Duration>,
+}
+
+impl Default for Scheduling {
+    fn default() -> Self {
+        Scheduling {
+            priority: Priority::Interactive,
+            tenant: String::new(),
+            weight: 1,
+            max_wait: None,
+        }
+    }
+}
+
+/// The place of a request in the queue. Tickets are ordered by priority, then
+/// by virtual start time, then by arrival
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Ticket {
+    priority: Priority,
+    start: u64,
+    seq: u64,
+}
+
+/// A request waiting in the queue
+#[derive(Debug)]
+struct Waiter {
+    /// The tenant the request is charged to, and the virtual cost it was
+    /// charged
+    tenant: String,
+    cost: u64,
+
+    /// Wakes up the request when it may be able to take a worker
+    notify: Arc<Notify>,
+}
+
+/// The queue of requests waiting for a worker
+#[derive(Debug)]
+pub struct Scheduler {
+    /// Number of idle workers that batch requests leave to interactive ones
+    reserved_workers: usize,
+
+    /// Maximum number of queued requests (0 = unlimited)
+    max_queue_size: usize,
+
+    queue: BTreeMap<Ticket, Waiter>,
+
+    /// The virtual finish time of the last request queued by each tenant
+    finish: HashMap<String, u64>,
+
+    /// The virtual start time of the last request that got a worker
+    virtual_time: u64,
+    seq: u64,
+}
+
+impl Scheduler {
+    pub fn new(reserved_workers: usize, max_queue_size: usize) -> Scheduler {
+        Scheduler {
+            reserved_workers,
+            max_queue_size,
+            queue: BTreeMap::new(),
+            finish: HashMap::new(),
+            virtual_time: 0,
+            seq: 0,
+        }
+    }
+
+    pub fn from_config(config: &Config) -> anyhow::Result<Scheduler> {
+        Ok(Scheduler::new(
+            config.get_int("scheduler.reserved_workers")? as usize,
+            config.get_int("scheduler.max_queue_size")? as usize,
+        ))
+    }
+
+    /// Queue a request. Fails with `Error::Busy` if the queue is full
+    pub fn enqueue(&mut self, scheduling: &Scheduling) -> Result<Ticket> {
+        let cost = COST / u64::from(scheduling.weight.max(1));
+        self.insert(scheduling, cost)
+    }
+
+    /// Queue a request again, after the worker it got failed. It was already
+    /// charged to its tenant, so it is queued at the current virtual time
+    pub fn requeue(&mut self, scheduling: &Scheduling) -> Result<Ticket> {
+        self.insert(scheduling, 0)
+    }
+
+    fn insert(&mut self, scheduling: &Scheduling, cost: u64) -> Result<Ticket> {
+        if self.max_queue_size > 0 && self.queue.len() >= self.max_queue_size {
+            return Err(Error::Busy);
+        }
+
+        let start = if cost == 0 {
+            self.virtual_time
+        } else {
+            let last_finish = self.finish.get(&scheduling.tenant).copied();
+            let start = last_finish.unwrap_or(0).max(self.virtual_time);
+            self.finish.insert(scheduling.tenant.clone(), start + cost);
+            start
+        };
+
+        self.seq += 1;
+        let ticket = Ticket {
+            priority: scheduling.priority,
+            start,
+            seq: self.seq,
+        };
+        let waiter = Waiter {
+            tenant: scheduling.tenant.clone(),
+            cost,
+            notify: Arc::new(Notify::new()),
+        };
+        self.queue.insert(ticket, waiter);
+        metrics::QUEUE_DEPTH.inc();
+        metrics::QUEUED
+            .with_label_values(&[ticket.priority.name()])
+            .inc();
+        Ok(ticket)
+    }
+
+    /// Whether the request holding `ticket` can take one of `idle` workers:
+    /// there must be an idle worker for each request ahead of it, and batch
+    /// requests must also leave the reserved workers. Only the requests ahead
+    /// of it up to the number of idle workers are counted
+    pub fn may_start(&self, ticket: &Ticket, idle: usize) -> bool {
+        let available = match ticket.priority {
+            Priority::Interactive => idle,
+            Priority::Batch => idle.saturating_sub(self.reserved_workers),
+        };
+        self.queue.range(..ticket).take(available).count() < available
+    }
+
+    /// Remove a request from the queue, once it has got a worker (`started`)
+    /// or given up waiting for one. A request that gives up is refunded to its
+    /// tenant, so that it does not delay the tenant's later requests
+    pub fn dequeue(&mut self, ticket: &Ticket, started: bool) {
+        let Some(waiter) = self.queue.remove(ticket) else {
+            return;
+        };
+        metrics::QUEUE_DEPTH.dec();
+        metrics::QUEUED
+            .with_label_values(&[ticket.priority.name()])
+            .dec();
+        if started {
+            self.virtual_time = self.virtual_time.max(ticket.start);
+        } else if let Some(finish) = self.finish.get_mut(&waiter.tenant) {
+            *finish = finish.saturating_sub(waiter.cost);
+        }
+
+        // Tenants whose requests have all been served start from the virtual
+        // time again, so they need not be remembered
+        if self.finish.len() > self.queue.len() {
+            let virtual_time = self.virtual_time;
+            self.finish.retain(|_, finish| *finish > virtual_time);
+        }
+        self.wake();
+    }
+
+    /// Wake up the request at the head of the queue to check whether it can
+    /// take a worker. If it takes one, it leaves the queue and wakes up the
+    /// next request in turn. If it cannot, no request behind it can either
+    pub fn wake(&self) {
+        if let Some((_, waiter)) = self.queue.first_key_value() {
+            waiter.notify.notify_one();
+        }
+    }
+
+    /// A handle to wait for the request holding `ticket` to be woken up with
+    pub fn notifier(&self, ticket: &Ticket) -> Option<Arc<Notify>> {
+        self.queue.get(ticket).map(|waiter| waiter.notify.clone())
+    }
+
+    /// Number of queued requests
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scheduling(priority: Priority, tenant: &str, weight: u32) -> Scheduling {
+        Scheduling {
+            priority,
+            tenant: tenant.into(),
+            weight,
+            max_wait: None,
+        }
+    }
+
+    /// Serve the queue one request at a time, returning the order the tickets
+    /// were served in
+    fn drain(scheduler: &mut Scheduler, tickets: &[Ticket]) -> Vec<usize> {
+        let mut order = vec![];
+        while !scheduler.is_empty() {
+            let i = tickets
+                .iter()
+                .position(|ticket| {
+                    scheduler.queue.contains_key(ticket) && scheduler.may_start(ticket, 1)
+                })
+                .unwrap();
+            scheduler.dequeue(&tickets[i], true);
+            order.push(i);
+        }
+        order
+    }
+
+    #[test]
+    fn test_priority() {
+        let mut scheduler = Scheduler::new(0, 0);
+        let batch = scheduler
+            .enqueue(&scheduling(Priority::Batch, "a", 1))
+            .unwrap();
+        let interactive = scheduler
+            .enqueue(&scheduling(Priority::Interactive, "a", 1))
+            .unwrap();
+        assert!(scheduler.may_start(&interactive, 1));
+        assert!(!scheduler.may_start(&batch, 1));
+        assert!(scheduler.may_start(&batch, 2));
+    }
+
+    #[test]
+    fn test_fair_queuing() {
+        // A tenant with many queued requests does not starve another that
+        // arrives later, and tenants are served in proportion to their weight
+        let mut scheduler = Scheduler::new(0, 0);
+        let mut tickets = vec![];
+        for _ in 0..4 {
+            tickets.push(
+                scheduler
+                    .enqueue(&scheduling(Priority::Batch, "a", 1))
+                    .unwrap(),
+            );
+        }
+        for _ in 0..4 {
+            tickets.push(
+                scheduler
+                    .enqueue(&scheduling(Priority::Batch, "b", 2))
+                    .unwrap(),
+            );
+        }
+        assert_eq!(
+            drain(&mut scheduler, &tickets),
+            vec![0, 4, 5, 1, 6, 7, 2, 3]
+        );
+    }
+
+    #[test]
+    fn test_reserved_workers() {
+        let mut scheduler = Scheduler::new(1, 0);
+        let batch = scheduler
+            .enqueue(&scheduling(Priority::Batch, "a", 1))
+            .unwrap();
+        assert!(!scheduler.may_start(&batch, 1));
+        assert!(scheduler.may_start(&batch, 2));
+
+        let interactive = scheduler
+            .enqueue(&scheduling(Priority::Interactive, "b", 1))
+            .unwrap();
+        assert!(scheduler.may_start(&interactive, 1));
+    }
+
+    #[test]
+    fn test_queue_size() {
+        let mut scheduler = Scheduler::new(0, 1);
+        let ticket = scheduler.enqueue(&Scheduling::default()).unwrap();
+        assert!(matches!(
+            scheduler.enqueue(&Scheduling::default()),
+            Err(Error::Busy)
+        ));
+        scheduler.dequeue(&ticket, false);
+        assert!(scheduler.enqueue(&Scheduling::default()).is_ok());
+    }
+
+    #[test]
+    fn test_refund() {
+        // Requests that give up waiting do not delay their tenant's next ones
+        let mut scheduler = Scheduler::new(0, 0);
+        for _ in 0..3 {
+            let ticket = scheduler
+                .enqueue(&scheduling(Priority::Batch, "a", 1))
+                .unwrap();
+            scheduler.dequeue(&ticket, false);
+        }
+        let a = scheduler
+            .enqueue(&scheduling(Priority::Batch, "a", 1))
+            .unwrap();
+        assert_eq!(a.start, 0);
+
+        // A retried request is not charged again
+        let b = scheduler
+            .enqueue(&scheduling(Priority::Batch, "b", 1))
+            .unwrap();
+        scheduler.dequeue(&b, true);
+        let retry = scheduler
+            .requeue(&scheduling(Priority::Batch, "b", 1))
+            .unwrap();
+        assert_eq!(retry.start, 0);
+        assert_eq!(scheduler.finish["b"], COST);
+    }
+
+    #[tokio::test]
+    async fn test_wake_head() {
+        let mut scheduler = Scheduler::new(0, 0);
+        let first = scheduler.enqueue(&Scheduling::default()).unwrap();
+        let second = scheduler.enqueue(&Scheduling::default()).unwrap();
+        let (first_notify, second_notify) = (
+            scheduler.notifier(&first).unwrap(),
+            scheduler.notifier(&second).unwrap(),
+        );
+        let woken = |notify: Arc<Notify>| async move {
+            let wait = Duration::from_millis(10);
+            tokio::time::timeout(wait, notify.notified()).await.is_ok()
+        };
+
+        // Only the head of the queue is woken up, until it leaves the queue
+        scheduler.wake();
+        assert!(woken(first_notify).await);
+        assert!(!woken(second_notify.clone()).await);
+        scheduler.dequeue(&first, true);
+        assert!(woken(second_notify).await);
+    }
+}
@@ -0,0 +1,164 @@
+//! Prometheus metrics for the server, manager and workers, exported in the
+//! Prometheus text format at `/metrics`
+
+use crate::error::Error;
+use crate::torch::InferenceType;
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, TextEncoder,
+};
+use std::time::Duration;
+
+/// Latency buckets, in seconds
+const BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// Inference requests, by inference type and status (`ok` or an error code)
+pub static REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "autodep_requests_total",
+        "Inference requests by inference type and status",
+        &["inference_type", "status"]
+    )
+    .unwrap()
+});
+
+/// Time from dispatching a request to receiving its inference
+pub static REQUEST_LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "autodep_request_duration_seconds",
+        "End-to-end latency of inference requests",
+        &["inference_type"],
+        BUCKETS.to_vec()
+    )
+    .unwrap()
+});
+
+/// Time spent computing inference on a worker, as reported by the worker
+pub static MODEL_LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "autodep_model_duration_seconds",
+        "Time spent computing inference on a worker",
+        &["inference_type"],
+        BUCKETS.to_vec()
+    )
+    .unwrap()
+});
+
+/// Requests waiting in the scheduler's queue for a worker
+pub static QUEUE_DEPTH: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!("autodep_queue_depth", "Requests waiting for a worker").unwrap()
+});
+
+/// Requests waiting in the scheduler's queue for a worker, by priority
+pub static QUEUED: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "autodep_queued_requests",
+        "Requests waiting for a worker, by priority",
+        &["priority"]
+    )
+    .unwrap()
+});
+
+/// Workers, by `WorkerStatus`. Set when metrics are scraped
+pub static WORKERS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!("autodep_workers", "Workers by status", &["status"]).unwrap()
+});
+
+/// Requests served by each worker. Set when metrics are scraped
+pub static WORKER_REQUESTS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "autodep_worker_requests_served",
+        "Requests served by each worker",
+        &["pid"]
+    )
+    .unwrap()
+});
+
+/// Worker processes spawned
+pub static SPAWNS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("autodep_worker_spawns_total", "Worker processes spawned").unwrap()
+});
+
+/// Worker processes that exited without being stopped by the manager
+pub static CRASHES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "autodep_worker_crashes_total",
+        "Worker processes that exited without being stopped"
+    )
+    .unwrap()
+});
+
+/// WebSocket frames that were dropped before inference, because newer frames
+/// arrived or all workers were busy
+pub static DROPPED_FRAMES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "autodep_ws_dropped_frames_total",
+        "WebSocket frames dropped before inference"
+    )
+    .unwrap()
+});
+
+/// Requests refused by the rate limiter, by the limit they were over: that of
+/// their client, or the global one
+pub static RATE_LIMITED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "autodep_rate_limited_total",
+        "Requests refused by the rate limiter",
+        &["limit"]
+    )
+    .unwrap()
+});
+
+/// Record the outcome of an inference request: the time the worker spent
+/// computing it if it succeeded, or its error
+pub fn observe_request(ty: &InferenceType, result: Result<Duration, &Error>, latency: Duration) {
+    let status = match result {
+        Ok(model_time) => {
+            MODEL_LATENCY
+                .with_label_values(&[ty.name()])
+                .observe(model_time.as_secs_f64());
+            "ok"
+        }
+        Err(err) => err.code(),
+    };
+    REQUESTS.with_label_values(&[ty.name(), status]).inc();
+    REQUEST_LATENCY
+        .with_label_values(&[ty.name()])
+        .observe(latency.as_secs_f64());
+}
+
+/// Encode all metrics in the Prometheus text format
+pub fn encode() -> anyhow::Result<String> {
+    let mut buf = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buf)?;
+    Ok(String::from_utf8(buf)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let ty = InferenceType::ImageToImage;
+        observe_request(
+            &ty,
+            Ok(Duration::from_millis(20)),
+            Duration::from_millis(30),
+        );
+        observe_request(&ty, Err(&Error::Busy), Duration::from_millis(1));
+
+        let text = encode().unwrap();
+        assert!(
+            text.contains(r#"autodep_requests_total{inference_type="ImageToImage",status="ok"}"#)
+        );
+        assert!(text.contains(
+            r#"autodep_requests_total{inference_type="ImageToImage",status="workers_busy"}"#
+        ));
+        assert!(text.contains("autodep_model_duration_seconds_bucket"));
+    }
+}
@@ -0,0 +1,389 @@
+//! Token buckets, which limit the rate of requests while allowing short
+//! bursts, and the rate limiter of the inference API built on them. The rate
+//! limiter gives each client a bucket of its own, so that a single noisy client
+//! cannot take up the whole worker pool, and can also limit the total rate of
+//! requests
+
+use crate::error::{Error, Result};
+use crate::metrics;
+use config::Config;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A bucket of tokens that refills at a constant rate. Each request takes a
+/// token, and requests that find the bucket empty are refused
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    /// Tokens added per second
+    rate: f64,
+
+    /// Maximum number of tokens in the bucket
+    burst: f64,
+
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket of `burst` tokens, refilled at `rate` tokens per second
+    pub fn new(rate: f64, burst: f64) -> TokenBucket {
+        TokenBucket {
+            rate,
+            burst,
+            tokens: burst,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Take a token, or return how long until one is available
+    pub fn try_take(&mut self, now: Instant) -> std::result::Result<(), Duration> {
+        self.try_take_n(now, 1.0)
+    }
+
+    /// Take `n` tokens at once, or return how long until they are available
+    pub fn try_take_n(&mut self, now: Instant, n: f64) -> std::result::Result<(), Duration> {
+        self.refill(now);
+        if self.tokens >= n {
+            self.tokens -= n;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((n - self.tokens) / self.rate))
+        }
+    }
+
+    /// The most tokens the bucket holds
+    pub fn burst(&self) -> f64 {
+        self.burst
+    }
+
+    /// Put back `n` tokens taken for a request that was refused after all
+    pub fn give_back(&mut self, n: f64) {
+        self.tokens = (self.tokens + n).min(self.burst);
+    }
+
+    /// How long until the bucket has refilled completely, so that dropping it
+    /// would not change anything
+    pub fn until_full(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        Duration::from_secs_f64((self.burst - self.tokens) / self.rate)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = now;
+    }
+}
+
+/// The limits of the rate limiter. A rate of 0 means unlimited
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    /// Sustained number of requests per second of each client
+    pub client_rate: f64,
+
+    /// Number of requests a client can make at once before its rate applies
+    pub client_burst: f64,
+
+    /// Sustained number of requests per second of all clients together
+    pub global_rate: f64,
+
+    /// Number of requests that can be made at once before the global rate
+    /// applies
+    pub global_burst: f64,
+
+    /// Header identifying the client of a request, such as `X-Real-IP` behind
+    /// a proxy. Clients are identified by their IP address when it is empty,
+    /// or missing from a request
+    pub client_header: String,
+
+    /// The addresses of the proxies whose `client_header` is trusted. The
+    /// header of other peers is ignored, as they could set it to anything
+    pub trusted_proxies: Vec<IpAddr>,
+
+    /// Number of clients whose buckets are kept. Past this, the bucket of the
+    /// least recently seen client is dropped once it has refilled, and new
+    /// clients are refused until then
+    pub max_clients: usize,
+}
+
+impl RateLimitConfig {
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        let trusted_proxies = config
+            .get::<Vec<String>>("rate_limit.trusted_proxies")?
+            .iter()
+            .map(|addr| addr.parse::<IpAddr>())
+            .collect::<std::result::Result<_, _>>()?;
+        let config = RateLimitConfig {
+            client_rate: config.get_float("rate_limit.requests_per_second")?,
+            client_burst: config.get_float("rate_limit.burst")?,
+            global_rate: config.get_float("rate_limit.global_requests_per_second")?,
+            global_burst: config.get_float("rate_limit.global_burst")?,
+            client_header: config.get_string("rate_limit.client_header")?,
+            trusted_proxies,
+            max_clients: config.get_int("rate_limit.max_clients")? as usize,
+        };
+
+        // A bucket of less than one token would refuse every request
+        if config.client_rate > 0.0 && config.client_burst < 1.0 {
+            anyhow::bail!("rate_limit.burst must be at least 1");
+        }
+        if config.global_rate > 0.0 && config.global_burst < 1.0 {
+            anyhow::bail!("rate_limit.global_burst must be at least 1");
+        }
+        if config.client_rate > 0.0 && config.max_clients == 0 {
+            anyhow::bail!("rate_limit.max_clients must be at least 1");
+        }
+        if !config.client_header.is_empty() && config.trusted_proxies.is_empty() {
+            anyhow::bail!("rate_limit.client_header is set, but no proxy is trusted to set it");
+        }
+        Ok(config)
+    }
+
+    /// Identify the client of a request by the value of `client_header`, if
+    /// the request came through a trusted proxy, or else by its IP address
+    pub fn client_id(&self, header: Option<&str>, addr: Option<IpAddr>) -> String {
+        let trusted = addr.is_some_and(|addr| self.trusted_proxies.contains(&addr));
+        match (header.filter(|_| trusted), addr) {
+            (Some(header), _) => header.trim().to_string(),
+            (None, Some(addr)) => addr.to_string(),
+            (None, None) => String::new(),
+        }
+    }
+}
+
+/// The buckets of clients, by their IP address or header, along with the order
+/// they were last used in
+#[derive(Debug, Default)]
+struct Clients {
+    /// Each client's bucket, and the number of its last request
+    buckets: HashMap<String, (TokenBucket, u64)>,
+
+    /// The clients by the number of their last request, least recent first
+    order: BTreeMap<u64, String>,
+
+    /// The number of the last request
+    last: u64,
+}
+
+impl Clients {
+    /// The bucket of a client, marked as the most recently used one. A new
+    /// client takes the place of the least recently used one when there are
+    /// `max_clients`, provided its bucket has refilled. Otherwise it is
+    /// refused with the time until that bucket has refilled
+    fn bucket(
+        &mut self,
+        client: &str,
+        config: &RateLimitConfig,
+        now: Instant,
+    ) -> std::result::Result<&mut TokenBucket, Duration> {
+        self.last += 1;
+        let last = self.last;
+        if let Some((_, used)) = self.buckets.get_mut(client) {
+            let client = self.order.remove(used).expect("every client is ordered");
+            *used = last;
+            self.order.insert(last, client);
+        } else {
+            if self.buckets.len() >= config.max_clients {
+                let (_, oldest) = self.order.first_key_value().expect("there are clients");
+                let (bucket, _) = self.buckets.get_mut(oldest).expect("ordered clients exist");
+                let wait = bucket.until_full(now);
+                if !wait.is_zero() {
+                    return Err(wait);
+                }
+                let (_, oldest) = self.order.pop_first().expect("there are clients");
+                self.buckets.remove(&oldest);
+            }
+            let bucket = TokenBucket::new(config.client_rate, config.client_burst);
+            self.buckets.insert(client.to_string(), (bucket, last));
+            self.order.insert(last, client.to_string());
+        }
+        Ok(&mut self
+            .buckets
+            .get_mut(client)
+            .expect("the client was added")
+            .0)
+    }
+}
+
+/// Limits the rate of requests of each client, and of all clients together
+#[derive(Debug)]
+pub struct RateLimiter {
+    pub config: RateLimitConfig,
+    global: Option<Mutex<TokenBucket>>,
+
+    clients: Mutex<Clients>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> RateLimiter {
+        let global = (config.global_rate > 0.0)
+            .then(|| Mutex::new(TokenBucket::new(config.global_rate, config.global_burst)));
+        RateLimiter {
+            config,
+            global,
+            clients: Mutex::new(Clients::default()),
+        }
+    }
+
+    /// Admit a request from `client`, or refuse it with
+    /// `Error::RateLimited` if it is over its client's limit or the global one
+    pub fn check(&self, client: &str) -> Result<()> {
+        self.check_at(client, Instant::now())
+    }
+
+    fn check_at(&self, client: &str, now: Instant) -> Result<()> {
+        let mut clients = self.clients.lock().unwrap();
+        if self.config.client_rate > 0.0 {
+            let rate = self.config.client_rate;
+            let bucket = clients.bucket(client, &self.config, now).map_err(|wait| {
+                metrics::RATE_LIMITED.with_label_values(&["client"]).inc();
+                Error::RateLimited("too many clients are making requests".into(), wait)
+            })?;
+            bucket.try_take(now).map_err(|wait| {
+                metrics::RATE_LIMITED.with_label_values(&["client"]).inc();
+                Error::RateLimited(
+                    format!("{client} is limited to {rate} requests per second"),
+                    wait,
+                )
+            })?;
+        }
+
+        if let Some(global) = &self.global {
+            if let Err(wait) = global.lock().unwrap().try_take(now) {
+                // The request is not served, so it does not count against its
+                // client
+                if let Some((bucket, _)) = clients.buckets.get_mut(client) {
+                    bucket.give_back(1.0);
+                }
+                metrics::RATE_LIMITED.with_label_values(&["global"]).inc();
+                let msg = "the server is receiving too many requests";
+                return Err(Error::RateLimited(msg.into(), wait));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(2.0, 3.0);
+        let start = bucket.updated;
+
+        // A full bucket allows a burst
+        for _ in 0..3 {
+            assert!(bucket.try_take(start).is_ok());
+        }
+        let wait = bucket.try_take(start).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(500));
+
+        // It refills at its rate, up to its burst
+        assert!(bucket.try_take(start + Duration::from_millis(500)).is_ok());
+        assert!(bucket.try_take(start + Duration::from_millis(600)).is_err());
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(bucket.try_take(later).is_ok());
+        }
+        assert!(bucket.try_take(later).is_err());
+        assert_eq!(bucket.until_full(later), Duration::from_millis(1500));
+        assert!(bucket.until_full(later + Duration::from_secs(2)).is_zero());
+    }
+
+    fn limiter(client_rate: f64, global_rate: f64) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            client_rate,
+            client_burst: 2.0,
+            global_rate,
+            global_burst: 3.0,
+            client_header: String::new(),
+            trusted_proxies: vec![],
+            max_clients: 2,
+        })
+    }
+
+    #[test]
+    fn test_clients() {
+        let limiter = limiter(1.0, 0.0);
+        let now = Instant::now();
+
+        // Each client has its own bucket
+        assert!(limiter.check_at("a", now).is_ok());
+        assert!(limiter.check_at("a", now).is_ok());
+        let err = limiter.check_at("a", now).unwrap_err();
+        assert!(matches!(err, Error::RateLimited(_, wait) if wait == Duration::from_secs(1)));
+        assert!(limiter.check_at("b", now).is_ok());
+
+        // There are too many clients, and the least recently seen one has not
+        // refilled its bucket yet
+        let err = limiter.check_at("c", now).unwrap_err();
+        assert!(matches!(err, Error::RateLimited(_, wait) if wait == Duration::from_secs(2)));
+
+        // Once it has, it is dropped to make room
+        let later = now + Duration::from_secs(2);
+        assert!(limiter.check_at("c", later).is_ok());
+        let clients = limiter.clients.lock().unwrap();
+        assert_eq!(clients.buckets.len(), 2);
+        assert!(clients.buckets.contains_key("b") && clients.buckets.contains_key("c"));
+        assert_eq!(clients.order.len(), 2);
+    }
+
+    #[test]
+    fn test_global() {
+        let limiter = limiter(0.0, 1.0);
+        let now = Instant::now();
+        for client in ["a", "b", "c"] {
+            assert!(limiter.check_at(client, now).is_ok());
+        }
+        assert!(matches!(
+            limiter.check_at("d", now),
+            Err(Error::RateLimited(..))
+        ));
+        assert!(limiter.check_at("d", now + Duration::from_secs(1)).is_ok());
+    }
+
+    #[test]
+    fn test_global_refusal() {
+        let limiter = limiter(1.0, 1.0);
+        let now = Instant::now();
+        for client in ["a", "a", "b"] {
+            assert!(limiter.check_at(client, now).is_ok());
+        }
+
+        // A request refused by the global limit keeps its client's token
+        assert!(limiter.check_at("b", now).is_err());
+        let mut clients = limiter.clients.lock().unwrap();
+        let (bucket, _) = clients.buckets.get_mut("b").unwrap();
+        assert!(bucket.try_take(now).is_ok());
+    }
+
+    #[test]
+    fn test_config() {
+        let config = |extra: &str| {
+            let defaults = include_str!("../config.toml");
+            Config::builder()
+                .add_source(config::File::from_str(defaults, config::FileFormat::Toml))
+                .add_source(config::File::from_str(extra, config::FileFormat::Toml))
+                .build()
+                .unwrap()
+        };
+        assert!(RateLimitConfig::from_config(&config("")).is_ok());
+        let zero_burst = "[rate_limit]\nrequests_per_second = 1\nburst = 0";
+        assert!(RateLimitConfig::from_config(&config(zero_burst)).is_err());
+        let untrusted = "[rate_limit]\nclient_header = \"X-Real-IP\"";
+        assert!(RateLimitConfig::from_config(&config(untrusted)).is_err());
+
+        // The header is only trusted from the proxies
+        let trusted =
+            "[rate_limit]\nclient_header = \"X-Real-IP\"\ntrusted_proxies = [\"10.0.0.1\"]";
+        let config = RateLimitConfig::from_config(&config(trusted)).unwrap();
+        let proxy = "10.0.0.1".parse().ok();
+        assert_eq!(config.client_id(Some("1.2.3.4"), proxy), "1.2.3.4");
+        let other = "10.0.0.2".parse().ok();
+        assert_eq!(config.client_id(Some("1.2.3.4"), other), "10.0.0.2");
+    }
+}
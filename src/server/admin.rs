@@ -0,0 +1,153 @@
+//! Admin routes that change the worker pool at runtime, and report on its
+//! clients. Every admin request must carry the `admin.token` as a bearer
+//! token, and the admin API is disabled when no token is configured
+
+use super::auth::bearer_token;
+use super::WebError;
+use crate::error::Error;
+use crate::keys::KeyStore;
+use crate::manager::pool::PoolUpdate;
+use crate::manager::Manager;
+use crate::util;
+use crate::webhooks::{DeliveryStatus, Webhooks};
+
+use actix_web::dev::Payload;
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{delete, get, patch, post, web, FromRequest, HttpRequest, HttpResponse, Responder};
+use config::Config;
+use serde::Deserialize;
+use std::future::{ready, Ready};
+use std::sync::RwLock;
+use tracing::*;
+
+type Result<T> = std::result::Result<T, WebError>;
+
+/// Proof that a request was made by an admin
+pub struct Admin;
+
+impl FromRequest for Admin {
+    type Error = WebError;
+    type Future = Ready<Result<Admin>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(authorize(req))
+    }
+}
+
+/// Check that a request carries the admin token
+fn authorize(req: &HttpRequest) -> Result<Admin> {
+    let token = req
+        .app_data::<web::Data<Config>>()
+        .and_then(|config| config.get_string("admin.token").ok())
+        .unwrap_or_default();
+    if token.is_empty() {
+        return Err(Error::Forbidden("the admin API is disabled".into()).into());
+    }
+
+    let header = req.headers().get(AUTHORIZATION);
+    let given = bearer_token(header.and_then(|header| header.to_str().ok()));
+    match given {
+        Some(given) if util::constant_time_eq(given.as_bytes(), token.as_bytes()) => Ok(Admin),
+        _ => Err(Error::Unauthorized("a valid admin token is required".into()).into()),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AddWorkers {
+    /// Number of workers to start
+    #[serde(default = "one")]
+    count: usize,
+}
+
+fn one() -> usize {
+    1
+}
+
+/// Start new workers
+#[post("/admin/workers")]
+pub async fn add_workers(
+    _admin: Admin,
+    req: web::Json<AddWorkers>,
+    state: web::Data<RwLock<Manager>>,
+) -> Result<impl Responder> {
+    info!("admin request to add {} workers", req.count);
+    let added = Manager::add_workers(&state, req.count).await?;
+    Ok(HttpResponse::Created().json(added))
+}
+
+/// Drain a worker and stop it
+#[delete("/admin/workers/{pid}")]
+pub async fn remove_worker(
+    _admin: Admin,
+    pid: web::Path<u32>,
+    state: web::Data<RwLock<Manager>>,
+) -> Result<impl Responder> {
+    let pid = pid.into_inner();
+    info!("admin request to remove worker {pid}");
+    Manager::remove_worker(&state, pid).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Get the pool settings
+#[get("/admin/pool")]
+pub async fn pool_settings(
+    _admin: Admin,
+    state: web::Data<RwLock<Manager>>,
+) -> Result<impl Responder> {
+    let settings = state.read().unwrap().pool_settings()?;
+    Ok(web::Json(settings))
+}
+
+/// Change the pool settings
+#[patch("/admin/pool")]
+pub async fn update_pool(
+    _admin: Admin,
+    req: web::Json<PoolUpdate>,
+    state: web::Data<RwLock<Manager>>,
+) -> Result<impl Responder> {
+    let settings = state.write().unwrap().update_pool(req.into_inner())?;
+    Ok(web::Json(settings))
+}
+
+#[derive(Deserialize)]
+pub struct Reload {
+    /// Path to a new model file. The current model file is reloaded if this
+    /// is left out
+    model: Option<String>,
+}
+
+/// Reload the model, or switch to a new one, replacing all workers
+#[post("/admin/reload")]
+pub async fn reload(
+    _admin: Admin,
+    req: web::Json<Reload>,
+    state: web::Data<RwLock<Manager>>,
+) -> Result<impl Responder> {
+    info!("admin request to reload the model");
+    let worker = Manager::reload_model(&state.into_inner(), req.into_inner().model).await?;
+    Ok(HttpResponse::Accepted().json(worker))
+}
+
+#[derive(Deserialize)]
+pub struct DeliveryFilter {
+    job: Option<String>,
+    status: Option<DeliveryStatus>,
+}
+
+/// Get the logged webhook deliveries, most recent first
+#[get("/admin/deliveries")]
+pub async fn deliveries(
+    _admin: Admin,
+    filter: web::Query<DeliveryFilter>,
+    webhooks: web::Data<Webhooks>,
+) -> Result<impl Responder> {
+    Ok(web::Json(
+        webhooks.deliveries(filter.job.as_deref(), filter.status),
+    ))
+}
+
+/// Get the usage of every API key
+#[get("/admin/keys")]
+pub async fn keys(_admin: Admin, keys: web::Data<KeyStore>) -> Result<impl Responder> {
+    Ok(web::Json(keys.usage()))
+}
@@ -0,0 +1,258 @@
+//! Authentication of the inference API by API key. Requests carry their key
+//! as a bearer token, and are admitted under the limits of their key by the
+//! `authenticate` middleware, before they reach a handler
+
+use super::WebError;
+use crate::error::Result;
+use crate::keys::{ApiKey, KeyStore, Permit};
+use crate::manager::Manager;
+use crate::torch::{InferenceTask, InferenceType};
+
+use actix_web::body::{BodySize, BoxBody, EitherBody, MessageBody};
+use actix_web::dev::{Payload, Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::web::Bytes;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest};
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+
+/// Get the bearer token of an `Authorization` header
+pub fn bearer_token(header: Option<&str>) -> Option<&str> {
+    header.and_then(|header| header.strip_prefix("Bearer "))
+}
+
+/// The response of the `authenticate` middleware: the response of the
+/// handler, or the error that refused the request
+type AuthResponse = ServiceResponse<EitherBody<PermitBody>>;
+
+/// Middleware that authenticates requests by their API key, and admits them
+/// under the limits of their key. A request counts against its key's
+/// concurrency limit until its response body is sent or dropped, so that
+/// event streams and WebSockets count for as long as they are open. Requests
+/// are let through when `auth.enabled` is false
+pub fn authenticate<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = std::result::Result<AuthResponse, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody + 'static,
+{
+    let res = match admit(&req) {
+        Ok(permit) => {
+            if let Some(permit) = &permit {
+                req.extensions_mut()
+                    .insert(Caller(Some(permit.key().clone())));
+            }
+            Ok((permit, srv.call(req)))
+        }
+        Err(e) => Err(req.error_response(WebError::from(e))),
+    };
+    async move {
+        match res {
+            Ok((permit, res)) => res.await.map(|res| {
+                res.map_body(|_, body| PermitBody {
+                    body: BoxBody::new(body),
+                    _permit: permit,
+                })
+                .map_into_left_body()
+            }),
+            Err(res) => Ok(res.map_into_right_body()),
+        }
+    }
+}
+
+/// The body of a response to an admitted request, which holds the request's
+/// permit until it is dropped
+pub struct PermitBody {
+    body: BoxBody,
+    _permit: Option<Permit>,
+}
+
+impl MessageBody for PermitBody {
+    type Error = Box<dyn std::error::Error>;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<std::result::Result<Bytes, Self::Error>>> {
+        Pin::new(&mut self.body).poll_next(cx)
+    }
+}
+
+/// Admit a request under the limits of its key, if keys are required
+fn admit(req: &ServiceRequest) -> Result<Option<Permit>> {
+    let Some(keys) = req.app_data::<web::Data<KeyStore>>() else {
+        return Ok(None);
+    };
+    if !keys.enabled() {
+        return Ok(None);
+    }
+    let model = req
+        .app_data::<web::Data<RwLock<Manager>>>()
+        .map(|manager| manager.read().unwrap().model().name.clone())
+        .unwrap_or_default();
+    let header = req.headers().get(AUTHORIZATION);
+    let token = bearer_token(header.and_then(|header| header.to_str().ok()));
+    keys.admit(token, &model)
+}
+
+/// The API key a request was made with, if keys are required
+#[derive(Debug, Clone, Default)]
+pub struct Caller(pub Option<Arc<ApiKey>>);
+
+impl Caller {
+    /// The caller of a request, as set by the `authenticate` middleware
+    pub fn of(req: &HttpRequest) -> Caller {
+        req.extensions()
+            .get::<Caller>()
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Check that the caller's key can request this type of inference
+    pub fn allow_inference(&self, ty: &InferenceType) -> Result<()> {
+        match &self.0 {
+            Some(key) => key.allow_inference(ty),
+            None => Ok(()),
+        }
+    }
+
+    /// Count `n` more requests against the caller's key, for requests that
+    /// carry several tasks
+    pub fn charge(&self, n: u64) -> Result<()> {
+        match &self.0 {
+            Some(key) => key.charge(n),
+            None => Ok(()),
+        }
+    }
+
+    /// Check that the caller's key can run a task
+    pub fn allow(&self, task: &InferenceTask) -> Result<()> {
+        self.allow_inference(&task.inference_type)
+    }
+
+    /// Check that the caller's key can run a task, passing it through if so
+    pub fn allow_task(&self, task: Result<InferenceTask>) -> Result<InferenceTask> {
+        task.and_then(|task| self.allow(&task).map(|_| task))
+    }
+}
+
+impl FromRequest for Caller {
+    type Error = actix_web::Error;
+    type Future = Ready<std::result::Result<Caller, actix_web::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Ok(Caller::of(req)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::KeyConfig;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App, HttpResponse};
+
+    async fn ok() -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    /// A response whose body never ends, like an event stream
+    async fn endless() -> HttpResponse {
+        let body = tokio_stream::pending::<std::result::Result<Bytes, actix_web::Error>>();
+        HttpResponse::Ok().streaming(body)
+    }
+
+    fn key() -> KeyConfig {
+        KeyConfig {
+            name: "alice".into(),
+            // The SHA-256 digest of "secret"
+            hash: "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b".into(),
+            requests_per_second: 0.0,
+            burst: None,
+            max_concurrent: 0,
+            daily_quota: 0,
+            models: vec![],
+            inference_types: vec![],
+            weight: None,
+            max_priority: None,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_authenticate() {
+        let key = KeyConfig {
+            daily_quota: 1,
+            ..key()
+        };
+        let keys = web::Data::new(KeyStore::new(true, vec![key]).unwrap());
+        let app = test::init_service(
+            App::new()
+                .app_data(keys)
+                .route("/healthz", web::get().to(ok))
+                .service(
+                    web::scope("")
+                        .wrap_fn(authenticate)
+                        .route("/inference", web::post().to(ok)),
+                ),
+        )
+        .await;
+
+        let status = |req: test::TestRequest| {
+            let app = &app;
+            async move { test::call_service(app, req.to_request()).await.status() }
+        };
+        let inference = || test::TestRequest::post().uri("/inference");
+        assert_eq!(status(inference()).await, StatusCode::UNAUTHORIZED);
+        let authorized = inference().insert_header((AUTHORIZATION, "Bearer secret"));
+        assert_eq!(status(authorized).await, StatusCode::OK);
+
+        // The key's daily quota is used up
+        let authorized = inference().insert_header((AUTHORIZATION, "Bearer secret"));
+        assert_eq!(status(authorized).await, StatusCode::TOO_MANY_REQUESTS);
+
+        // Routes outside the scope need no key
+        let healthz = test::TestRequest::get().uri("/healthz");
+        assert_eq!(status(healthz).await, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_streamed_permit() {
+        let key = KeyConfig {
+            max_concurrent: 1,
+            ..key()
+        };
+        let keys = web::Data::new(KeyStore::new(true, vec![key]).unwrap());
+        let app = test::init_service(
+            App::new().app_data(keys).service(
+                web::scope("")
+                    .wrap_fn(authenticate)
+                    .route("/stream", web::get().to(endless)),
+            ),
+        )
+        .await;
+        let stream = || {
+            test::TestRequest::get()
+                .uri("/stream")
+                .insert_header((AUTHORIZATION, "Bearer secret"))
+                .to_request()
+        };
+
+        // The request counts as in flight while its body streams
+        let open = test::call_service(&app, stream()).await;
+        assert_eq!(open.status(), StatusCode::OK);
+        let refused = test::call_service(&app, stream()).await;
+        assert_eq!(refused.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        drop(open);
+        let res = test::call_service(&app, stream()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,340 @@
+//! The client-facing gRPC API, for services that would rather not speak JSON.
+//! It is served alongside the HTTP API on `grpc_server.port`, and shares the
+//! validation and dispatch of the HTTP routes, so that a request behaves the
+//! same over either
+
+use super::auth::{bearer_token, Caller};
+use super::priority::{parse_priority, scheduling_of};
+use super::routes::{admit_batch, parse_timeout, readiness, serve_batch, validate_input};
+use super::validate::InputLimits;
+use crate::api::autodep_server::{self, AutodepServer};
+use crate::api::{Model, Models, Status as ServerStatus};
+use crate::error::Error;
+use crate::keys::{KeyStore, Permit};
+use crate::manager::scheduler::{Priority, Scheduling};
+use crate::manager::Manager;
+use crate::ratelimit::RateLimiter;
+use crate::rpc::{self, batch_result};
+use crate::worker::WorkerStatus;
+use crate::{telemetry, torch};
+
+use config::Config;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tonic::metadata::MetadataMap;
+use tonic::{Request, Response, Status};
+use tracing::*;
+
+type Result<T> = std::result::Result<T, Status>;
+
+/// gRPC metadata key clients can use to set the deadline of a request, in
+/// millis, like the `X-Request-Timeout` header
+const TIMEOUT_METADATA: &str = "x-request-timeout";
+
+/// gRPC metadata key clients can use to set the priority of a request, like
+/// the `X-Priority` header
+const PRIORITY_METADATA: &str = "x-priority";
+
+/// gRPC metadata key telling clients how many times their request was retried
+/// on another worker
+const RETRIES_METADATA: &str = "x-autodep-retries";
+
+pub struct Api {
+    manager: Arc<RwLock<Manager>>,
+    config: Config,
+    limits: Arc<InputLimits>,
+    keys: Arc<KeyStore>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl Api {
+    pub fn new(
+        manager: Arc<RwLock<Manager>>,
+        config: Config,
+        limits: Arc<InputLimits>,
+        keys: Arc<KeyStore>,
+        limiter: Arc<RateLimiter>,
+    ) -> Api {
+        Api {
+            manager,
+            config,
+            limits,
+            keys,
+            limiter,
+        }
+    }
+
+    /// Serve the API on `addr` until the server stops. Messages can be as
+    /// large as a batch request over HTTP
+    pub async fn serve(self, addr: SocketAddr) -> anyhow::Result<()> {
+        let max_message_size = self.config.get_int("batch.max_payload")? as usize;
+        let svc = AutodepServer::new(self).max_decoding_message_size(max_message_size);
+        info!("serving the gRPC API on {addr}");
+        tonic::transport::Server::builder()
+            .add_service(svc)
+            .serve(addr)
+            .await?;
+        Ok(())
+    }
+
+    /// Get the deadline of a request from its metadata
+    fn timeout(&self, metadata: &MetadataMap) -> crate::error::Result<Duration> {
+        let timeout = metadata.get(TIMEOUT_METADATA);
+        parse_timeout(timeout.map(|t| t.to_str().unwrap_or("")), &self.config)
+    }
+
+    /// Check an inference request against the rate limits
+    fn limit<T>(&self, request: &Request<T>) -> crate::error::Result<()> {
+        self.limiter.check(&self.client(request))
+    }
+
+    /// Identify the client of a request by the metadata named after
+    /// `rate_limit.client_header`, if it came through a trusted proxy, or by
+    /// its IP address
+    fn client<T>(&self, request: &Request<T>) -> String {
+        let header = self.limiter.config.client_header.to_ascii_lowercase();
+        let value = (!header.is_empty())
+            .then(|| request.metadata().get(header.as_str()))
+            .flatten();
+        self.limiter.config.client_id(
+            value.and_then(|value| value.to_str().ok()),
+            request.remote_addr().map(|addr| addr.ip()),
+        )
+    }
+
+    /// How to queue a request, by the priority in its metadata or else
+    /// `default`, as the HTTP API does
+    fn scheduling<T>(
+        &self,
+        request: &Request<T>,
+        caller: &Caller,
+        default: Priority,
+    ) -> crate::error::Result<Scheduling> {
+        let value = request.metadata().get(PRIORITY_METADATA);
+        let priority = parse_priority(value.map(|v| v.to_str().unwrap_or("")), None, default)?;
+        Ok(scheduling_of(caller, priority, || self.client(request)))
+    }
+
+    /// Authenticate a request by the API key in its `authorization` metadata,
+    /// and admit it under the limits of its key, as the HTTP API does. The
+    /// request counts against its key's concurrency limit until the permit is
+    /// dropped
+    fn admit(&self, metadata: &MetadataMap) -> crate::error::Result<(Option<Permit>, Caller)> {
+        let header = metadata.get("authorization").and_then(|v| v.to_str().ok());
+        let model = self.manager.read().unwrap().model().name.clone();
+        let permit = self.keys.admit(bearer_token(header), &model)?;
+        let caller = Caller(permit.as_ref().map(|permit| permit.key().clone()));
+        Ok((permit, caller))
+    }
+}
+
+/// Get the ID of an incoming request, or generate one, along with a span to
+/// serve it under
+fn request_span(name: &str, metadata: &MetadataMap) -> (String, Span) {
+    let request_id = telemetry::request_id(Some(telemetry::rpc_request_id(metadata)));
+    let span = info_span!("grpc", method = name, request_id = %request_id);
+    telemetry::set_parent(&span, metadata);
+    (request_id, span)
+}
+
+/// Send the ID of a request back to the client, as the HTTP API does
+fn with_request_id<T>(mut res: Response<T>, request_id: &str) -> Response<T> {
+    if let Ok(id) = request_id.parse() {
+        let metadata = res.metadata_mut();
+        metadata.insert(telemetry::REQUEST_ID_METADATA, id);
+    }
+    res
+}
+
+#[tonic::async_trait]
+impl autodep_server::Autodep for Api {
+    /// Run inference on a single task
+    async fn infer(
+        &self,
+        request: Request<rpc::InferenceTask>,
+    ) -> Result<Response<rpc::Inference>> {
+        let (request_id, span) = request_span("infer", request.metadata());
+        self.limit(&request)?;
+        let (_permit, caller) = self.admit(request.metadata())?;
+        let timeout = self.timeout(request.metadata())?;
+        let scheduling = self.scheduling(&request, &caller, Priority::Interactive)?;
+        let task = torch::InferenceTask::try_from(request.into_inner())?;
+
+        async {
+            info!("got inference request: {:?}", task);
+            validate_input(&task, &self.limits)?;
+            caller.allow(&task)?;
+            let res =
+                Manager::dispatch(&self.manager, task, &request_id, &scheduling, timeout).await?;
+            info!("finished serving inference request");
+
+            let mut response = Response::new(rpc::Inference::from(res.inference));
+            response
+                .metadata_mut()
+                .insert(RETRIES_METADATA, res.retries.into());
+            Ok::<_, Status>(with_request_id(response, &request_id))
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Run inference on a batch of tasks. A task that is invalid or fails does
+    /// not fail the batch: its error is returned in its place
+    async fn infer_batch(
+        &self,
+        request: Request<rpc::InferenceBatch>,
+    ) -> Result<Response<rpc::BatchResults>> {
+        let (request_id, span) = request_span("infer_batch", request.metadata());
+        self.limit(&request)?;
+        let (_permit, caller) = self.admit(request.metadata())?;
+        let timeout = self.timeout(request.metadata())?;
+        let scheduling = self.scheduling(&request, &caller, Priority::Batch)?;
+        let tasks = request
+            .into_inner()
+            .tasks
+            .into_iter()
+            .map(|task| caller.allow_task(torch::InferenceTask::try_from(task)))
+            .collect::<Vec<_>>();
+
+        async {
+            info!("got a batch of {} inference requests", tasks.len());
+            admit_batch(tasks.len(), &self.config, &caller)?;
+            let outputs = serve_batch(
+                &self.manager,
+                tasks,
+                &request_id,
+                &scheduling,
+                timeout,
+                &self.limits,
+            )
+            .await?;
+            let results = outputs
+                .into_iter()
+                .map(|res| rpc::BatchResult {
+                    result: Some(match res {
+                        Ok(inference) => batch_result::Result::Inference(inference.into()),
+                        Err(e) => batch_result::Result::Error(e.into()),
+                    }),
+                })
+                .collect();
+            info!("finished serving batch inference request");
+
+            let response = Response::new(rpc::BatchResults { results });
+            Ok::<_, Status>(with_request_id(response, &request_id))
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// List the models being served
+    async fn list_models(&self, request: Request<rpc::Empty>) -> Result<Response<Models>> {
+        let _permit = self.admit(request.metadata())?;
+        let model = self.manager.read().unwrap().model();
+        Ok(Response::new(Models {
+            models: vec![Model {
+                name: model.name.clone(),
+                path: model.path.clone(),
+                version: model.version.clone(),
+            }],
+        }))
+    }
+
+    /// Get the status of the server: the outcome of the readiness check, and
+    /// the number of workers by status
+    async fn get_status(&self, request: Request<rpc::Empty>) -> Result<Response<ServerStatus>> {
+        let _permit = self.admit(request.metadata())?;
+        let statuses: Vec<WorkerStatus> = {
+            let manager = self.manager.read().unwrap();
+            manager
+                .all_status()
+                .map_err(Error::from)?
+                .into_values()
+                .collect()
+        };
+        let mut workers = HashMap::new();
+        for status in statuses {
+            *workers.entry(format!("{status:?}")).or_insert(0) += 1;
+        }
+
+        let health = readiness(&self.manager, &self.config).await?;
+        Ok(Response::new(ServerStatus {
+            ready: health.ok,
+            errors: health.errors,
+            ready_workers: health.workers.unwrap_or_default() as u32,
+            workers,
+            queue_depth: health.queue_depth.unwrap_or_default(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::autodep_server::Autodep;
+    use crate::ratelimit::RateLimitConfig;
+    use config::{File, FileFormat};
+
+    /// An API in front of a manager without workers
+    fn api() -> Api {
+        let config = Config::builder()
+            .add_source(File::from_str(
+                include_str!("../../config.toml"),
+                FileFormat::Toml,
+            ))
+            .build()
+            .unwrap();
+        let limits = InputLimits::from_config(&config).unwrap();
+        let limiter = RateLimiter::new(RateLimitConfig::from_config(&config).unwrap());
+        Api::new(
+            Arc::new(RwLock::new(Manager::without_workers(config.clone()))),
+            config,
+            Arc::new(limits),
+            Arc::new(KeyStore::new(false, vec![]).unwrap()),
+            Arc::new(limiter),
+        )
+    }
+
+    fn text_task(text: Option<&str>) -> rpc::InferenceTask {
+        rpc::InferenceTask {
+            inference_type: Some(rpc::InferenceType {
+                r#type: 2,
+                top_n: None,
+            }),
+            image: None,
+            text: text.map(Into::into),
+            raw_image: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_infer() {
+        let api = api();
+
+        // Malformed and invalid tasks are rejected before they are queued
+        let status = api.infer(Request::new(text_task(None))).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        let long = "a".repeat(1 << 20);
+        let status = api
+            .infer(Request::new(text_task(Some(&long))))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        let mut request = Request::new(text_task(Some("hello")));
+        request
+            .metadata_mut()
+            .insert(TIMEOUT_METADATA, "soon".parse().unwrap());
+        let status = api.infer(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        // Without workers, a valid task waits until its deadline
+        let mut request = Request::new(text_task(Some("hello")));
+        request
+            .metadata_mut()
+            .insert(TIMEOUT_METADATA, "10".parse().unwrap());
+        let status = api.infer(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+    }
+}
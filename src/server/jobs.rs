@@ -0,0 +1,132 @@
+//! Routes for asynchronous inference jobs. A job is submitted with the same
+//! body as a synchronous inference request, and its status and result are
+//! polled with the ID it is given. A job may also carry a `callback_url`, to
+//! which it is POSTed once it finishes
+
+use super::auth::Caller;
+use super::priority::scheduling;
+use super::request::TaskRequest;
+use super::routes::validate_input;
+use super::validate::InputLimits;
+use super::{RequestId, WebError};
+use crate::jobs::JobStore;
+use crate::manager::scheduler::Priority;
+use crate::manager::Manager;
+use crate::webhooks::Webhooks;
+
+use actix_web::http::header::LOCATION;
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse, Responder};
+use serde::Deserialize;
+use std::sync::RwLock;
+use tracing::*;
+use utoipa::ToSchema;
+
+type Result<T> = std::result::Result<T, WebError>;
+
+/// The body of a job: an inference task, and where to deliver its result
+#[derive(Deserialize, ToSchema)]
+pub struct JobRequest {
+    #[serde(flatten)]
+    task: TaskRequest,
+
+    /// URL the job is POSTed to once it finishes
+    callback_url: Option<String>,
+}
+
+/// Submit an inference job, and return at once with its ID. The `Location`
+/// of the job is under the same prefix as the request, `/jobs` or `/v1/jobs`
+#[utoipa::path(
+    post,
+    path = "/jobs",
+    request_body = JobRequest,
+    responses(
+        (status = 202, description = "The job was queued", body = Job),
+        (status = "4XX", description = "The job is invalid", body = ErrorBody),
+    ),
+)]
+#[post("/jobs")]
+#[tracing::instrument(skip_all, fields(request_id = %request_id.0))]
+pub async fn submit(
+    req: web::Json<JobRequest>,
+    http_req: HttpRequest,
+    request_id: RequestId,
+    state: web::Data<RwLock<Manager>>,
+    jobs: web::Data<JobStore>,
+    limits: web::Data<InputLimits>,
+    caller: Caller,
+) -> Result<impl Responder> {
+    let JobRequest { task, callback_url } = req.into_inner();
+    let TaskRequest { task, priority } = task;
+    info!("got inference job: {:?}", task);
+    validate_input(&task, &limits)?;
+    caller.allow(&task)?;
+    if let Some(url) = &callback_url {
+        jobs.webhooks().validate_url(url).await?;
+    }
+
+    let scheduling = scheduling(&http_req, priority, Priority::Batch)?;
+    let job = jobs.into_inner().submit(
+        state.into_inner(),
+        task,
+        request_id.0,
+        scheduling,
+        callback_url,
+    )?;
+    Ok(HttpResponse::Accepted()
+        .insert_header((LOCATION, format!("{}/{}", http_req.path(), job.id)))
+        .json(job))
+}
+
+/// Get the status of a job, and its result once it has finished
+#[utoipa::path(
+    get,
+    path = "/jobs/{id}",
+    params(("id" = String, Path, description = "The ID of the job")),
+    responses(
+        (status = 200, description = "The job", body = Job),
+        (status = 404, description = "There is no such job", body = ErrorBody),
+    ),
+)]
+#[get("/jobs/{id}")]
+pub async fn status(id: web::Path<String>, jobs: web::Data<JobStore>) -> Result<impl Responder> {
+    Ok(web::Json(jobs.get(&id)?))
+}
+
+/// Cancel a job. A finished job is deleted instead
+#[utoipa::path(
+    delete,
+    path = "/jobs/{id}",
+    params(("id" = String, Path, description = "The ID of the job")),
+    responses(
+        (status = 200, description = "The job was cancelled", body = Job),
+        (status = 204, description = "The finished job was deleted"),
+        (status = 404, description = "There is no such job", body = ErrorBody),
+    ),
+)]
+#[delete("/jobs/{id}")]
+pub async fn cancel(id: web::Path<String>, jobs: web::Data<JobStore>) -> Result<impl Responder> {
+    Ok(match jobs.cancel(&id)? {
+        Some(job) => HttpResponse::Ok().json(job),
+        None => HttpResponse::NoContent().finish(),
+    })
+}
+
+/// Get the deliveries of a job to its callback URL, most recent first
+#[utoipa::path(
+    get,
+    path = "/jobs/{id}/deliveries",
+    params(("id" = String, Path, description = "The ID of the job")),
+    responses(
+        (status = 200, description = "The deliveries of the job", body = Vec<Delivery>),
+        (status = 404, description = "There is no such job", body = ErrorBody),
+    ),
+)]
+#[get("/jobs/{id}/deliveries")]
+pub async fn deliveries(
+    id: web::Path<String>,
+    jobs: web::Data<JobStore>,
+    webhooks: web::Data<Webhooks>,
+) -> Result<impl Responder> {
+    let job = jobs.get(&id)?;
+    Ok(web::Json(webhooks.deliveries(Some(&job.id), None)))
+}
@@ -1,15 +1,44 @@
-use crate::manager::Manager;
-use actix_web::http::header::ContentType;
+use crate::error::Error;
+use crate::jobs::{JobConfig, JobStore};
+use crate::keys::KeyStore;
+use crate::manager::{monitor, Manager};
+use crate::ratelimit::{RateLimitConfig, RateLimiter};
+use crate::telemetry::{self, REQUEST_ID_HEADER};
+use crate::webhooks::{WebhookConfig, Webhooks};
+use actix_web::dev::{Payload, Service};
+use actix_web::error::JsonPayloadError;
+use actix_web::http::header::{self, ContentType, HeaderName, HeaderValue};
 use actix_web::http::StatusCode;
-use actix_web::HttpResponse;
-use actix_web::{middleware, web, App, HttpServer};
-use anyhow::anyhow;
+use actix_web::{
+    middleware, web, App, FromRequest, HttpMessage, HttpRequest, HttpResponse, HttpServer,
+};
 use config::Config;
-use std::collections::HashMap;
+use serde::Serialize;
+use std::future::{ready, Ready};
 use std::io;
-use std::sync::RwLock;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, RwLock};
+use tls::{CertStore, TlsConfig};
+use utoipa::ToSchema;
+use validate::InputLimits;
+use ws::WsConfig;
 
+pub mod admin;
+pub mod auth;
+pub mod grpc;
+pub mod jobs;
+pub mod openapi;
+pub mod priority;
+pub mod request;
 pub mod routes;
+pub mod throttle;
+pub mod tls;
+pub mod validate;
+pub mod ws;
+
+/// Header telling clients how many times their request was retried on another
+/// worker
+pub const RETRIES_HEADER: &str = "X-Autodep-Retries";
 
 pub struct Server;
 
@@ -19,30 +48,190 @@ impl Server {
             Manager::new(model, config.clone()).await.unwrap(),
         ));
 
+        // Recycle workers in the background
+        actix_web::rt::spawn(monitor::run(manager.clone().into_inner()));
+
+        // Expire finished jobs in the background, and deliver them to their
+        // callback URLs
+        let webhooks = WebhookConfig::from_config(&config).unwrap();
+        let webhooks = web::Data::new(Webhooks::new(webhooks).unwrap());
+        let job_config = JobConfig::from_config(&config).unwrap();
+        let jobs = JobStore::new(job_config, webhooks.clone().into_inner()).unwrap();
+        let jobs = web::Data::new(jobs);
+        actix_web::rt::spawn(crate::jobs::run_sweeper(jobs.clone().into_inner()));
+
+        let limits = web::Data::new(InputLimits::from_config(&config).unwrap());
+        let max_body_size = config.get_int("http_server.max_body_size").unwrap() as usize;
+        let max_batch_payload = config.get_int("batch.max_payload").unwrap() as usize;
+        let ws_config = web::Data::new(WsConfig::from_config(&config).unwrap());
+        let keys = web::Data::new(KeyStore::from_config(&config).unwrap());
+        let limiter = RateLimitConfig::from_config(&config).unwrap();
+        let limiter = web::Data::new(RateLimiter::new(limiter));
+
+        // Serve the gRPC API in the background. It has no TLS of its own
+        let tls = TlsConfig::from_config(&config).unwrap();
+        if config.get_bool("grpc_server.enabled").unwrap() {
+            check_grpc_plaintext(&config, &tls)?;
+            let port = config.get_int("grpc_server.port").unwrap() as u16;
+            let addr = listen_addr(&config, port)?;
+            let api = grpc::Api::new(
+                manager.clone().into_inner(),
+                config.clone(),
+                limits.clone().into_inner(),
+                keys.clone().into_inner(),
+                limiter.clone().into_inner(),
+            );
+            actix_web::rt::spawn(async move {
+                if let Err(e) = api.serve(addr).await {
+                    tracing::error!("the gRPC API failed: {e}");
+                }
+            });
+        }
+
         // Start the HTTP server
         let cfg = config.clone();
-        HttpServer::new(move || {
+        let server = HttpServer::new(move || {
             App::new()
                 .app_data(manager.clone())
                 .app_data(web::Data::new(cfg.clone()))
-                .wrap(middleware::Logger::default())
-                .service(routes::inference)
+                .app_data(limits.clone())
+                .app_data(jobs.clone())
+                .app_data(webhooks.clone())
+                .app_data(ws_config.clone())
+                .app_data(keys.clone())
+                .app_data(limiter.clone())
+                .app_data(
+                    web::JsonConfig::default()
+                        .limit(max_body_size)
+                        .error_handler(json_error),
+                )
+                .wrap_fn(|req, srv| {
+                    // Tag every request with an ID, and send it back to the
+                    // client
+                    let client_id = req.headers().get(REQUEST_ID_HEADER);
+                    let id = telemetry::request_id(client_id.and_then(|id| id.to_str().ok()));
+                    req.extensions_mut().insert(RequestId(id.clone()));
+                    let res = srv.call(req);
+                    async move {
+                        let mut res = res.await?;
+                        let header = HeaderName::try_from(REQUEST_ID_HEADER);
+                        if let (Ok(header), Ok(id)) = (header, HeaderValue::from_str(&id)) {
+                            res.headers_mut().insert(header, id);
+                        }
+                        Ok(res)
+                    }
+                })
+                .wrap(middleware::Logger::new(
+                    r#"%a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T %{X-Request-Id}o"#,
+                ))
+                // Requests to the inference API are rate limited before they
+                // are authenticated, so that floods of bad keys are refused
+                // cheaply
+                .service(
+                    web::scope("/v1")
+                        .wrap_fn(auth::authenticate)
+                        .wrap_fn(throttle::limit)
+                        .service(routes::inference_v1)
+                        .configure(|cfg| inference_api(cfg, max_batch_payload)),
+                )
                 .service(routes::worker_status)
                 .service(routes::all_workers)
                 .service(routes::worker_info)
-        })
-        .bind(format!(
-            "0.0.0.0:{}",
-            config.clone().get_int("http_server.port").unwrap()
-        ))?
-        .run()
-        .await
+                .service(routes::prometheus_metrics)
+                .service(openapi::spec)
+                .service(routes::healthz)
+                .service(routes::readyz)
+                .service(admin::add_workers)
+                .service(admin::remove_worker)
+                .service(admin::pool_settings)
+                .service(admin::update_pool)
+                .service(admin::reload)
+                .service(admin::deliveries)
+                .service(admin::keys)
+                // The unversioned API is registered last, as its scope
+                // matches every path
+                .service(
+                    web::scope("")
+                        .wrap_fn(auth::authenticate)
+                        .wrap_fn(throttle::limit)
+                        .service(routes::inference)
+                        .configure(|cfg| inference_api(cfg, max_batch_payload)),
+                )
+        });
+
+        // Serve HTTPS if TLS is enabled, reloading the certificate on SIGHUP
+        let addr = listen_addr(&config, config.get_int("http_server.port").unwrap() as u16)?;
+        let server = if tls.enabled {
+            let certs = Arc::new(CertStore::load(&tls).unwrap());
+            actix_web::rt::spawn(tls::reload_on_sighup(certs.clone()));
+            server.bind_rustls_021(addr, tls.server_config(certs).unwrap())?
+        } else {
+            server.bind(addr)?
+        };
+        server.run().await
+    }
+}
+
+/// The address the servers listen on: `http_server.host`, on `port`
+fn listen_addr(config: &Config, port: u16) -> io::Result<SocketAddr> {
+    let host = config.get_string("http_server.host").unwrap();
+    let addr = (host.as_str(), port).to_socket_addrs()?.next();
+    addr.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("cannot resolve {host}")))
+}
+
+/// Refuse to serve the gRPC API in plaintext while the HTTP API is served
+/// over TLS, unless `grpc_server.allow_plaintext` says it is meant to be,
+/// such as behind a proxy that terminates TLS
+fn check_grpc_plaintext(config: &Config, tls: &TlsConfig) -> io::Result<()> {
+    if tls.enabled && !config.get_bool("grpc_server.allow_plaintext").unwrap() {
+        let msg = "TLS is enabled, but the gRPC API has no TLS: set grpc_server.enabled \
+            to false, or grpc_server.allow_plaintext to true";
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+    }
+    Ok(())
+}
+
+/// Register the routes of the inference API, which are served both under
+/// `/v1` and, for clients of the unversioned API, at the root. `/inference`
+/// is not among them, as its response differs between the two. These routes
+/// keep their own response formats, as they predate the envelope of
+/// `/v1/inference`
+fn inference_api(cfg: &mut web::ServiceConfig, max_batch_payload: usize) {
+    cfg.service(routes::inference_stream)
+        .service(
+            web::resource("/inference/batch")
+                .app_data(
+                    web::JsonConfig::default()
+                        .limit(max_batch_payload)
+                        .error_handler(json_error),
+                )
+                .route(web::post().to(routes::batch_inference)),
+        )
+        .service(ws::inference)
+        .service(jobs::submit)
+        .service(jobs::status)
+        .service(jobs::cancel)
+        .service(jobs::deliveries);
+}
+
+/// The ID of the request being served
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+impl FromRequest for RequestId {
+    type Error = actix_web::Error;
+    type Future = Ready<std::result::Result<RequestId, actix_web::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let id = req.extensions().get::<RequestId>().cloned();
+        let id = id.unwrap_or_else(|| RequestId(telemetry::request_id(None)));
+        ready(Ok(id))
     }
 }
 
 #[derive(Debug)]
 pub struct WebError {
-    err: anyhow::Error,
+    err: Error,
 }
 
 impl std::fmt::Display for WebError {
@@ -53,32 +242,97 @@ impl std::fmt::Display for WebError {
 
 impl actix_web::error::ResponseError for WebError {
     fn error_response(&self) -> HttpResponse {
-        let err = HashMap::from([("errors", vec![self.to_string()])]);
+        let err = ErrorBody {
+            code: self.err.code(),
+            errors: vec![self.to_string()],
+        };
 
-        HttpResponse::build(self.status_code())
-            .insert_header(ContentType::json())
-            .json(err)
+        let mut res = HttpResponse::build(self.status_code());
+        if let Error::Retries(n, _) = self.err {
+            res.insert_header((RETRIES_HEADER, n));
+        }
+        if let Error::Unauthorized(_) = self.err {
+            res.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+        }
+        if let Error::RateLimited(_, retry_after) = self.err {
+            // Whole seconds, rounded up so that clients do not retry too early
+            let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            res.insert_header((header::RETRY_AFTER, secs.max(1)));
+        }
+        res.insert_header(ContentType::json()).json(err)
     }
 
     fn status_code(&self) -> StatusCode {
-        StatusCode::INTERNAL_SERVER_ERROR
+        status_code(&self.err)
+    }
+}
+
+/// The JSON body of an error response
+#[derive(Serialize, ToSchema)]
+struct ErrorBody {
+    /// A machine-readable error code
+    code: &'static str,
+    errors: Vec<String>,
+}
+
+fn status_code(err: &Error) -> StatusCode {
+    match err {
+        Error::InvalidInput(_) => StatusCode::BAD_REQUEST,
+        Error::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        Error::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        Error::UnsupportedInference(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        Error::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+        Error::Forbidden(_) => StatusCode::FORBIDDEN,
+        Error::NotFound(_) => StatusCode::NOT_FOUND,
+        Error::RateLimited(..) => StatusCode::TOO_MANY_REQUESTS,
+        Error::Busy => StatusCode::SERVICE_UNAVAILABLE,
+        Error::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+        Error::Worker(status) if status.code() == tonic::Code::Unavailable => {
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+        Error::Retries(_, err) => status_code(err),
+        Error::Worker(_) | Error::Other(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Turn errors in parsing a JSON request body into `WebError`s
+fn json_error(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    let err = match err {
+        JsonPayloadError::Overflow { .. } | JsonPayloadError::OverflowKnownLength { .. } => {
+            Error::PayloadTooLarge(err.to_string())
+        }
+        JsonPayloadError::ContentType => Error::UnsupportedMediaType(err.to_string()),
+        JsonPayloadError::Deserialize(e) if e.is_data() => {
+            Error::InvalidInput(format!("invalid request body: {e}"))
+        }
+        JsonPayloadError::Deserialize(e) => {
+            Error::InvalidInput(format!("request body is not valid JSON: {e}"))
+        }
+        _ => Error::InvalidInput(err.to_string()),
+    };
+    WebError::from(err).into()
+}
+
+impl From<Error> for WebError {
+    fn from(err: Error) -> WebError {
+        WebError { err }
     }
 }
 
 impl From<anyhow::Error> for WebError {
     fn from(err: anyhow::Error) -> WebError {
-        WebError { err }
+        WebError { err: err.into() }
     }
 }
 
 impl From<config::ConfigError> for WebError {
     fn from(err: config::ConfigError) -> WebError {
-        WebError { err: anyhow!(err) }
+        WebError { err: err.into() }
     }
 }
 
 impl From<base64::DecodeError> for WebError {
     fn from(err: base64::DecodeError) -> Self {
-        WebError { err: anyhow!(err) }
+        WebError { err: err.into() }
     }
 }
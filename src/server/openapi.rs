@@ -0,0 +1,104 @@
+//! The OpenAPI 3 specification of the HTTP API, generated from the types of
+//! its request and response bodies, and served at `/openapi.json`. It covers
+//! the API of clients only: the routes for operators (`/admin/*`, `/workers*`
+//! and `/metrics`) are left out on purpose, as is `/ws/inference`, since
+//! OpenAPI cannot describe the messages of a WebSocket
+
+use super::request::{TaggedTask, TaskRequest};
+use super::routes::{BatchItem, BatchResponse, Health, InferenceResponse};
+use super::{jobs, routes, ErrorBody};
+use crate::jobs::{Job, JobError, JobStatus};
+use crate::manager::dispatch::Timings;
+use crate::manager::scheduler::Priority;
+use crate::torch::{B64Image, Class, Inference, InferenceTask, InferenceType, WireInputData};
+use crate::webhooks::{Attempt, Delivery, DeliveryStatus};
+
+use actix_web::{get, web, Responder};
+use utoipa::OpenApi;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Autodep",
+        description = "Inference on TorchScript models, distributed across a pool of workers. \
+            The inference routes are served under `/v1`, and at the root for clients of the \
+            unversioned API. The WebSocket API at `/ws/inference` and the routes for operators \
+            are not described here"
+    ),
+    paths(
+        routes::inference_v1,
+        routes::inference,
+        routes::inference_stream,
+        routes::batch_inference,
+        jobs::submit,
+        jobs::status,
+        jobs::cancel,
+        jobs::deliveries,
+        routes::healthz,
+        routes::readyz,
+    ),
+    components(schemas(
+        TaskRequest,
+        TaggedTask,
+        Priority,
+        InferenceTask,
+        WireInputData,
+        B64Image,
+        InferenceType,
+        Inference,
+        Class,
+        InferenceResponse,
+        Timings,
+        BatchItem,
+        BatchResponse,
+        jobs::JobRequest,
+        Job,
+        JobStatus,
+        JobError,
+        Delivery,
+        DeliveryStatus,
+        Attempt,
+        Health,
+        ErrorBody,
+    ))
+)]
+pub struct ApiDoc;
+
+/// Get the OpenAPI specification of the HTTP API
+#[get("/openapi.json")]
+pub async fn spec() -> impl Responder {
+    web::Json(ApiDoc::openapi())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spec() {
+        let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        assert!(doc["paths"]["/inference"]["post"].is_object());
+        assert!(doc["paths"]["/v1/inference"]["post"].is_object());
+        assert!(doc["paths"]["/jobs/{id}"]["delete"].is_object());
+        assert!(doc["paths"]["/jobs/{id}/deliveries"]["get"].is_object());
+
+        // Operator and WebSocket routes are left out
+        let paths = doc["paths"].as_object().unwrap();
+        assert!(paths.keys().all(|path| !path.starts_with("/admin")
+            && !path.starts_with("/workers")
+            && path != "/metrics"
+            && path != "/ws/inference"));
+
+        // Internal input variants are left out
+        let input = doc["components"]["schemas"]["InputData"].to_string();
+        assert!(input.contains("\"B64Image\"") && !input.contains("\"Image\""));
+
+        // Every referenced schema is defined
+        let schemas = &doc["components"]["schemas"];
+        let text = doc.to_string();
+        for name in text.split("#/components/schemas/").skip(1) {
+            let name = name.split('"').next().unwrap();
+            assert!(schemas[name].is_object(), "{name} is not defined");
+        }
+    }
+}
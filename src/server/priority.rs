@@ -0,0 +1,127 @@
+//! The place of inference requests in the manager's queue. The priority of a
+//! request is set by its `X-Priority` header, or else by the `priority` field
+//! of its body, and otherwise depends on the route: single requests are
+//! interactive, and batches and jobs are batch requests. Requests are shared
+//! fairly between tenants: the owners of API keys, or the clients identified
+//! by the rate limiter when keys are not required. An API key can cap the
+//! priority of its requests, so that its clients cannot jump the queue
+
+use super::auth::Caller;
+use super::throttle;
+use crate::error::Result;
+use crate::manager::scheduler::{Priority, Scheduling};
+
+use actix_web::HttpRequest;
+
+/// Header clients can use to set the priority of a request, `interactive` or
+/// `batch`
+pub const PRIORITY_HEADER: &str = "X-Priority";
+
+/// How to queue a request, given the priority of its body, if any, and the
+/// default priority of its route
+pub fn scheduling(
+    req: &HttpRequest,
+    body: Option<Priority>,
+    default: Priority,
+) -> Result<Scheduling> {
+    let header = req.headers().get(PRIORITY_HEADER);
+    let priority = parse_priority(header.map(|h| h.to_str().unwrap_or("")), body, default)?;
+    Ok(scheduling_of(&Caller::of(req), priority, || {
+        throttle::client(req)
+    }))
+}
+
+/// Get the priority of a request from its header or gRPC metadata, which
+/// takes precedence over its body
+pub(super) fn parse_priority(
+    value: Option<&str>,
+    body: Option<Priority>,
+    default: Priority,
+) -> Result<Priority> {
+    match value {
+        Some(value) => value.parse(),
+        None => Ok(body.unwrap_or(default)),
+    }
+}
+
+/// Queue a request as its API key's, at no more than the key's priority, or
+/// as its client's if keys are not required
+pub(super) fn scheduling_of(
+    caller: &Caller,
+    priority: Priority,
+    client: impl FnOnce() -> String,
+) -> Scheduling {
+    match &caller.0 {
+        Some(key) => Scheduling {
+            priority: key.priority(priority),
+            tenant: key.name().to_string(),
+            weight: key.weight(),
+            max_wait: None,
+        },
+        None => Scheduling {
+            priority,
+            tenant: client(),
+            weight: 1,
+            max_wait: None,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::keys::{KeyConfig, KeyStore};
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_scheduling() {
+        let req = TestRequest::default()
+            .peer_addr("10.0.0.1:1234".parse().unwrap())
+            .to_http_request();
+        let queued = scheduling(&req, Some(Priority::Interactive), Priority::Batch).unwrap();
+        assert_eq!(queued.priority, Priority::Interactive);
+        assert_eq!(queued.tenant, "10.0.0.1");
+        assert_eq!(queued.weight, 1);
+
+        // The header takes precedence over the body
+        let req = TestRequest::default()
+            .insert_header((PRIORITY_HEADER, "Batch"))
+            .to_http_request();
+        let priority = scheduling(&req, Some(Priority::Interactive), Priority::Interactive)
+            .unwrap()
+            .priority;
+        assert_eq!(priority, Priority::Batch);
+
+        let req = TestRequest::default()
+            .insert_header((PRIORITY_HEADER, "urgent"))
+            .to_http_request();
+        let err = scheduling(&req, None, Priority::Interactive).unwrap_err();
+        assert!(matches!(err, Error::InvalidInput(_)));
+    }
+
+    #[test]
+    fn test_max_priority() {
+        let config = KeyConfig {
+            name: "alice".into(),
+            // The SHA-256 digest of "secret"
+            hash: "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b".into(),
+            requests_per_second: 0.0,
+            burst: None,
+            max_concurrent: 0,
+            daily_quota: 0,
+            models: vec![],
+            inference_types: vec![],
+            weight: Some(2),
+            max_priority: Some(Priority::Batch),
+        };
+        let keys = KeyStore::new(true, vec![config]).unwrap();
+        let permit = keys.admit(Some("secret"), "resnet18").unwrap().unwrap();
+        let caller = Caller(Some(permit.key().clone()));
+
+        // The key's requests cannot be interactive
+        let queued = scheduling_of(&caller, Priority::Interactive, String::new);
+        assert_eq!(queued.priority, Priority::Batch);
+        assert_eq!((queued.tenant.as_str(), queued.weight), ("alice", 2));
+    }
+}
@@ -0,0 +1,168 @@
+//! The formats of an inference request body. A task can be sent in the format
+//! of `torch::InferenceTask`, whose enums are externally tagged
+//! (`{"B64Image": {...}}`), or in a flatter format tagged by a `task` field:
+//!
+//! ```json
+//! {"task": "image_classification", "image": "<base 64 encoded image>", "top_n": 5}
+//! ```
+//!
+//! Either format can also carry the `priority` of the request
+
+use crate::manager::scheduler::Priority;
+use crate::torch::{B64Image, InferenceTask, InferenceType, InputData};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use utoipa::openapi::{AllOfBuilder, ObjectBuilder, OneOfBuilder, Ref, RefOr, Schema};
+use utoipa::ToSchema;
+
+/// An inference task, tagged by its `task` field
+#[derive(Deserialize, Debug, ToSchema)]
+#[serde(tag = "task", rename_all = "snake_case")]
+pub enum TaggedTask {
+    /// Classify an image, returning its `top_n` most likely classes
+    ImageClassification {
+        /// A base 64 encoded image
+        image: String,
+        top_n: u16,
+    },
+
+    /// Compute an image from an image, such as a segmentation mask
+    ImageToImage {
+        /// A base 64 encoded image
+        image: String,
+    },
+
+    /// Generate text from a prompt
+    TextToText { text: String },
+}
+
+impl From<TaggedTask> for InferenceTask {
+    fn from(task: TaggedTask) -> InferenceTask {
+        let image = |image| {
+            InputData::B64Image(B64Image {
+                image,
+                height: None,
+                width: None,
+            })
+        };
+        match task {
+            TaggedTask::ImageClassification { image: data, top_n } => InferenceTask {
+                data: image(data),
+                inference_type: InferenceType::ImageClassification { top_n },
+            },
+            TaggedTask::ImageToImage { image: data } => InferenceTask {
+                data: image(data),
+                inference_type: InferenceType::ImageToImage,
+            },
+            TaggedTask::TextToText { text } => InferenceTask {
+                data: InputData::Text(text),
+                inference_type: InferenceType::TextToText,
+            },
+        }
+    }
+}
+
+/// The body of an inference request, in either format. A body with a `task`
+/// field is parsed as a `TaggedTask`, so that a malformed body is reported
+/// against the format it was meant to be in
+#[derive(Debug)]
+pub struct TaskRequest {
+    pub task: InferenceTask,
+
+    /// The priority of the request, unless its `X-Priority` header sets one
+    pub priority: Option<Priority>,
+}
+
+impl<'de> Deserialize<'de> for TaskRequest {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<TaskRequest, D::Error> {
+        let mut value = serde_json::Value::deserialize(deserializer)?;
+        let priority = value
+            .as_object_mut()
+            .and_then(|body| body.remove("priority"))
+            .map(Priority::deserialize)
+            .transpose()
+            .map_err(D::Error::custom)?;
+        let task = if value.get("task").is_some() {
+            TaggedTask::deserialize(value).map(InferenceTask::from)
+        } else {
+            InferenceTask::deserialize(value)
+        };
+        let task = task.map_err(D::Error::custom)?;
+        Ok(TaskRequest { task, priority })
+    }
+}
+
+impl<'s> ToSchema<'s> for TaskRequest {
+    fn schema() -> (&'s str, RefOr<Schema>) {
+        let task = OneOfBuilder::new()
+            .item(Ref::from_schema_name("TaggedTask"))
+            .item(Ref::from_schema_name("InferenceTask"))
+            .build();
+        let priority = ObjectBuilder::new().property("priority", Ref::from_schema_name("Priority"));
+        let schema = AllOfBuilder::new()
+            .item(Schema::OneOf(task))
+            .item(priority)
+            .description(Some("An inference task in either format, and its priority"))
+            .build();
+        ("TaskRequest", Schema::AllOf(schema).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(body: &str) -> Result<InferenceTask, serde_json::Error> {
+        serde_json::from_str::<TaskRequest>(body).map(|req| req.task)
+    }
+
+    #[test]
+    fn test_tagged() {
+        let task = parse(r#"{"task": "image_classification", "image": "abc", "top_n": 3}"#);
+        let task = task.unwrap();
+        assert!(matches!(
+            task.inference_type,
+            InferenceType::ImageClassification { top_n: 3 }
+        ));
+        assert!(matches!(task.data, InputData::B64Image(image) if image.image == "abc"));
+
+        let task = parse(r#"{"task": "text_to_text", "text": "hello"}"#).unwrap();
+        assert!(matches!(task.inference_type, InferenceType::TextToText));
+        assert!(matches!(task.data, InputData::Text(text) if text == "hello"));
+    }
+
+    #[test]
+    fn test_legacy() {
+        let body = r#"{"data": {"Text": "hello"}, "inference_type": "TextToText"}"#;
+        let task = parse(body).unwrap();
+        assert!(matches!(task.inference_type, InferenceType::TextToText));
+    }
+
+    #[test]
+    fn test_priority() {
+        let body = r#"{"task": "text_to_text", "text": "hello", "priority": "batch"}"#;
+        let req = serde_json::from_str::<TaskRequest>(body).unwrap();
+        assert_eq!(req.priority, Some(Priority::Batch));
+        assert!(matches!(req.task.inference_type, InferenceType::TextToText));
+
+        let body = r#"{"task": "text_to_text", "text": "hello"}"#;
+        let req = serde_json::from_str::<TaskRequest>(body).unwrap();
+        assert_eq!(req.priority, None);
+
+        let body = r#"{"task": "text_to_text", "text": "hello", "priority": "urgent"}"#;
+        let err = serde_json::from_str::<TaskRequest>(body).unwrap_err();
+        assert!(err.to_string().contains("unknown variant `urgent`"));
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = parse(r#"{"task": "image_classification", "image": "abc"}"#).unwrap_err();
+        assert!(err.to_string().contains("missing field `top_n`"));
+
+        let err = parse(r#"{"task": "translation", "text": "hello"}"#).unwrap_err();
+        assert!(err.to_string().contains("unknown variant `translation`"));
+
+        let err = parse(r#"{"data": {"Text": "hello"}}"#).unwrap_err();
+        assert!(err.to_string().contains("missing field `inference_type`"));
+    }
+}
@@ -2,132 +2,425 @@
 //! is the "front end". The inference route is automatically created, and
 //! distributes inference computation across the array of workers.
 
-use super::WebError;
+use super::auth::Caller;
+use super::priority::scheduling;
+use super::request::TaskRequest;
+use super::validate::{validate, InputLimits};
+use super::{ErrorBody, RequestId, WebError, RETRIES_HEADER};
 
-use crate::manager::{self, Manager};
-
-use crate::rpc;
-use crate::rpc::worker_client::WorkerClient;
-use tonic::Request;
-
-use crate::torch::{Image, InputData};
+use crate::error::Error;
+use crate::manager::dispatch::Timings;
+use crate::manager::scheduler::{Priority, Scheduling};
+use crate::manager::Manager;
+use crate::metrics;
+use crate::torch::{self, Inference};
 use crate::worker::WorkerStatus;
-use crate::{config, torch};
 
-use actix_web::{get, post, web, HttpRequest, Responder};
+use actix_web::http::header::{CACHE_CONTROL, CONTENT_TYPE};
+use actix_web::http::StatusCode;
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
 use anyhow::anyhow;
-use base64::{engine::general_purpose, Engine as _};
+use config::Config;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
 use tracing::*;
+use utoipa::ToSchema;
 
-use std::sync::RwLock;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 type Result<T> = std::result::Result<T, WebError>;
 
+/// Header clients can use to set the deadline of a request, in millis
+const TIMEOUT_HEADER: &str = "X-Request-Timeout";
+
+/// The JSON body of a `/v1/inference` response
+#[derive(Serialize, ToSchema)]
+pub(super) struct InferenceResponse {
+    request_id: String,
+
+    /// The name of the model that computed the inference
+    model: String,
+
+    /// A digest of the model file, which changes whenever the model does
+    model_version: String,
+
+    /// The PID of the worker that computed the inference
+    worker: u32,
+
+    timings: Timings,
+    result: Inference,
+}
+
+/// Run inference on a single task
+#[utoipa::path(
+    post,
+    path = "/v1/inference",
+    request_body = TaskRequest,
+    params(
+        ("X-Request-Timeout" = Option<u64>, Header, description = "Deadline in millis"),
+        ("X-Priority" = Option<Priority>, Header,
+            description = "`interactive` or `batch`, instead of the body's `priority`"),
+    ),
+    responses(
+        (status = 200, description = "The inference", body = InferenceResponse),
+        (status = "4XX", description = "The request is invalid", body = ErrorBody),
+        (status = "5XX", description = "The request failed", body = ErrorBody),
+    ),
+)]
 #[post("/inference")]
+#[tracing::instrument(skip_all, fields(request_id = %request_id.0))]
+pub async fn inference_v1(
+    req: web::Json<TaskRequest>,
+    http_req: HttpRequest,
+    request_id: RequestId,
+    state: web::Data<RwLock<Manager>>,
+    config: web::Data<Config>,
+    limits: web::Data<InputLimits>,
+    caller: Caller,
+) -> Result<impl Responder> {
+    let req = req.into_inner();
+    let input = req.task;
+    info!("got inference request: {:?}", input);
+    validate_input(&input, &limits)?;
+    caller.allow(&input)?;
+
+    let timeout = request_timeout(&http_req, &config)?;
+    let scheduling = scheduling(&http_req, req.priority, Priority::Interactive)?;
+    let manager = state.into_inner();
+    let res = Manager::dispatch(&manager, input, &request_id.0, &scheduling, timeout).await?;
+    info!("finished serving inference request");
+
+    let body = InferenceResponse {
+        request_id: request_id.0,
+        model: res.model.name.clone(),
+        model_version: res.model.version.clone(),
+        worker: res.worker.pid,
+        timings: res.timings,
+        result: res.inference.0,
+    };
+    Ok(HttpResponse::Ok()
+        .insert_header((RETRIES_HEADER, res.retries))
+        .json(body))
+}
+
+/// Run inference on a single task, and return the inference along with the
+/// time the model took to compute it. Kept for clients of the unversioned
+/// API; new clients should use `/v1/inference`
+#[utoipa::path(
+    post,
+    path = "/inference",
+    request_body = TaskRequest,
+    params(
+        ("X-Request-Timeout" = Option<u64>, Header, description = "Deadline in millis"),
+        ("X-Priority" = Option<Priority>, Header,
+            description = "`interactive` or `batch`, instead of the body's `priority`"),
+    ),
+    responses(
+        (status = 200, description = "The inference and the time the model took to compute it",
+            body = (Inference, Object)),
+        (status = "4XX", description = "The request is invalid", body = ErrorBody),
+        (status = "5XX", description = "The request failed", body = ErrorBody),
+    ),
+)]
+#[post("/inference")]
+#[tracing::instrument(skip_all, fields(request_id = %request_id.0))]
 pub async fn inference(
-    req: web::Json<torch::InferenceTask>,
+    req: web::Json<TaskRequest>,
+    http_req: HttpRequest,
+    request_id: RequestId,
     state: web::Data<RwLock<Manager>>,
+    config: web::Data<Config>,
+    limits: web::Data<InputLimits>,
+    caller: Caller,
 ) -> Result<impl Responder> {
-    // Parse the input request
-    let input = req.into_inner();
+    // Parse and validate the input request
+    let req = req.into_inner();
+    let input = req.task;
     info!("got inference request: {:?}", input);
+    validate_input(&input, &limits)?;
+    caller.allow(&input)?;
 
-    // Get a handle to an idle worker
-    let worker = {
-        let worker = {
-            let m = state.read().unwrap();
-            m.get_idle_worker()
-        };
+    let timeout = request_timeout(&http_req, &config)?;
+    let scheduling = scheduling(&http_req, req.priority, Priority::Interactive)?;
+    let manager = state.into_inner();
+    let res = Manager::dispatch(&manager, input, &request_id.0, &scheduling, timeout).await?;
 
-        debug!("found idle worker");
-
-        match worker {
-            Some(worker) => Ok::<manager::Handle, anyhow::Error>(worker),
-            None => {
-                warn!("all workers are busy");
-                Err(anyhow!("all workers are busy"))
-                /*
-                if config::AUTO_SCALE {
-                    // Start a new worker
-                    info!("dynamically starting a new worker");
-                    manager.start_new_workers(1).await?;
-                    let worker = {
-                        let worker = manager.get_idle_worker();
-                        if let Some(worker) = worker {
-                            manager.set_worker_status(worker.pid, WorkerStatus::Working);
-                            debug!("set idle worker to busy");
-                            Ok(worker)
-                        } else {
-                            Err(anyhow!(
-                                "all workers are busy, even after starting a new worker"
-                            ))
-                        }
-                    }?;
-                    Ok(worker)
-                } else {
-                    Err(anyhow!("all workers are busy: retry again later"))
-                }
-                */
-            }
-        }
-    }?;
+    debug!("received inference response");
 
-    // Send the inference request to the worker via RPC
-    let channel = worker.channel.clone();
-    debug!("sending inference request");
+    info!("finished serving inference request");
 
-    // Mark the work as busy
-    let fast_workers = {
-        let s = state.read().unwrap();
-        s.config.get_bool("manager.fast_workers")?
-    };
+    Ok(HttpResponse::Ok()
+        .insert_header((RETRIES_HEADER, res.retries))
+        .json(res.inference))
+}
 
-    //let output = Manager::run_inference(channel, input).await?;
+/// Run inference, and stream its progress and the text it generates as
+/// Server-Sent Events, followed by its result
+#[utoipa::path(
+    post,
+    path = "/inference/stream",
+    request_body = TaskRequest,
+    params(
+        ("X-Request-Timeout" = Option<u64>, Header, description = "Deadline in millis"),
+        ("X-Priority" = Option<Priority>, Header,
+            description = "`interactive` or `batch`, instead of the body's `priority`"),
+    ),
+    responses(
+        (status = 200, description = "`progress`, `token`, `inference` and `error` events",
+            content_type = "text/event-stream", body = String),
+        (status = "4XX", description = "The request is invalid", body = ErrorBody),
+        (status = "5XX", description = "The request failed", body = ErrorBody),
+    ),
+)]
+#[post("/inference/stream")]
+#[tracing::instrument(skip_all, fields(request_id = %request_id.0))]
+pub async fn inference_stream(
+    req: web::Json<TaskRequest>,
+    http_req: HttpRequest,
+    request_id: RequestId,
+    state: web::Data<RwLock<Manager>>,
+    config: web::Data<Config>,
+    limits: web::Data<InputLimits>,
+    caller: Caller,
+) -> Result<impl Responder> {
+    let req = req.into_inner();
+    let input = req.task;
+    info!("got streaming inference request: {:?}", input);
+    validate_input(&input, &limits)?;
+    caller.allow(&input)?;
 
-    let mut worker_client = WorkerClient::new(channel);
-    let ty = input.inference_type.clone();
-    let req = Request::new(input.into());
+    let timeout = request_timeout(&http_req, &config)?;
+    let scheduling = scheduling(&http_req, req.priority, Priority::Interactive)?;
+    let manager = state.into_inner();
+    let mut events = Manager::dispatch_stream(&manager, input, &request_id.0, &scheduling, timeout);
 
-    if !fast_workers {
-        let mut manager = state.write().unwrap();
-        manager.set_worker_status(worker.pid, WorkerStatus::Working);
-        debug!("set idle worker to busy");
-    }
-    let rpc_output: rpc::Inference = worker_client
-        .compute_inference(req)
+    // Wait for the first event, so that a request that fails before it starts
+    // gets an error status rather than an error event
+    let first = events
+        .recv()
         .await
-        .unwrap()
-        .into_inner();
+        .ok_or_else(|| anyhow!("the inference stream ended without a result"))??;
 
-    // Mark the worker as Idle again
-    {
-        let mut manager = state.write().unwrap();
-        manager.set_worker_status(worker.pid, WorkerStatus::Idle);
-    }
+    let events = tokio_stream::once(Ok(first))
+        .chain(ReceiverStream::new(events))
+        .map(|event| Ok::<_, actix_web::Error>(web::Bytes::from(sse_event(event))));
+    Ok(HttpResponse::Ok()
+        .insert_header((CONTENT_TYPE, "text/event-stream"))
+        .insert_header((CACHE_CONTROL, "no-cache"))
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(events))
+}
 
-    // Parse output
-    let output = match ty {
-        torch::InferenceType::ImageClassification { .. } => {
-            let classes: Vec<torch::Class> = rpc_output.classification.unwrap().into();
-            torch::Inference::Classification(classes)
-        }
-        torch::InferenceType::ImageToImage => {
-            torch::Inference::B64Image(rpc_output.image.unwrap().into())
+/// Encode an event of a streamed inference as a Server-Sent Event
+fn sse_event(event: crate::error::Result<torch::InferenceEvent>) -> String {
+    let (name, data) = match event {
+        Ok(torch::InferenceEvent::Progress(stage)) => ("progress", json!({ "stage": stage })),
+        Ok(torch::InferenceEvent::Token(text)) => ("token", json!({ "text": text })),
+        Ok(torch::InferenceEvent::Done((output, _))) => ("inference", json!(output)),
+        Err(e) => {
+            warn!("streaming inference failed: {e}");
+            let body = ErrorBody {
+                code: e.code(),
+                errors: vec![e.to_string()],
+            };
+            ("error", json!(body))
         }
-        _ => unimplemented!(),
     };
+    format!("event: {name}\ndata: {data}\n\n")
+}
 
-    let res = (
-        output,
-        std::time::Duration::from_secs_f32(rpc_output.duration),
-    );
+/// The outcome of a task of a batch: its inference, or why it failed
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum BatchItem {
+    Ok(Inference),
+    Error(ErrorBody),
+}
 
-    debug!("received inference response");
+impl From<crate::error::Result<torch::TimedInference>> for BatchItem {
+    fn from(res: crate::error::Result<torch::TimedInference>) -> BatchItem {
+        match res {
+            Ok((output, _)) => BatchItem::Ok(output),
+            Err(e) => BatchItem::Error(ErrorBody {
+                code: e.code(),
+                errors: vec![e.to_string()],
+            }),
+        }
+    }
+}
 
-    info!("finished serving inference request");
+/// The JSON body of a `/inference/batch` response
+#[derive(Serialize, ToSchema)]
+pub(super) struct BatchResponse {
+    /// The outcome of each task, in order
+    results: Vec<BatchItem>,
+}
+
+/// Run inference on a batch of tasks. A task that is invalid or fails does
+/// not fail the batch: the response holds the outcome of each task, in order.
+/// Registered in `Server::new`, with its own body size limit
+#[utoipa::path(
+    post,
+    path = "/inference/batch",
+    request_body = Vec<TaskRequest>,
+    params(
+        ("X-Request-Timeout" = Option<u64>, Header, description = "Deadline in millis"),
+        ("X-Priority" = Option<Priority>, Header,
+            description = "`interactive` or `batch`, which defaults to `batch`"),
+    ),
+    responses(
+        (status = 200, description = "The outcome of each task", body = BatchResponse),
+        (status = "4XX", description = "The batch is invalid", body = ErrorBody),
+        (status = "5XX", description = "The batch failed", body = ErrorBody),
+    ),
+)]
+#[tracing::instrument(skip_all, fields(request_id = %request_id.0))]
+pub async fn batch_inference(
+    req: web::Json<Vec<serde_json::Value>>,
+    http_req: HttpRequest,
+    request_id: RequestId,
+    state: web::Data<RwLock<Manager>>,
+    config: web::Data<Config>,
+    limits: web::Data<InputLimits>,
+    caller: Caller,
+) -> Result<impl Responder> {
+    let tasks = req.into_inner();
+    info!("got a batch of {} inference requests", tasks.len());
+
+    let timeout = request_timeout(&http_req, &config)?;
+    let scheduling = scheduling(&http_req, None, Priority::Batch)?;
+    admit_batch(tasks.len(), &config, &caller)?;
+    let tasks = tasks
+        .into_iter()
+        .map(|task| caller.allow_task(parse_task(task)))
+        .collect();
+    let manager = state.into_inner();
+    let outputs = serve_batch(
+        &manager,
+        tasks,
+        &request_id.0,
+        &scheduling,
+        timeout,
+        &limits,
+    )
+    .await?;
+    let results = outputs.into_iter().map(BatchItem::from).collect();
+    info!("finished serving batch inference request");
+    Ok(web::Json(BatchResponse { results }))
+}
+
+/// Parse a task of a batch on its own, so that a malformed task does not fail
+/// the others. The tasks of a batch share the priority of the batch, so their
+/// own `priority` is ignored
+fn parse_task(task: serde_json::Value) -> crate::error::Result<torch::InferenceTask> {
+    let task = TaskRequest::deserialize(task)
+        .map_err(|e| Error::InvalidInput(format!("invalid task: {e}")))?;
+    Ok(task.task)
+}
+
+/// Check the size of a batch of `len` tasks, and charge its tasks to the
+/// caller's key. The batch was admitted as a single request, so every task
+/// but the first is charged here
+pub(super) fn admit_batch(
+    len: usize,
+    config: &Config,
+    caller: &Caller,
+) -> crate::error::Result<()> {
+    let max_size = config.get_int("batch.max_size")? as usize;
+    if len == 0 {
+        let msg = "a batch must have at least one task";
+        return Err(Error::InvalidInput(msg.into()));
+    }
+    if len > max_size {
+        let msg = format!("a batch can have at most {max_size} tasks, not {len}");
+        return Err(Error::PayloadTooLarge(msg));
+    }
+    caller.charge(len as u64 - 1)
+}
 
-    //Ok(web::Json(output))
-    Ok(web::Json(res))
+/// Validate the tasks of a batch admitted by `admit_batch`, and dispatch the
+/// valid ones. Tasks that could not be parsed are passed as errors. Returns
+/// the outcome of each task, in order
+pub(super) async fn serve_batch(
+    manager: &Arc<RwLock<Manager>>,
+    tasks: Vec<crate::error::Result<torch::InferenceTask>>,
+    request_id: &str,
+    scheduling: &Scheduling,
+    timeout: Duration,
+    limits: &InputLimits,
+) -> crate::error::Result<Vec<crate::error::Result<torch::TimedInference>>> {
+    // Only dispatch the valid tasks
+    let mut valid = vec![];
+    let invalid = tasks
+        .into_iter()
+        .map(|task| {
+            let task = task.and_then(|task| validate_input(&task, limits).map(|()| task));
+            match task {
+                Ok(task) => {
+                    valid.push(task);
+                    None
+                }
+                Err(e) => Some(Err(e)),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let outputs = Manager::dispatch_batch(manager, valid, request_id, scheduling, timeout).await?;
+
+    let mut outputs = outputs.into_iter();
+    Ok(invalid
+        .into_iter()
+        .map(|item| item.unwrap_or_else(|| outputs.next().expect("every task has a result")))
+        .collect())
+}
+
+/// Validate an inference request, counting failures in the request metrics
+pub(super) fn validate_input(
+    input: &torch::InferenceTask,
+    limits: &InputLimits,
+) -> crate::error::Result<()> {
+    validate(input, limits).inspect_err(|e| {
+        let ty = input.inference_type.name();
+        metrics::REQUESTS.with_label_values(&[ty, e.code()]).inc();
+    })
+}
+
+/// Get the deadline of a request. Clients can set their own deadline (in
+/// millis) with the `X-Request-Timeout` header, up to
+/// `manager.max_request_timeout`. Otherwise, `manager.request_timeout` is used
+pub(super) fn request_timeout(req: &HttpRequest, config: &Config) -> Result<Duration> {
+    let header = req.headers().get(TIMEOUT_HEADER);
+    let timeout = header.map(|h| h.to_str().unwrap_or(""));
+    Ok(parse_timeout(timeout, config)?)
+}
+
+/// Get the deadline of a request from the value of its `X-Request-Timeout`
+/// header, or gRPC metadata, if it has one
+pub(super) fn parse_timeout(
+    value: Option<&str>,
+    config: &Config,
+) -> crate::error::Result<Duration> {
+    let max = config.get_int("manager.max_request_timeout")? as u64;
+    let timeout = match value {
+        Some(value) => match value.parse::<u64>() {
+            Ok(0) => {
+                return Err(Error::InvalidInput(format!(
+                    "{TIMEOUT_HEADER} must be at least 1 milli"
+                )))
+            }
+            Ok(timeout) => timeout.min(max),
+            Err(_) => {
+                return Err(Error::InvalidInput(format!(
+                    "{TIMEOUT_HEADER} must be a number of millis"
+                )))
+            }
+        },
+        None => config.get_int("manager.request_timeout")? as u64,
+    };
+    Ok(Duration::from_millis(timeout))
 }
 
 /// HTTP request to get the status of all workers
@@ -138,7 +431,7 @@ pub async fn worker_status(
 ) -> Result<impl Responder> {
     let status = {
         let manager = state.read().unwrap();
-        manager.all_status().unwrap()
+        manager.all_states()
     };
 
     Ok(web::Json(status))
@@ -149,7 +442,7 @@ pub async fn worker_status(
 pub async fn all_workers(_req: HttpRequest, state: web::Data<RwLock<Manager>>) -> impl Responder {
     let workers = {
         let manager = state.read().unwrap();
-        manager.all_workers().unwrap()
+        manager.all_workers()
     };
 
     web::Json(workers)
@@ -161,8 +454,232 @@ pub async fn worker_info(
     _req: HttpRequest,
     state: web::Data<RwLock<Manager>>,
 ) -> Result<impl Responder> {
-    let manager = state.read().unwrap();
-    let stats = manager.all_stats().await?;
-    let stats_list = stats.into_iter().collect::<Vec<_>>();
-    Ok(web::Json(stats_list))
+    let stats = Manager::all_stats(&state).await;
+    Ok(web::Json(stats))
+}
+
+/// HTTP request to get server, manager and worker metrics, in the Prometheus
+/// text format
+#[get("/metrics")]
+pub async fn prometheus_metrics(
+    _req: HttpRequest,
+    state: web::Data<RwLock<Manager>>,
+) -> Result<impl Responder> {
+    let workers: Vec<WorkerStatus> = {
+        let manager = state.read().unwrap();
+        manager.all_status()?.into_values().collect()
+    };
+
+    // Count workers by status
+    metrics::WORKERS.reset();
+    for status in [
+        WorkerStatus::Working,
+        WorkerStatus::Idle,
+        WorkerStatus::ShuttingDown,
+        WorkerStatus::Error,
+    ] {
+        let count = workers.iter().filter(|&s| *s == status).count();
+        metrics::WORKERS
+            .with_label_values(&[&format!("{status:?}")])
+            .set(count as i64);
+    }
+
+    // Get the number of requests served by each worker
+    metrics::WORKER_REQUESTS.reset();
+    for (pid, info) in Manager::all_stats(&state).await {
+        metrics::WORKER_REQUESTS
+            .with_label_values(&[&pid.to_string()])
+            .set(info.stats.reqs_served as i64);
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(metrics::encode()?))
+}
+
+/// The JSON body of a `/healthz` or `/readyz` response
+#[derive(Serialize, ToSchema)]
+pub(super) struct Health {
+    pub(super) ok: bool,
+
+    /// Why the server is not ready
+    pub(super) errors: Vec<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) workers: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) queue_depth: Option<i64>,
+}
+
+/// Liveness check: succeeds as long as the HTTP server is up
+#[utoipa::path(get, path = "/healthz", responses((status = 200, body = Health)))]
+#[get("/healthz")]
+pub async fn healthz(_req: HttpRequest) -> impl Responder {
+    HttpResponse::Ok().json(Health {
+        ok: true,
+        errors: vec![],
+        workers: None,
+        queue_depth: None,
+    })
+}
+
+/// Readiness check: succeeds when at least `readiness.min_workers` workers
+/// have their model loaded, and fewer than `readiness.max_queue_depth`
+/// requests are queued. Otherwise fails with a `503`, listing the failed checks
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    responses(
+        (status = 200, description = "The server is ready", body = Health),
+        (status = 503, description = "The server is not ready", body = Health),
+    ),
+)]
+#[get("/readyz")]
+pub async fn readyz(
+    _req: HttpRequest,
+    state: web::Data<RwLock<Manager>>,
+    config: web::Data<Config>,
+) -> Result<impl Responder> {
+    let health = readiness(&state, &config).await?;
+    let status = if health.ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(HttpResponse::build(status).json(health))
+}
+
+/// Run the readiness checks of `/readyz`
+pub(super) async fn readiness(
+    manager: &RwLock<Manager>,
+    config: &Config,
+) -> crate::error::Result<Health> {
+    let min_workers = config.get_int("readiness.min_workers")? as usize;
+    let max_queue_depth = config.get_int("readiness.max_queue_depth")?;
+
+    let workers = Manager::healthy_workers(manager).await?;
+    let queue_depth = metrics::QUEUE_DEPTH.get();
+    let errors = readiness_errors(workers, min_workers, queue_depth, max_queue_depth);
+
+    Ok(Health {
+        ok: errors.is_empty(),
+        errors,
+        workers: Some(workers),
+        queue_depth: Some(queue_depth),
+    })
+}
+
+/// The readiness checks that fail with `workers` healthy workers and
+/// `queue_depth` queued requests
+fn readiness_errors(
+    workers: usize,
+    min_workers: usize,
+    queue_depth: i64,
+    max_queue_depth: i64,
+) -> Vec<String> {
+    let mut errors = vec![];
+    if workers == 0 {
+        errors.push("no worker has loaded the model".to_string());
+    } else if workers < min_workers {
+        errors.push(format!("ready workers: {workers}, required: {min_workers}"));
+    }
+    if max_queue_depth > 0 && queue_depth >= max_queue_depth {
+        errors.push(format!(
+            "queue is saturated: {queue_depth} requests, the limit is {max_queue_depth}"
+        ));
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use std::time::Duration;
+
+    #[test]
+    fn test_sse_event() {
+        let progress = sse_event(Ok(torch::InferenceEvent::Progress("decode".into())));
+        assert_eq!(
+            progress,
+            "event: progress\ndata: {\"stage\":\"decode\"}\n\n"
+        );
+
+        let token = sse_event(Ok(torch::InferenceEvent::Token("a\nb".into())));
+        assert_eq!(token, "event: token\ndata: {\"text\":\"a\\nb\"}\n\n");
+
+        let output = torch::Inference::Text("ab".into());
+        let done = sse_event(Ok(torch::InferenceEvent::Done((output, Duration::ZERO))));
+        assert_eq!(done, "event: inference\ndata: {\"Text\":\"ab\"}\n\n");
+
+        let error = sse_event(Err(Error::Busy));
+        assert_eq!(
+            error,
+            "event: error\ndata: {\"code\":\"workers_busy\",\"errors\":[\"all workers are busy\"]}\n\n"
+        );
+    }
+
+    #[test]
+    fn test_inference_response() {
+        let body = InferenceResponse {
+            request_id: "abc".into(),
+            model: "resnet".into(),
+            model_version: "0123456789ab".into(),
+            worker: 42,
+            timings: Timings {
+                queue: Duration::from_micros(1500),
+                rpc: Duration::from_millis(20),
+                inference: Duration::from_millis(12),
+            },
+            result: torch::Inference::Text("ab".into()),
+        };
+        assert_eq!(
+            serde_json::to_value(body).unwrap(),
+            json!({
+                "request_id": "abc",
+                "model": "resnet",
+                "model_version": "0123456789ab",
+                "worker": 42,
+                "timings": {"queue_ms": 1.5, "rpc_ms": 20.0, "inference_ms": 12.0},
+                "result": {"Text": "ab"},
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_timeout() {
+        let config = Config::builder()
+            .set_default("manager.request_timeout", 1000)
+            .unwrap()
+            .set_default("manager.max_request_timeout", 5000)
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let timeout = |value| parse_timeout(value, &config);
+        assert_eq!(timeout(None).unwrap(), Duration::from_millis(1000));
+        assert_eq!(timeout(Some("200")).unwrap(), Duration::from_millis(200));
+        assert_eq!(timeout(Some("9000")).unwrap(), Duration::from_millis(5000));
+        assert!(matches!(timeout(Some("soon")), Err(Error::InvalidInput(_))));
+        assert!(matches!(timeout(Some("0")), Err(Error::InvalidInput(_))));
+    }
+
+    #[test]
+    fn test_readiness_errors() {
+        assert!(readiness_errors(2, 2, 0, 100).is_empty());
+        assert_eq!(
+            readiness_errors(0, 2, 0, 100),
+            vec!["no worker has loaded the model"]
+        );
+        assert_eq!(
+            readiness_errors(1, 2, 0, 100),
+            vec!["ready workers: 1, required: 2"]
+        );
+        assert_eq!(
+            readiness_errors(2, 2, 100, 100),
+            vec!["queue is saturated: 100 requests, the limit is 100"]
+        );
+        assert!(readiness_errors(2, 2, 1000, 0).is_empty());
+    }
 }
@@ -0,0 +1,108 @@
+//! Rate limiting of the inference API. Requests are checked against the
+//! bucket of their client before anything else is done with them, so that a
+//! client sending too many requests is refused without taking up a worker
+
+use super::WebError;
+use crate::error::Result;
+use crate::ratelimit::RateLimiter;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::{web, HttpRequest};
+use std::future::Future;
+
+/// Middleware that refuses requests over the rate limits of `[rate_limit]`
+/// with a `429`
+pub fn limit<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = std::result::Result<ServiceResponse<EitherBody<B>>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let res = match check(&req) {
+        Ok(()) => Ok(srv.call(req)),
+        Err(e) => Err(req.error_response(WebError::from(e))),
+    };
+    async move {
+        match res {
+            Ok(res) => res.await.map(ServiceResponse::map_into_left_body),
+            Err(res) => Ok(res.map_into_right_body()),
+        }
+    }
+}
+
+fn check(req: &ServiceRequest) -> Result<()> {
+    match req.app_data::<web::Data<RateLimiter>>() {
+        Some(limiter) => limiter.check(&client(req.request())),
+        None => Ok(()),
+    }
+}
+
+/// Identify the client of a request by the header named by
+/// `rate_limit.client_header`, if it came through a trusted proxy, or by its
+/// IP address
+pub fn client(req: &HttpRequest) -> String {
+    let addr = req.peer_addr().map(|addr| addr.ip());
+    let Some(limiter) = req.app_data::<web::Data<RateLimiter>>() else {
+        return addr.map(|addr| addr.to_string()).unwrap_or_default();
+    };
+    let header = limiter.config.client_header.as_str();
+    let value = (!header.is_empty())
+        .then(|| req.headers().get(header))
+        .flatten();
+    limiter
+        .config
+        .client_id(value.and_then(|value| value.to_str().ok()), addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ratelimit::RateLimitConfig;
+    use actix_web::http::header::RETRY_AFTER;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App, HttpResponse};
+
+    async fn ok() -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    #[actix_web::test]
+    async fn test_limit() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            client_rate: 0.5,
+            client_burst: 1.0,
+            global_rate: 0.0,
+            global_burst: 0.0,
+            client_header: "X-Real-IP".into(),
+            trusted_proxies: vec!["10.0.0.100".parse().unwrap()],
+            max_clients: 100,
+        });
+        let app = test::init_service(
+            App::new().app_data(web::Data::new(limiter)).service(
+                web::scope("")
+                    .wrap_fn(limit)
+                    .route("/inference", web::post().to(ok)),
+            ),
+        )
+        .await;
+
+        let request = |client: &str| {
+            test::TestRequest::post()
+                .uri("/inference")
+                .insert_header(("X-Real-IP", client))
+                .peer_addr("10.0.0.100:4000".parse().unwrap())
+                .to_request()
+        };
+        let res = test::call_service(&app, request("10.0.0.1")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = test::call_service(&app, request("10.0.0.1")).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get(RETRY_AFTER).unwrap(), "2");
+
+        // Other clients are not affected
+        let res = test::call_service(&app, request("10.0.0.2")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
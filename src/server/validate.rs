@@ -0,0 +1,210 @@
+//! Validation of inference requests. Requests are checked here, before they
+//! are dispatched, so that malformed or hostile input never reaches a worker
+
+use crate::error::{Error, Result};
+use crate::torch::{B64Image, InferenceTask, InferenceType, InputData};
+use base64::{engine::general_purpose, Engine as _};
+use config::Config;
+use image::io::Reader;
+use std::io::Cursor;
+
+/// Limits on the input of an inference request
+#[derive(Debug, Clone)]
+pub struct InputLimits {
+    /// Maximum number of pixels in an input image. Checked before the image
+    /// is decoded, to reject decompression bombs
+    pub max_pixels: u64,
+
+    /// Maximum number of classes a classification can return
+    pub max_top_n: u16,
+
+    /// Maximum length of input text, in bytes
+    pub max_text_length: usize,
+}
+
+impl InputLimits {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        Ok(InputLimits {
+            max_pixels: config.get_int("validation.max_pixels")? as u64,
+            max_top_n: config.get_int("validation.max_top_n")? as u16,
+            max_text_length: config.get_int("validation.max_text_length")? as usize,
+        })
+    }
+}
+
+/// Check that an inference request is well-formed and within limits
+pub fn validate(task: &InferenceTask, limits: &InputLimits) -> Result<()> {
+    match (&task.inference_type, &task.data) {
+        (InferenceType::ImageClassification { top_n }, InputData::B64Image(image)) => {
+            validate_top_n(*top_n, limits)?;
+            validate_image(image, limits)
+        }
+        (InferenceType::ImageClassification { top_n }, InputData::Image(image)) => {
+            validate_top_n(*top_n, limits)?;
+            validate_image_bytes(&image.image, limits)
+        }
+        (InferenceType::ImageToImage, InputData::B64Image(image)) => validate_image(image, limits),
+        (InferenceType::ImageToImage, InputData::Image(image)) => {
+            validate_image_bytes(&image.image, limits)
+        }
+        (InferenceType::TextToText, InputData::Text(text)) => {
+            if text.len() > limits.max_text_length {
+                return Err(Error::PayloadTooLarge(format!(
+                    "text is longer than {} bytes",
+                    limits.max_text_length
+                )));
+            }
+            Ok(())
+        }
+        (ty, _) => Err(Error::InvalidInput(format!(
+            "invalid input type for {ty:?} inference"
+        ))),
+    }
+}
+
+/// Check that an inference type can be computed on a stream of images, such as
+/// the frames sent over a WebSocket
+pub fn validate_image_type(ty: &InferenceType, limits: &InputLimits) -> Result<()> {
+    match ty {
+        InferenceType::ImageClassification { top_n } => validate_top_n(*top_n, limits),
+        InferenceType::ImageToImage => Ok(()),
+        ty => Err(Error::InvalidInput(format!(
+            "{ty:?} inference does not take images"
+        ))),
+    }
+}
+
+fn validate_top_n(top_n: u16, limits: &InputLimits) -> Result<()> {
+    if top_n == 0 || top_n > limits.max_top_n {
+        return Err(Error::InvalidInput(format!(
+            "top_n must be between 1 and {}",
+            limits.max_top_n
+        )));
+    }
+    Ok(())
+}
+
+/// Check that an image is valid base 64, in a format that can be decoded, and
+/// not too large. Only the image header is read
+fn validate_image(image: &B64Image, limits: &InputLimits) -> Result<()> {
+    let bytes = general_purpose::STANDARD.decode(&image.image)?;
+    validate_image_bytes(&bytes, limits)
+}
+
+/// Check that an encoded image is in a format that can be decoded, and not too
+/// large
+pub fn validate_image_bytes(bytes: &[u8], limits: &InputLimits) -> Result<()> {
+    let reader = Reader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|e| Error::UnsupportedMediaType(e.to_string()))?;
+    if reader.format().is_none() {
+        return Err(Error::UnsupportedMediaType("unknown image format".into()));
+    }
+    let (width, height) = reader
+        .into_dimensions()
+        .map_err(|e| Error::UnsupportedMediaType(e.to_string()))?;
+
+    if width as u64 * height as u64 > limits.max_pixels {
+        return Err(Error::PayloadTooLarge(format!(
+            "image is {width}x{height}, but at most {} pixels are allowed",
+            limits.max_pixels
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test;
+
+    const LIMITS: InputLimits = InputLimits {
+        max_pixels: 4096 * 4096,
+        max_top_n: 1000,
+        max_text_length: 16,
+    };
+
+    fn classification(data: InputData, top_n: u16) -> InferenceTask {
+        InferenceTask {
+            data,
+            inference_type: InferenceType::ImageClassification { top_n },
+        }
+    }
+
+    fn b64(image: &str) -> InputData {
+        InputData::B64Image(B64Image {
+            image: image.into(),
+            height: None,
+            width: None,
+        })
+    }
+
+    #[test]
+    fn test_valid_image() {
+        let task = classification(test::get_test_image(), 5);
+        assert!(validate(&task, &LIMITS).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_base64() {
+        let task = classification(b64("not base 64!"), 5);
+        assert!(matches!(
+            validate(&task, &LIMITS),
+            Err(Error::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn test_undecodable_image() {
+        let task = classification(b64(&general_purpose::STANDARD.encode("hello")), 5);
+        assert!(matches!(
+            validate(&task, &LIMITS),
+            Err(Error::UnsupportedMediaType(_))
+        ));
+    }
+
+    #[test]
+    fn test_too_many_pixels() {
+        let limits = InputLimits {
+            max_pixels: 100,
+            ..LIMITS
+        };
+        let task = classification(test::get_test_image(), 5);
+        assert!(matches!(
+            validate(&task, &limits),
+            Err(Error::PayloadTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn test_top_n_bounds() {
+        for top_n in [0, 1001] {
+            let task = classification(test::get_test_image(), top_n);
+            assert!(matches!(
+                validate(&task, &LIMITS),
+                Err(Error::InvalidInput(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_wrong_input_type() {
+        let task = classification(InputData::Text("hello".into()), 5);
+        assert!(matches!(
+            validate(&task, &LIMITS),
+            Err(Error::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn test_image_type() {
+        let ty = InferenceType::ImageClassification { top_n: 5 };
+        assert!(validate_image_type(&ty, &LIMITS).is_ok());
+        assert!(validate_image_type(&InferenceType::ImageToImage, &LIMITS).is_ok());
+        assert!(matches!(
+            validate_image_type(&InferenceType::TextToText, &LIMITS),
+            Err(Error::InvalidInput(_))
+        ));
+    }
+}
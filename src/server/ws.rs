@@ -0,0 +1,409 @@
+//! A WebSocket route for clients that send a continuous stream of images, such
+//! as the frames of a video. A client keeps a single connection open, sets the
+//! type of inference with a JSON control message, and then sends each frame
+//! as a binary message. Results are sent back as JSON messages tagged with the
+//! ID of their frame, in the order they finish.
+//!
+//! When workers fall behind, stale frames are dropped rather than queued, so
+//! that results keep up with the most recent frame
+
+use super::auth::Caller;
+use super::priority::scheduling;
+use super::routes::request_timeout;
+use super::throttle;
+use super::validate::{validate_image_bytes, validate_image_type, InputLimits};
+use super::{ErrorBody, RequestId, WebError};
+use crate::error::{Error, Result};
+use crate::manager::dispatch::Dispatched;
+use crate::manager::scheduler::{Priority, Scheduling};
+use crate::manager::Manager;
+use crate::metrics;
+use crate::ratelimit::RateLimiter;
+use crate::torch::{Image, Inference, InferenceTask, InferenceType, InputData};
+
+use actix_web::web::Bytes;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use actix_ws::{AggregatedMessage, CloseCode, CloseReason, Session};
+use config::Config;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+use tracing::*;
+
+/// Size of the frame ID at the start of every binary message
+const FRAME_ID_SIZE: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct WsConfig {
+    /// Maximum size of a message, in bytes
+    pub max_message_size: usize,
+
+    /// Maximum number of frames of a connection computed at once
+    pub max_in_flight: usize,
+
+    /// Frames that wait longer than this for a worker are dropped
+    pub max_frame_age: Duration,
+}
+
+impl WsConfig {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        Ok(WsConfig {
+            max_message_size: config.get_int("websocket.max_message_size")? as usize,
+            max_in_flight: config.get_int("websocket.max_in_flight")?.max(1) as usize,
+            max_frame_age: Duration::from_millis(config.get_int("websocket.max_frame_age")? as u64),
+        })
+    }
+}
+
+/// A JSON control message sent by the client
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Control {
+    /// Set the type of inference computed on the frames that follow
+    Configure { inference_type: InferenceType },
+}
+
+/// A JSON message sent back to the client
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Reply {
+    /// The inference of a frame
+    Result { frame: u64, result: Inference },
+
+    /// A frame or control message failed. `frame` is missing for failed
+    /// control messages
+    Error {
+        frame: Option<u64>,
+        #[serde(flatten)]
+        error: ErrorBody,
+    },
+
+    /// A frame was dropped without being computed
+    Dropped { frame: u64 },
+}
+
+impl Reply {
+    fn error(frame: Option<u64>, err: &Error) -> Reply {
+        Reply::Error {
+            frame,
+            error: ErrorBody {
+                code: err.code(),
+                errors: vec![err.to_string()],
+            },
+        }
+    }
+}
+
+/// A frame waiting for a worker
+struct Frame {
+    id: u64,
+    image: Bytes,
+    received: Instant,
+}
+
+/// Open a WebSocket for inference on a stream of frames
+#[get("/ws/inference")]
+#[tracing::instrument(skip_all, fields(request_id = %request_id.0))]
+pub async fn inference(
+    http_req: HttpRequest,
+    body: web::Payload,
+    request_id: RequestId,
+    state: web::Data<RwLock<Manager>>,
+    config: web::Data<Config>,
+    limits: web::Data<InputLimits>,
+    ws_config: web::Data<WsConfig>,
+) -> std::result::Result<HttpResponse, actix_web::Error> {
+    let timeout = request_timeout(&http_req, &config)?;
+    let scheduling = scheduling(&http_req, None, Priority::Interactive).map_err(WebError::from)?;
+    let (res, session, messages) = actix_ws::handle(&http_req, body)?;
+    let messages = messages
+        .max_frame_size(ws_config.max_message_size)
+        .aggregate_continuations()
+        .max_continuation_size(ws_config.max_message_size);
+
+    info!("opened inference WebSocket");
+    let conn = Connection {
+        manager: state.into_inner(),
+        limits: limits.into_inner(),
+        config: ws_config.into_inner(),
+        request_id: request_id.0,
+        timeout,
+        scheduling,
+        caller: Caller::of(&http_req),
+        limiter: http_req
+            .app_data::<web::Data<RateLimiter>>()
+            .map(|limiter| limiter.clone().into_inner()),
+        client: throttle::client(&http_req),
+        session,
+        inference_type: None,
+        pending: None,
+        in_flight: JoinSet::new(),
+    };
+    actix_web::rt::spawn(conn.run(messages).in_current_span());
+    Ok(res)
+}
+
+/// The state of an open WebSocket
+struct Connection {
+    manager: Arc<RwLock<Manager>>,
+    limits: Arc<InputLimits>,
+    config: Arc<WsConfig>,
+    request_id: String,
+    timeout: Duration,
+
+    /// How the frames of the connection are queued for a worker
+    scheduling: Scheduling,
+
+    caller: Caller,
+
+    /// The rate limiter, and the client it identifies the connection as
+    limiter: Option<Arc<RateLimiter>>,
+    client: String,
+
+    session: Session,
+
+    /// The type of inference computed on frames, once the client has set it
+    inference_type: Option<InferenceType>,
+
+    /// The most recent frame that has not been sent to a worker yet
+    pending: Option<Frame>,
+
+    /// Frames being computed
+    in_flight: JoinSet<(u64, Result<Dispatched>)>,
+}
+
+impl Connection {
+    /// Serve the connection until either side closes it. Frames still being
+    /// computed are abandoned when it closes
+    async fn run(mut self, mut messages: actix_ws::AggregatedMessageStream) {
+        let reason = loop {
+            let sent = tokio::select! {
+                msg = messages.recv() => match msg {
+                    Some(Ok(msg)) => match self.handle(msg).await {
+                        Ok(true) => Ok(()),
+                        Ok(false) => break None,
+                        Err(closed) => Err(closed),
+                    },
+                    Some(Err(e)) => {
+                        warn!("WebSocket protocol error: {e}");
+                        break Some(CloseReason::from((CloseCode::Protocol, e.to_string())));
+                    }
+                    None => break None,
+                },
+                Some(done) = self.in_flight.join_next(), if !self.in_flight.is_empty() => {
+                    match done {
+                        Ok((frame, res)) => self.finish(frame, res).await,
+                        Err(e) => {
+                            error!("WebSocket frame task failed: {e}");
+                            Ok(())
+                        }
+                    }
+                }
+            };
+            if sent.is_err() || self.start_pending().await.is_err() {
+                // The client is gone
+                break None;
+            }
+        };
+
+        info!("closing inference WebSocket");
+        let _ = self.session.close(reason).await;
+    }
+
+    /// Handle a message from the client. Returns false once the client closes
+    /// the connection
+    async fn handle(
+        &mut self,
+        msg: AggregatedMessage,
+    ) -> std::result::Result<bool, actix_ws::Closed> {
+        match msg {
+            AggregatedMessage::Text(text) => self.control(&text).await?,
+            AggregatedMessage::Binary(bytes) => self.frame(bytes).await?,
+            AggregatedMessage::Ping(bytes) => self.session.pong(&bytes).await?,
+            AggregatedMessage::Pong(_) => {}
+            AggregatedMessage::Close(_) => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    async fn control(&mut self, text: &str) -> std::result::Result<(), actix_ws::Closed> {
+        let res = serde_json::from_str::<Control>(text)
+            .map_err(|e| Error::InvalidInput(format!("invalid control message: {e}")))
+            .and_then(|control| {
+                debug!("got control message: {:?}", control);
+                match control {
+                    Control::Configure { inference_type } => {
+                        validate_image_type(&inference_type, &self.limits)?;
+                        self.caller.allow_inference(&inference_type)?;
+                        self.inference_type = Some(inference_type);
+                        Ok(())
+                    }
+                }
+            });
+        match res {
+            Ok(()) => Ok(()),
+            Err(e) => self.send(&Reply::error(None, &e)).await,
+        }
+    }
+
+    /// Queue a frame, dropping the frame it replaces
+    async fn frame(&mut self, bytes: Bytes) -> std::result::Result<(), actix_ws::Closed> {
+        if bytes.len() < FRAME_ID_SIZE {
+            let err = Error::InvalidInput(format!(
+                "frames must start with a {FRAME_ID_SIZE}-byte frame ID"
+            ));
+            return self.send(&Reply::error(None, &err)).await;
+        }
+        let mut id = [0; FRAME_ID_SIZE];
+        id.copy_from_slice(&bytes[..FRAME_ID_SIZE]);
+        let frame = Frame {
+            id: u64::from_be_bytes(id),
+            image: bytes.slice(FRAME_ID_SIZE..),
+            received: Instant::now(),
+        };
+
+        if let Some(stale) = self.pending.replace(frame) {
+            self.drop_frame(stale.id).await?;
+        }
+        Ok(())
+    }
+
+    /// Send the pending frame to a worker, if fewer than
+    /// `websocket.max_in_flight` frames are being computed
+    async fn start_pending(&mut self) -> std::result::Result<(), actix_ws::Closed> {
+        if self.in_flight.len() >= self.config.max_in_flight {
+            return Ok(());
+        }
+        let Some(frame) = self.pending.take() else {
+            return Ok(());
+        };
+        if frame.received.elapsed() > self.config.max_frame_age {
+            return self.drop_frame(frame.id).await;
+        }
+
+        let task = self
+            .task(&frame)
+            .and_then(|task| self.charge().map(|()| task));
+        let task = task.inspect_err(|e| {
+            let ty = self
+                .inference_type
+                .as_ref()
+                .map_or("unknown", |ty| ty.name());
+            metrics::REQUESTS.with_label_values(&[ty, e.code()]).inc();
+        });
+        let task = match task {
+            Ok(task) => task,
+            Err(e) => return self.send(&Reply::error(Some(frame.id), &e)).await,
+        };
+
+        // The frame is dropped if it gets no worker before it is too old
+        let manager = self.manager.clone();
+        let request_id = format!("{}-{}", self.request_id, frame.id);
+        let max_wait = self
+            .config
+            .max_frame_age
+            .saturating_sub(frame.received.elapsed());
+        let scheduling = Scheduling {
+            max_wait: Some(max_wait),
+            ..self.scheduling.clone()
+        };
+        let timeout = self.timeout;
+        self.in_flight.spawn(
+            async move {
+                let res =
+                    Manager::dispatch(&manager, task, &request_id, &scheduling, timeout).await;
+                (frame.id, res)
+            }
+            .in_current_span(),
+        );
+        Ok(())
+    }
+
+    /// Validate a frame, and turn it into an inference task
+    fn task(&self, frame: &Frame) -> Result<InferenceTask> {
+        let inference_type = self.inference_type.clone().ok_or_else(|| {
+            Error::InvalidInput("send a configure message before the first frame".into())
+        })?;
+        validate_image_bytes(&frame.image, &self.limits)?;
+
+        Ok(InferenceTask {
+            data: InputData::Image(Image {
+                image: frame.image.to_vec(),
+                height: None,
+                width: None,
+            }),
+            inference_type,
+        })
+    }
+
+    /// Count a frame against the rate limit of the client and the quota of
+    /// its key, as the connection was only admitted once
+    fn charge(&self) -> Result<()> {
+        if let Some(limiter) = &self.limiter {
+            limiter.check(&self.client)?;
+        }
+        self.caller.charge(1)
+    }
+
+    /// Send the outcome of a frame to the client. Frames that found every
+    /// worker busy are dropped
+    async fn finish(
+        &mut self,
+        frame: u64,
+        res: Result<Dispatched>,
+    ) -> std::result::Result<(), actix_ws::Closed> {
+        match res {
+            Ok(dispatched) => {
+                let (result, _) = dispatched.inference;
+                self.send(&Reply::Result { frame, result }).await
+            }
+            Err(Error::Busy) => self.drop_frame(frame).await,
+            Err(e) => {
+                warn!("inference on frame {frame} failed: {e}");
+                self.send(&Reply::error(Some(frame), &e)).await
+            }
+        }
+    }
+
+    async fn drop_frame(&mut self, frame: u64) -> std::result::Result<(), actix_ws::Closed> {
+        debug!("dropping frame {frame}");
+        metrics::DROPPED_FRAMES.inc();
+        self.send(&Reply::Dropped { frame }).await
+    }
+
+    async fn send(&mut self, reply: &Reply) -> std::result::Result<(), actix_ws::Closed> {
+        let text = serde_json::to_string(reply).expect("replies are serializable");
+        self.session.text(text).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_control() {
+        let msg =
+            r#"{"type": "configure", "inference_type": {"ImageClassification": {"top_n": 3}}}"#;
+        let Control::Configure { inference_type } = serde_json::from_str(msg).unwrap();
+        assert!(matches!(
+            inference_type,
+            InferenceType::ImageClassification { top_n: 3 }
+        ));
+
+        assert!(serde_json::from_str::<Control>(r#"{"type": "unknown"}"#).is_err());
+    }
+
+    #[test]
+    fn test_replies() {
+        let dropped = serde_json::to_string(&Reply::Dropped { frame: 7 }).unwrap();
+        assert_eq!(dropped, r#"{"type":"dropped","frame":7}"#);
+
+        let error = serde_json::to_string(&Reply::error(Some(8), &Error::Busy)).unwrap();
+        assert_eq!(
+            error,
+            r#"{"type":"error","frame":8,"code":"workers_busy","errors":["all workers are busy"]}"#
+        );
+    }
+}
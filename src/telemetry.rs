@@ -0,0 +1,225 @@
+//! Logging and distributed tracing. Every inference request has an ID, which
+//! is carried from the HTTP server to the worker in gRPC metadata along with
+//! the trace context, so that the spans and log lines of both processes can be
+//! correlated. Spans can be exported to an OpenTelemetry collector over OTLP,
+//! or written to a file as JSON lines
+
+use crate::util;
+use anyhow::{anyhow, bail};
+use config::Config;
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use std::fs::{self, OpenOptions};
+use std::sync::Mutex;
+use tonic::metadata::{KeyRef, MetadataKey, MetadataMap, MetadataValue};
+use tracing::{Span, Subscriber};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::filter::{filter_fn, EnvFilter};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Header carrying the ID of a request. Clients can set their own ID, and
+/// every response carries the ID its request was served under
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// gRPC metadata key carrying the ID of a request from the manager to a worker
+pub const REQUEST_ID_METADATA: &str = "x-request-id";
+
+/// Maximum length of a request ID set by a client
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Set up logging to stdout, filtered by `manager.logging` and formatted as
+/// `logging.format`, and the trace exporter chosen by `tracing.exporter`.
+/// `service` names the process in exported traces
+pub fn init(config: &Config, service: &str) -> anyhow::Result<()> {
+    let filter = EnvFilter::try_new(config.get_string("manager.logging")?)?;
+    let (text_logs, json_logs) = match config.get_string("logging.format")?.as_str() {
+        "text" => (Some(tracing_subscriber::fmt::layer()), None),
+        "json" => (None, Some(tracing_subscriber::fmt::layer().json())),
+        other => bail!("unknown log format {other:?}"),
+    };
+
+    // The trace context is sent along with every RPC whatever the exporter,
+    // so that the spans of a worker belong to the trace of their request
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let exporter = config.get_string("tracing.exporter")?;
+    let (otel, json) = match exporter.as_str() {
+        "none" => (None, None),
+        "otlp" => (Some(otlp_layer(config, service)?), None),
+        "json" => (
+            Some(context_layer(service)),
+            Some(json_layer(config, service)?),
+        ),
+        other => bail!("unknown trace exporter {other:?}"),
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(text_logs)
+        .with(json_logs)
+        .with(otel)
+        .with(json)
+        .try_init()?;
+    Ok(())
+}
+
+/// Flush any spans that have not been exported yet
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+/// Export spans over OTLP to the collector at `tracing.otlp_endpoint`
+fn otlp_layer<S>(
+    config: &Config,
+    service: &str,
+) -> anyhow::Result<OpenTelemetryLayer<S, trace::Tracer>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(config.get_string("tracing.otlp_endpoint")?),
+        )
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                service.to_string(),
+            )])),
+        )
+        .install_batch(runtime::Tokio)?;
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Track the trace context of spans without exporting them, so that it is
+/// propagated to workers when spans are only written to files
+fn context_layer<S>(service: &str) -> OpenTelemetryLayer<S, trace::Tracer>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let tracer = trace::TracerProvider::default().tracer(service.to_string());
+    tracing_opentelemetry::layer().with_tracer(tracer)
+}
+
+/// Write every closed span, with its timings and the spans it is nested in,
+/// to `<tracing.json_dir>/<service>_<pid>.json`
+fn json_layer<S>(config: &Config, service: &str) -> anyhow::Result<impl Layer<S>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let dir = config.get_string("tracing.json_dir")?;
+    fs::create_dir_all(&dir)?;
+    let path = format!("{dir}/{service}_{}.json", std::process::id());
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|e| anyhow!("failed to open trace file {path}: {e}"))?;
+
+    Ok(tracing_subscriber::fmt::layer()
+        .json()
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(Mutex::new(file))
+        .with_filter(filter_fn(|meta| meta.is_span())))
+}
+
+/// Get the ID of a request from the ID sent by the client, if it is usable,
+/// or generate a new one
+pub fn request_id(client_id: Option<&str>) -> String {
+    match client_id {
+        Some(id) if !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN => id.to_string(),
+        _ => util::random_id(),
+    }
+}
+
+/// Attach the ID of a request, and the context of the current span, to an
+/// outgoing RPC
+pub fn inject(request_id: &str, metadata: &mut MetadataMap) {
+    if let Ok(id) = MetadataValue::try_from(request_id) {
+        metadata.insert(REQUEST_ID_METADATA, id);
+    }
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&Span::current().context(), &mut MetadataInjector(metadata))
+    });
+}
+
+/// Get the ID of the request an incoming RPC is serving
+pub fn rpc_request_id(metadata: &MetadataMap) -> &str {
+    metadata
+        .get(REQUEST_ID_METADATA)
+        .and_then(|id| id.to_str().ok())
+        .unwrap_or_default()
+}
+
+/// Make `span` a child of the span that sent an incoming RPC, so that it is
+/// exported as part of the same trace
+pub fn set_parent(span: &Span, metadata: &MetadataMap) {
+    let cx = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&MetadataExtractor(metadata))
+    });
+    span.set_parent(cx);
+}
+
+struct MetadataInjector<'a>(&'a mut MetadataMap);
+
+impl Injector for MetadataInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(key), Ok(value)) = (
+            MetadataKey::from_bytes(key.as_bytes()),
+            MetadataValue::try_from(value),
+        ) {
+            self.0.insert(key, value);
+        }
+    }
+}
+
+struct MetadataExtractor<'a>(&'a MetadataMap);
+
+impl Extractor for MetadataExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0
+            .keys()
+            .map(|key| match key {
+                KeyRef::Ascii(key) => key.as_str(),
+                KeyRef::Binary(key) => key.as_str(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_id() {
+        assert_eq!(request_id(Some("abc-123")), "abc-123");
+
+        let generated = request_id(None);
+        assert_eq!(generated.len(), 32);
+        assert_ne!(generated, request_id(None));
+        assert_eq!(request_id(Some("")).len(), 32);
+        assert_eq!(request_id(Some(&"a".repeat(200))).len(), 32);
+    }
+
+    #[test]
+    fn test_request_id_metadata() {
+        let mut metadata = MetadataMap::new();
+        assert_eq!(rpc_request_id(&metadata), "");
+
+        inject("abc-123", &mut metadata);
+        assert_eq!(rpc_request_id(&metadata), "abc-123");
+    }
+}
@@ -1,7 +1,8 @@
 //! Code for loading and running (trained) PyTorch models
 
+use crate::error::{Error, Result};
 use crate::rpc;
-use anyhow::{anyhow, Result};
+use anyhow::anyhow;
 use base64::{
     alphabet,
     engine::{self, general_purpose},
@@ -15,12 +16,19 @@ use serde::{Deserialize, Serialize};
 use std::{fmt::Debug, io::Cursor};
 use tch::vision::imagenet;
 use tch::{IValue, Kind};
+use tracing::{info_span, warn};
+use utoipa::openapi::Ref;
+use utoipa::ToSchema;
 
 use image::GenericImageView;
-use tch::{nn, no_grad, vision, Device, Tensor};
+use tch::{nn, no_grad, vision, Tensor};
 
 pub type TimedInference = (Inference, time::Duration);
 
+/// Receives the events of an inference as it runs. Returning an error stops
+/// the inference, when nobody is listening anymore
+pub type Emit<'a> = &'a mut dyn FnMut(InferenceEvent) -> Result<()>;
+
 /// An in-memory representation of an image (not base 64). Can be the input or output of a model
 #[derive(Debug, Serialize, Clone, Deserialize)]
 pub struct Image {
@@ -30,7 +38,7 @@ pub struct Image {
 }
 
 /// A base 64 image
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
 pub struct B64Image {
     pub image: String,
     pub height: Option<u32>,
@@ -47,14 +55,15 @@ impl Debug for B64Image {
     }
 }
 
-impl From<B64Image> for Image {
-    fn from(b64_img: B64Image) -> Image {
-        let image = base64::decode(&b64_img.image).unwrap_or_else(|_| Vec::new());
-        Image {
-            image,
+impl TryFrom<B64Image> for Image {
+    type Error = Error;
+
+    fn try_from(b64_img: B64Image) -> Result<Image> {
+        Ok(Image {
+            image: base64::decode(&b64_img.image)?,
             height: b64_img.height,
             width: b64_img.width,
-        }
+        })
     }
 }
 
@@ -86,14 +95,14 @@ pub enum InferenceRequest {
 }
 
 /// A class prediction outputted by a classifier model
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 pub struct Class {
     probability: Option<f64>,
     label: Option<String>,
 }
 
 /// The output of a model's inference
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 pub enum Inference {
     Text(String),
     Classification(Vec<Class>),
@@ -101,8 +110,21 @@ pub enum Inference {
     B64Image(B64Image),
 }
 
+/// An event of an inference that is streamed as it runs
+#[derive(Debug, Clone)]
+pub enum InferenceEvent {
+    /// A stage of the inference has finished
+    Progress(String),
+
+    /// A piece of generated text
+    Token(String),
+
+    /// The result of the inference, which ends the stream
+    Done(TimedInference),
+}
+
 /// The type of inference to compute
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
 pub enum InferenceType {
     /// `InputData::Image` to `Inference::Classification`
     ImageClassification { top_n: u16 },
@@ -115,87 +137,207 @@ pub enum InferenceType {
     TextToText,
 }
 
-/// Input data that inference can be computed on
-#[derive(Deserialize, Debug, Serialize)]
+impl InferenceType {
+    /// The name of the inference type, without its parameters
+    pub fn name(&self) -> &'static str {
+        match self {
+            InferenceType::ImageClassification { .. } => "ImageClassification",
+            InferenceType::ImageToImage => "ImageToImage",
+            InferenceType::TextToText => "TextToText",
+        }
+    }
+}
+
+/// Input data that inference can be computed on. It is sent and received as
+/// `WireInputData`
+#[derive(Deserialize, Debug, Serialize, Clone)]
+#[serde(from = "WireInputData", into = "WireInputData")]
 pub enum InputData {
     Text(String),
-    //Image(Image),
+
+    /// An image that is not base 64 encoded, such as a WebSocket frame. Only
+    /// used internally, so that it is not encoded on its way to the worker
+    Image(Image),
     B64Image(B64Image),
 }
 
+/// The input data of a request body: the variants of `InputData` that can be
+/// sent over the wire. An `InputData::Image` is sent as a base 64 image
+#[derive(Deserialize, Serialize, ToSchema)]
+#[schema(as = InputData)]
+pub enum WireInputData {
+    Text(String),
+    B64Image(B64Image),
+}
+
+impl From<WireInputData> for InputData {
+    fn from(data: WireInputData) -> InputData {
+        match data {
+            WireInputData::Text(text) => InputData::Text(text),
+            WireInputData::B64Image(image) => InputData::B64Image(image),
+        }
+    }
+}
+
+impl From<InputData> for WireInputData {
+    fn from(data: InputData) -> WireInputData {
+        match data {
+            InputData::Text(text) => WireInputData::Text(text),
+            InputData::Image(image) => WireInputData::B64Image(image.into()),
+            InputData::B64Image(image) => WireInputData::B64Image(image),
+        }
+    }
+}
+
+/// The schema of `InputData`, which is the schema of `WireInputData`
+fn wire_input_data() -> Ref {
+    Ref::from_schema_name("InputData")
+}
+
+impl InputData {
+    /// The image of an image input, decoded from base 64 if it has to be, or
+    /// `None` if the input is not an image
+    fn into_image(self) -> Option<Result<Image>> {
+        match self {
+            InputData::Image(image) => Some(Ok(image)),
+            InputData::B64Image(image) => Some(image.try_into()),
+            InputData::Text(_) => None,
+        }
+    }
+}
+
 /// The input to this module's ML engine -- a request for inference
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 pub struct InferenceTask {
+    #[schema(schema_with = wire_input_data)]
     pub data: InputData,
     pub inference_type: InferenceType,
 }
 
+/// How text is generated by `TextToText` inference
+#[derive(Debug, Clone)]
+pub struct Generation {
+    /// Maximum number of tokens generated for a request
+    pub max_new_tokens: usize,
+
+    /// Token that ends the generated text, if any
+    pub end_token: Option<i64>,
+}
+
+impl Generation {
+    pub fn from_config(config: &config::Config) -> Result<Self> {
+        let end_token = config.get_int("worker.end_token")?;
+        Ok(Generation {
+            max_new_tokens: config.get_int("worker.max_new_tokens")? as usize,
+            end_token: (end_token >= 0).then_some(end_token),
+        })
+    }
+}
+
+impl Default for Generation {
+    fn default() -> Self {
+        Generation {
+            max_new_tokens: 256,
+            end_token: None,
+        }
+    }
+}
+
 /// Load and run a TorchScript file
 #[derive(Debug)]
 pub struct TorchModel {
     /// The loaded torch model
     model: tch::jit::CModule,
+    generation: Generation,
 }
 
 impl TorchModel {
     pub fn new(filename: &str) -> Result<Self> {
         Ok(TorchModel {
             model: tch::CModule::load(filename)?,
+            generation: Generation::default(),
         })
     }
 
+    /// Set how text is generated
+    pub fn with_generation(mut self, generation: Generation) -> Self {
+        self.generation = generation;
+        self
+    }
+
     /// Run image classification
-    fn image_classification(&self, image: Image, top_n: u16) -> Result<Inference> {
-        let image = imagenet::load_image_from_memory(&image.image)?;
-        let output = self
-            .model
-            .forward_ts(&[image.unsqueeze(0)])?
+    fn image_classification(&self, image: Image, top_n: u16, emit: Emit) -> Result<Inference> {
+        let image = Self::decode_image(&image)?;
+        emit(InferenceEvent::Progress("decode".into()))?;
+        Ok(self.classify(&[image], &[top_n])?.remove(0))
+    }
+
+    /// Decode an image into a tensor that can be classified
+    fn decode_image(image: &Image) -> Result<Tensor> {
+        info_span!("decode")
+            .in_scope(|| imagenet::load_image_from_memory(&image.image))
+            .map_err(|e| Error::UnsupportedMediaType(e.to_string()))
+    }
+
+    /// Classify decoded images in one forward pass, returning the `top_n[i]`
+    /// most likely classes of the `i`th image
+    fn classify(&self, images: &[Tensor], top_n: &[u16]) -> Result<Vec<Inference>> {
+        let output = info_span!("forward", batch_size = images.len())
+            .in_scope(|| self.model.forward_ts(&[Tensor::stack(images, 0)]))?
             .softmax(-1, Some(tch::kind::Kind::Float));
-        let classes = imagenet::top(&output, top_n as i64)
+        let _encode = info_span!("encode").entered();
+        let inferences = top_n
             .iter()
-            .map(|(p, l)| Class {
-                probability: Some(*p),
-                label: Some(l.into()),
+            .enumerate()
+            .map(|(i, &n)| {
+                let classes = imagenet::top(&output.get(i as i64), n as i64)
+                    .iter()
+                    .map(|(p, l)| Class {
+                        probability: Some(*p),
+                        label: Some(l.into()),
+                    })
+                    .collect();
+                Inference::Classification(classes)
             })
             .collect();
-        Ok(Inference::Classification(classes))
+        Ok(inferences)
     }
 
     /// Run image-to-image inference
-    fn image_to_image(&self, image: Image) -> Result<Inference> {
+    fn image_to_image(&self, image: Image, emit: Emit) -> Result<Inference> {
         // Load image and convert to float tensor
-        let img = tch::vision::image::load_from_memory(&image.image)?;
+        let img = info_span!("decode")
+            .in_scope(|| tch::vision::image::load_from_memory(&image.image))
+            .map_err(|e| Error::UnsupportedMediaType(e.to_string()))?;
         let img = img.to_kind(tch::Kind::Float) / 255.;
+        emit(InferenceEvent::Progress("decode".into()))?;
 
         // Add a batch dimension
         let img = img.unsqueeze(0);
         let img = IValue::Tensor(img);
 
         // Run the model on the image
-        let output = no_grad(|| self.model.forward_is(&[img]))?;
+        let output =
+            info_span!("forward").in_scope(|| no_grad(|| self.model.forward_is(&[img])))?;
         let (_, output) = match output {
             IValue::GenericDict(tensors) => tensors.into_iter().find(|(label, _)| match label {
                 IValue::String(s) => s == "out",
                 _ => false,
             }),
-            _ => {
-                return Err(anyhow!(
-                    "image-to-image inference failed on the forward step"
-                ))
-            }
+            _ => None,
         }
-        .unwrap();
+        .ok_or_else(|| anyhow!("image-to-image inference failed on the forward step"))?;
+        emit(InferenceEvent::Progress("forward".into()))?;
 
         // Extract the tensor
         let output_predictions = match output {
             IValue::Tensor(t) => t.squeeze().argmax(0, false),
-            _ => {
-                return Err(anyhow!(
-                    "image-to-image inference failed to return a tensor"
-                ))
-            }
+            _ => return Err(anyhow!("image-to-image inference failed to return a tensor").into()),
         };
 
+        // Render the output as a PNG
+        let _encode = info_span!("encode").entered();
+
         // Create the palette and colors
         let palette = Tensor::from_slice(&[2i64.pow(25) - 1, 2i64.pow(15) - 1, 2i64.pow(21) - 1]);
         let colors: Tensor =
@@ -206,7 +348,7 @@ impl TorchModel {
 
         // Wrtie tensor to vector
         let mut output_data = vec![];
-        let mut i = output_predictions.view([-1]).iter::<i64>().unwrap();
+        let mut i = output_predictions.view([-1]).iter::<i64>()?;
         while let Some(class_index) = i.next() {
             let color = colors.get(class_index as i64);
             let mut dst = vec![0; 3];
@@ -216,13 +358,14 @@ impl TorchModel {
 
         // Convert tensor to rgb image
         let output_image: ImageBuffer<Rgb<u8>, _> =
-            ImageBuffer::from_raw(width as u32, height as u32, output_data).unwrap();
+            ImageBuffer::from_raw(width as u32, height as u32, output_data)
+                .ok_or_else(|| anyhow!("model output does not fit in an image"))?;
 
         // Write to b64
         let mut image_data: Vec<u8> = Vec::new();
         output_image
             .write_to(&mut Cursor::new(&mut image_data), ImageOutputFormat::Png)
-            .unwrap();
+            .map_err(anyhow::Error::from)?;
         let b64img = base64::encode(image_data);
         Ok(Inference::B64Image(B64Image {
             image: b64img,
@@ -231,53 +374,138 @@ impl TorchModel {
         }))
     }
 
-    /// Not yet supported
-    pub fn text_to_text(&self, task: InferenceTask) -> Result<Inference> {
-        let device = Device::Cpu;
-
-        // Create example input tensor
-        let input_ids = Tensor::from_slice(&[0, 1, 2, 3, 4]).to_device(device);
-        let attention_mask = Tensor::from_slice(&[1, 1, 1, 1, 1]).to_device(device);
-        let token_type_ids = Tensor::from_slice(&[0, 0, 0, 0, 0]).to_device(device);
-
-        // Run the model with no_grad to prevent tracking history
-        let result = no_grad(|| {
-            self.model
-                .forward_ts(&[
-                    input_ids.unsqueeze(0),
-                    attention_mask.unsqueeze(0),
-                    token_type_ids.unsqueeze(0),
-                ])
-                .unwrap()
-        });
-
-        println!("{:?}", result);
+    /// Generate text following a prompt, emitting each token as it is
+    /// generated. The model is a causal language model over UTF-8 bytes: it
+    /// takes token IDs of shape `[1, n]`, and returns logits of shape
+    /// `[1, n, vocab]`. Tokens are chosen greedily, until the end token or
+    /// `max_new_tokens` tokens. A TorchScript model takes no cache of the
+    /// keys and values of past tokens, so the whole sequence is run again for
+    /// each token, and generation takes time quadratic in its length: keep
+    /// `max_new_tokens` small for long prompts
+    fn text_to_text(&self, prompt: &str, emit: Emit) -> Result<Inference> {
+        let mut ids: Vec<i64> = prompt.bytes().map(i64::from).collect();
+        let mut text = vec![];
+
+        // Bytes of a character that is not complete yet
+        let mut pending = vec![];
+
+        for _ in 0..self.generation.max_new_tokens {
+            let input = Tensor::from_slice(&ids).unsqueeze(0);
+            let logits = info_span!("forward", tokens = ids.len())
+                .in_scope(|| no_grad(|| self.model.forward_ts(&[input])))?;
+            let next = logits.get(0).get(-1).argmax(-1, false).int64_value(&[]);
+            if Some(next) == self.generation.end_token {
+                break;
+            }
+            let byte = u8::try_from(next)
+                .map_err(|_| anyhow!("model generated token {next}, which is not a byte"))?;
+            ids.push(next);
+            text.push(byte);
+
+            pending.push(byte);
+            match std::str::from_utf8(&pending) {
+                Ok(token) => emit(InferenceEvent::Token(token.into()))?,
+                Err(e) if e.error_len().is_some() => {
+                    let token = String::from_utf8_lossy(&pending).into_owned();
+                    emit(InferenceEvent::Token(token))?
+                }
+                Err(_) => continue,
+            }
+            pending.clear();
+        }
 
-        todo!()
+        Ok(Inference::Text(String::from_utf8_lossy(&text).into_owned()))
     }
 
     /// Run inference on the loaded model given an `InferenceTask`
     pub fn run(&self, task: InferenceTask) -> Result<(Inference, time::Duration)> {
+        self.run_streaming(task, &mut |_| Ok(()))
+    }
+
+    /// Run inference on the loaded model given an `InferenceTask`, emitting
+    /// progress and generated tokens as it runs
+    pub fn run_streaming(&self, task: InferenceTask, emit: Emit) -> Result<TimedInference> {
         let now = time::Instant::now();
         match task.inference_type {
-            InferenceType::ImageClassification { top_n } => match task.data {
-                InputData::B64Image(image) => Ok((
-                    self.image_classification(image.into(), top_n)?,
+            InferenceType::ImageClassification { top_n } => match task.data.into_image() {
+                Some(image) => Ok((
+                    self.image_classification(image?, top_n, emit)?,
                     now.elapsed(),
                 )),
-                _ => Err(anyhow!(
-                    "invalid input type for ImageClassification inference"
+                None => Err(Error::InvalidInput(
+                    "invalid input type for ImageClassification inference".into(),
                 )),
             },
-            InferenceType::ImageToImage => match task.data {
-                InputData::B64Image(image) => {
-                    Ok((self.image_to_image(image.into())?, now.elapsed()))
-                }
-                _ => Err(anyhow!("invalid input type for ImageToImage inference")),
+            InferenceType::ImageToImage => match task.data.into_image() {
+                Some(image) => Ok((self.image_to_image(image?, emit)?, now.elapsed())),
+                None => Err(Error::InvalidInput(
+                    "invalid input type for ImageToImage inference".into(),
+                )),
+            },
+            InferenceType::TextToText => match task.data {
+                InputData::Text(prompt) => Ok((self.text_to_text(&prompt, emit)?, now.elapsed())),
+                _ => Err(Error::InvalidInput(
+                    "invalid input type for TextToText inference".into(),
+                )),
             },
-            _ => Err(anyhow!("that inference type is not currently supported")),
         }
     }
+
+    /// Run inference on a batch of tasks, returning the outcome of each task
+    /// in order. Image classifications are run through the model in a single
+    /// forward pass. If the model cannot take a batch, they are run one at a
+    /// time instead, as are all other tasks
+    pub fn run_batch(&self, tasks: Vec<InferenceTask>) -> Vec<Result<TimedInference>> {
+        let mut results: Vec<Option<Result<TimedInference>>> =
+            (0..tasks.len()).map(|_| None).collect();
+
+        // Decode the images to classify
+        let (mut indices, mut images, mut top_n) = (vec![], vec![], vec![]);
+        for (i, task) in tasks.into_iter().enumerate() {
+            match task {
+                InferenceTask {
+                    data: data @ (InputData::B64Image(_) | InputData::Image(_)),
+                    inference_type: InferenceType::ImageClassification { top_n: n },
+                } => match data
+                    .into_image()
+                    .expect("the input is an image")
+                    .and_then(|image| Self::decode_image(&image))
+                {
+                    Ok(image) => {
+                        indices.push(i);
+                        images.push(image);
+                        top_n.push(n);
+                    }
+                    Err(e) => results[i] = Some(Err(e)),
+                },
+                task => results[i] = Some(self.run(task)),
+            }
+        }
+
+        if !images.is_empty() {
+            let now = time::Instant::now();
+            match self.classify(&images, &top_n) {
+                Ok(outputs) => {
+                    for (i, output) in indices.into_iter().zip(outputs) {
+                        results[i] = Some(Ok((output, now.elapsed())));
+                    }
+                }
+                Err(e) => {
+                    warn!("batched forward pass failed, classifying one image at a time: {e}");
+                    for ((i, image), n) in indices.into_iter().zip(images).zip(top_n) {
+                        let now = time::Instant::now();
+                        let res = self.classify(&[image], &[n]);
+                        results[i] = Some(res.map(|mut output| (output.remove(0), now.elapsed())));
+                    }
+                }
+            }
+        }
+
+        results
+            .into_iter()
+            .map(|res| res.expect("every task has a result"))
+            .collect()
+    }
 }
 
 impl From<(Vec<Class>, time::Duration)> for rpc::Inference {
@@ -325,93 +553,126 @@ impl From<TimedInference> for rpc::Inference {
     }
 }
 
-impl From<rpc::B64Image> for Image {
-    fn from(image: rpc::B64Image) -> Image {
-        Image {
-            image: base64::decode(image.image).unwrap(),
+impl From<InferenceEvent> for rpc::InferenceEvent {
+    fn from(event: InferenceEvent) -> rpc::InferenceEvent {
+        use rpc::inference_event::Event;
+        let event = match event {
+            InferenceEvent::Progress(stage) => Event::Progress(stage),
+            InferenceEvent::Token(token) => Event::Token(token),
+            InferenceEvent::Done(inference) => Event::Inference(inference.into()),
+        };
+        rpc::InferenceEvent { event: Some(event) }
+    }
+}
+
+impl TryFrom<rpc::B64Image> for Image {
+    type Error = Error;
+
+    fn try_from(image: rpc::B64Image) -> Result<Image> {
+        Ok(Image {
+            image: base64::decode(image.image)?,
             height: image.height,
             width: image.width,
-        }
+        })
     }
 }
 
-impl From<rpc::InferenceTask> for InferenceTask {
-    fn from(task: rpc::InferenceTask) -> InferenceTask {
-        let ty = task.inference_type.clone();
-        match ty.expect("must provide inference type").r#type {
+impl TryFrom<rpc::InferenceTask> for InferenceTask {
+    type Error = Error;
+
+    fn try_from(task: rpc::InferenceTask) -> Result<InferenceTask> {
+        let missing = |what: &str| Error::InvalidInput(format!("must provide {what}"));
+        let ty = task
+            .inference_type
+            .ok_or_else(|| missing("inference type"))?;
+        Ok(match ty.r#type {
             // ImageClassification
             0 => InferenceTask {
-                data: InputData::B64Image(
-                    task.image
-                        .expect("must provide image for ImageClassification inference")
-                        .into(),
-                ),
+                data: input_image(task.image, task.raw_image)
+                    .ok_or_else(|| missing("image for ImageClassification inference"))?,
                 inference_type: InferenceType::ImageClassification {
-                    top_n: task.inference_type.unwrap().top_n.unwrap() as u16,
+                    top_n: ty
+                        .top_n
+                        .ok_or_else(|| missing("top_n for ImageClassification inference"))?
+                        as u16,
                 },
             },
             // ImageToImage
             1 => InferenceTask {
-                data: InputData::B64Image(
-                    task.image
-                        .expect("must provide image for ImageToImage inference")
-                        .into(),
-                ),
+                data: input_image(task.image, task.raw_image)
+                    .ok_or_else(|| missing("image for ImageToImage inference"))?,
                 inference_type: InferenceType::ImageToImage,
             },
             // TextToText
             2 => InferenceTask {
                 data: InputData::Text(
                     task.text
-                        .expect("must provide text for TextToText inference"),
+                        .ok_or_else(|| missing("text for TextToText inference"))?,
                 ),
                 inference_type: InferenceType::TextToText,
             },
-            _ => unreachable!(),
-        }
+            n => {
+                return Err(Error::UnsupportedInference(format!(
+                    "unknown inference type {n}"
+                )))
+            }
+        })
+    }
+}
+
+/// The input of an image task, preferring the raw image over base 64
+fn input_image(image: Option<rpc::B64Image>, raw: Option<rpc::Image>) -> Option<InputData> {
+    match (raw, image) {
+        (Some(raw), _) => Some(InputData::Image(Image {
+            image: raw.image,
+            height: raw.height,
+            width: raw.width,
+        })),
+        (None, Some(image)) => Some(InputData::B64Image(image.into())),
+        (None, None) => None,
     }
 }
 
 impl From<InferenceTask> for rpc::InferenceTask {
     fn from(task: InferenceTask) -> rpc::InferenceTask {
-        match task.inference_type {
-            InferenceType::ImageClassification { top_n } => rpc::InferenceTask {
-                inference_type: Some(rpc::InferenceType {
-                    r#type: 0,
-                    top_n: Some(top_n as u32),
-                }),
-                image: match task.data {
-                    InputData::B64Image(img) => Some(rpc::B64Image {
-                        image: img.image,
-                        height: img.height,
-                        width: img.width,
-                    }),
-                    InputData::Text(_) => None,
-                },
-                text: None,
+        let inference_type = match task.inference_type {
+            InferenceType::ImageClassification { top_n } => rpc::InferenceType {
+                r#type: 0,
+                top_n: Some(top_n as u32),
             },
-            InferenceType::ImageToImage => {
-                rpc::InferenceTask {
-                    inference_type: Some(rpc::InferenceType {
-                        r#type: 1,
-                        top_n: None,
-                    }),
-                    image: match task.data {
-                        InputData::B64Image(img) => Some(rpc::B64Image {
-                            //image: img.image.into(),
-                            image: img.image,
-                            height: img.height,
-                            width: img.width,
-                        }),
-                        InputData::Text(_) => None,
-                    },
-                    text: None,
-                }
+            InferenceType::ImageToImage => rpc::InferenceType {
+                r#type: 1,
+                top_n: None,
+            },
+            InferenceType::TextToText => rpc::InferenceType {
+                r#type: 2,
+                top_n: None,
+            },
+        };
+        let mut rpc_task = rpc::InferenceTask {
+            inference_type: Some(inference_type),
+            image: None,
+            text: None,
+            raw_image: None,
+        };
+        match task.data {
+            InputData::Text(text) => rpc_task.text = Some(text),
+            InputData::Image(img) => {
+                rpc_task.raw_image = Some(rpc::Image {
+                    image: img.image,
+                    height: img.height,
+                    width: img.width,
+                })
             }
-            InferenceType::TextToText => {
-                unimplemented!("inference type TextToText not implemented yet")
+            InputData::B64Image(img) => {
+                rpc_task.image = Some(rpc::B64Image {
+                    image: img.image,
+                    height: img.height,
+                    width: img.width,
+                })
             }
         }
+        rpc_task
     }
 }
 
@@ -433,6 +694,26 @@ mod tests {
     use super::*;
     use crate::util::test;
 
+    #[test]
+    fn test_raw_image() {
+        // A raw image reaches the worker as is, and not in base 64
+        let task = InferenceTask {
+            data: InputData::Image(Image {
+                image: vec![1, 2, 3],
+                height: Some(1),
+                width: None,
+            }),
+            inference_type: InferenceType::ImageToImage,
+        };
+        let rpc_task = rpc::InferenceTask::from(task);
+        assert!(rpc_task.image.is_none());
+        assert_eq!(rpc_task.raw_image.as_ref().unwrap().image, vec![1, 2, 3]);
+
+        let task = InferenceTask::try_from(rpc_task).unwrap();
+        let image = task.data.into_image().unwrap().unwrap();
+        assert_eq!((image.image, image.height), (vec![1, 2, 3], Some(1)));
+    }
+
     #[test]
     fn test_resnet18() {
         let loader = TorchModel::new("models/resnet18.pt".into()).unwrap();
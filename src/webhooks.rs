@@ -0,0 +1,525 @@
+//! Webhook callbacks. When a job with a `callback_url` finishes, the job is
+//! POSTed to that URL, signed with an HMAC of the request so that receivers
+//! can check that it came from this server. Failed deliveries are retried
+//! with exponential backoff, and every delivery is recorded in a bounded log.
+//! Callbacks are only sent to public addresses, unless their host is allowed
+//! in the config, so that clients cannot use them to reach internal services
+
+use crate::error::{Error, Result};
+use crate::jobs::Job;
+use crate::util;
+use config::Config;
+use hmac::{Hmac, Mac};
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::{redirect, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::*;
+use utoipa::ToSchema;
+
+/// Header carrying the signature of a callback: `sha256=<hex digest>`, the
+/// HMAC-SHA256 of `<timestamp>.<body>` keyed with `webhooks.secret`
+pub const SIGNATURE_HEADER: &str = "X-Autodep-Signature";
+
+/// Header carrying the Unix time a callback was sent at
+pub const TIMESTAMP_HEADER: &str = "X-Autodep-Timestamp";
+
+/// How callbacks are signed and retried
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    /// Key of the HMAC signature. Callbacks are not signed if it is empty
+    pub secret: String,
+
+    /// Maximum number of delivery attempts
+    pub max_attempts: u32,
+
+    /// Delay before the first retry. Each retry doubles it
+    pub initial_backoff: Duration,
+
+    /// Maximum delay between retries
+    pub max_backoff: Duration,
+
+    /// Deadline of a delivery attempt
+    pub timeout: Duration,
+
+    /// Number of deliveries kept in the log
+    pub log_size: usize,
+
+    /// Hosts callbacks may be sent to even if they are not public, such as
+    /// receivers on the internal network
+    pub allowed_hosts: Vec<String>,
+}
+
+impl WebhookConfig {
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        let millis = |key| -> anyhow::Result<Duration> {
+            Ok(Duration::from_millis(config.get_int(key)? as u64))
+        };
+        let max_attempts = config.get_int("webhooks.max_attempts")?;
+        if max_attempts < 1 {
+            anyhow::bail!("webhooks.max_attempts must be at least 1");
+        }
+        Ok(WebhookConfig {
+            secret: config.get_string("webhooks.secret")?,
+            max_attempts: max_attempts as u32,
+            initial_backoff: millis("webhooks.initial_backoff")?,
+            max_backoff: millis("webhooks.max_backoff")?,
+            timeout: millis("webhooks.timeout")?,
+            log_size: config.get_int("webhooks.log_size")? as usize,
+            allowed_hosts: config.get("webhooks.allowed_hosts")?,
+        })
+    }
+}
+
+/// The state of a delivery
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, ToSchema)]
+pub enum DeliveryStatus {
+    /// Not delivered yet, but will be attempted again
+    Pending,
+
+    /// Accepted by the receiver
+    Delivered,
+
+    /// Not delivered after every attempt
+    Failed,
+}
+
+/// A delivery attempt
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Attempt {
+    /// Unix time of the attempt
+    pub time: u64,
+
+    /// HTTP status returned by the receiver, if it responded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The delivery of a job to its callback URL
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Delivery {
+    pub id: String,
+    pub job_id: String,
+    pub url: String,
+    pub status: DeliveryStatus,
+    pub attempts: Vec<Attempt>,
+}
+
+/// Delivers callbacks, and keeps a log of the most recent deliveries
+pub struct Webhooks {
+    config: WebhookConfig,
+    client: reqwest::Client,
+    log: Mutex<VecDeque<Delivery>>,
+}
+
+impl Webhooks {
+    pub fn new(config: WebhookConfig) -> anyhow::Result<Self> {
+        // Hosts are resolved again when callbacks are delivered, in case
+        // their addresses changed since they were validated. Redirects are
+        // not followed, as they could lead anywhere
+        let resolver = PublicResolver {
+            allowed_hosts: config.allowed_hosts.clone(),
+        };
+        let client = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .redirect(redirect::Policy::none())
+            .dns_resolver(Arc::new(resolver))
+            .build()?;
+        Ok(Webhooks {
+            client,
+            config,
+            log: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    /// Check that a callback URL can be delivered to: that it is an http or
+    /// https URL, and that its host only resolves to public addresses, unless
+    /// it is in `webhooks.allowed_hosts`
+    pub async fn validate_url(&self, url: &str) -> Result<()> {
+        let invalid = |msg: String| Error::InvalidInput(format!("invalid callback_url: {msg}"));
+        let parsed = reqwest::Url::parse(url).map_err(|e| invalid(e.to_string()))?;
+        match parsed.scheme() {
+            "http" | "https" => {}
+            scheme => {
+                return Err(Error::InvalidInput(format!(
+                    "callback_url must be an http or https URL, not {scheme}"
+                )))
+            }
+        }
+
+        let host = parsed.host_str().ok_or_else(|| invalid("no host".into()))?;
+        if is_allowed(&self.config.allowed_hosts, host) {
+            return Ok(());
+        }
+        let port = parsed.port_or_known_default().unwrap_or(80);
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let addrs = tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|e| invalid(format!("could not resolve {host}: {e}")))?;
+        for addr in addrs {
+            if !is_public(addr.ip()) {
+                return Err(invalid(format!("{host} is not a public address")));
+            }
+        }
+        Ok(())
+    }
+
+    /// Sign a callback body sent at `timestamp`
+    pub fn sign(&self, timestamp: u64, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.config.secret.as_bytes())
+            .expect("HMAC accepts keys of any size");
+        mac.update(format!("{timestamp}.").as_bytes());
+        mac.update(body);
+        let digest = mac.finalize().into_bytes();
+        digest.iter().fold(String::from("sha256="), |mut hex, b| {
+            let _ = write!(hex, "{b:02x}");
+            hex
+        })
+    }
+
+    /// POST a finished job to `url`, retrying with exponential backoff until
+    /// the receiver accepts it or `webhooks.max_attempts` attempts have failed
+    pub async fn deliver(&self, url: String, job: Job) {
+        let body = match serde_json::to_vec(&job) {
+            Ok(body) => body,
+            Err(e) => {
+                error!("failed to serialize job {}: {e}", job.id);
+                return;
+            }
+        };
+        let id = util::random_id();
+        self.log(Delivery {
+            id: id.clone(),
+            job_id: job.id.clone(),
+            url: url.clone(),
+            status: DeliveryStatus::Pending,
+            attempts: vec![],
+        });
+
+        let mut backoff = self.config.initial_backoff;
+        for n in 1..=self.config.max_attempts {
+            let (attempt, retry) = self.attempt(&url, &body).await;
+            let status = match retry {
+                None => DeliveryStatus::Delivered,
+                Some(true) if n < self.config.max_attempts => DeliveryStatus::Pending,
+                Some(_) => DeliveryStatus::Failed,
+            };
+            if let Some(e) = &attempt.error {
+                warn!(
+                    "callback for job {} to {url} failed (attempt {n}): {e}",
+                    job.id
+                );
+            }
+            self.update(&id, attempt, status);
+
+            if status != DeliveryStatus::Pending {
+                info!("callback for job {} to {url}: {status:?}", job.id);
+                return;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(self.config.max_backoff);
+        }
+    }
+
+    /// Make a delivery attempt. Returns the attempt, and whether it should be
+    /// retried if it failed (`None` if it succeeded)
+    async fn attempt(&self, url: &str, body: &[u8]) -> (Attempt, Option<bool>) {
+        let time = util::time();
+        let mut req = self
+            .client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(TIMESTAMP_HEADER, time);
+        if !self.config.secret.is_empty() {
+            req = req.header(SIGNATURE_HEADER, self.sign(time, body));
+        }
+
+        match req.body(body.to_vec()).send().await {
+            Ok(res) if res.status().is_success() => {
+                let attempt = Attempt {
+                    time,
+                    status: Some(res.status().as_u16()),
+                    error: None,
+                };
+                (attempt, None)
+            }
+            Ok(res) => {
+                let status = res.status();
+                let retry = status.is_server_error()
+                    || status == StatusCode::TOO_MANY_REQUESTS
+                    || status == StatusCode::REQUEST_TIMEOUT;
+                let attempt = Attempt {
+                    time,
+                    status: Some(status.as_u16()),
+                    error: Some(format!("receiver responded with {status}")),
+                };
+                (attempt, Some(retry))
+            }
+            Err(e) => {
+                let attempt = Attempt {
+                    time,
+                    status: None,
+                    error: Some(e.to_string()),
+                };
+                (attempt, Some(true))
+            }
+        }
+    }
+
+    /// Add a delivery to the log, dropping the oldest ones if it is full
+    fn log(&self, delivery: Delivery) {
+        let mut log = self.log.lock().unwrap();
+        log.push_back(delivery);
+        while log.len() > self.config.log_size {
+            log.pop_front();
+        }
+    }
+
+    /// Record an attempt of a delivery, if it is still in the log
+    fn update(&self, id: &str, attempt: Attempt, status: DeliveryStatus) {
+        let mut log = self.log.lock().unwrap();
+        if let Some(delivery) = log.iter_mut().find(|d| d.id == id) {
+            delivery.attempts.push(attempt);
+            delivery.status = status;
+        }
+    }
+
+    /// Get the logged deliveries, most recent first, optionally only those of
+    /// one job or with one status
+    pub fn deliveries(
+        &self,
+        job_id: Option<&str>,
+        status: Option<DeliveryStatus>,
+    ) -> Vec<Delivery> {
+        self.log
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|d| job_id.map_or(true, |id| d.job_id == id))
+            .filter(|d| status.map_or(true, |s| d.status == s))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Resolves the hosts of callbacks, leaving out addresses that are not
+/// public, unless the host is allowed
+struct PublicResolver {
+    allowed_hosts: Vec<String>,
+}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let allowed = is_allowed(&self.allowed_hosts, name.as_str());
+        Box::pin(async move {
+            let host = name.as_str();
+            let addrs = tokio::net::lookup_host((host, 0))
+                .await?
+                .filter(|addr| allowed || is_public(addr.ip()))
+                .collect::<Vec<SocketAddr>>();
+            if addrs.is_empty() {
+                return Err(format!("{host} has no public address").into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Whether a host is in `webhooks.allowed_hosts`
+fn is_allowed(allowed_hosts: &[String], host: &str) -> bool {
+    allowed_hosts
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(host))
+}
+
+/// Whether an address is reachable on the internet, rather than private,
+/// loopback, link-local (including cloud metadata services at
+/// 169.254.169.254), or otherwise reserved
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // Shared address space (RFC 6598), and other reserved ranges
+                || (a == 100 && (64..128).contains(&b))
+                || a == 0
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local (fc00::/7) and link-local (fe80::/10)
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jobs::JobStatus;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// The signature and signed payload of each request a receiver got
+    type Received = Arc<Mutex<Vec<(String, String)>>>;
+
+    fn webhooks(max_attempts: u32) -> Webhooks {
+        Webhooks::new(WebhookConfig {
+            secret: "secret".into(),
+            max_attempts,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(5),
+            timeout: Duration::from_secs(5),
+            log_size: 10,
+            allowed_hosts: vec!["127.0.0.1".into()],
+        })
+        .unwrap()
+    }
+
+    fn job() -> Job {
+        Job {
+            id: util::random_id(),
+            status: JobStatus::Completed,
+            created: util::time(),
+            finished: Some(util::time()),
+            result: None,
+            error: None,
+            callback_url: None,
+        }
+    }
+
+    /// Start a receiver that fails the first `failures` requests, and records
+    /// the signature of every request
+    fn receiver(failures: usize) -> (String, Received) {
+        let received = Arc::new(Mutex::new(vec![]));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let log = received.clone();
+        let server = HttpServer::new(move || {
+            let (log, calls) = (log.clone(), calls.clone());
+            App::new().default_service(web::to(move |req: HttpRequest, body: web::Bytes| {
+                let header = |name| {
+                    let value = req.headers().get(name).unwrap();
+                    value.to_str().unwrap().to_string()
+                };
+                let signed = format!(
+                    "{}.{}",
+                    header(TIMESTAMP_HEADER),
+                    String::from_utf8_lossy(&body)
+                );
+                log.lock().unwrap().push((header(SIGNATURE_HEADER), signed));
+
+                let failed = calls.fetch_add(1, Ordering::SeqCst) < failures;
+                async move {
+                    match failed {
+                        true => HttpResponse::ServiceUnavailable().finish(),
+                        false => HttpResponse::Ok().finish(),
+                    }
+                }
+            }))
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let url = format!("http://{}/callback", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+        (url, received)
+    }
+
+    #[actix_web::test]
+    async fn test_delivery_retries() {
+        let (url, received) = receiver(2);
+        let webhooks = webhooks(5);
+        let job = job();
+        webhooks.deliver(url, job.clone()).await;
+
+        let deliveries = webhooks.deliveries(Some(&job.id), None);
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].status, DeliveryStatus::Delivered);
+        let statuses: Vec<_> = deliveries[0].attempts.iter().map(|a| a.status).collect();
+        assert_eq!(statuses, vec![Some(503), Some(503), Some(200)]);
+
+        // Every request is signed
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 3);
+        for (signature, signed) in received.iter() {
+            let (timestamp, body) = signed.split_once('.').unwrap();
+            let expected = webhooks.sign(timestamp.parse().unwrap(), body.as_bytes());
+            assert_eq!(*signature, expected);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_delivery_failure() {
+        let (url, _) = receiver(usize::MAX);
+        let webhooks = webhooks(2);
+        webhooks.deliver(url, job()).await;
+
+        let failed = webhooks.deliveries(None, Some(DeliveryStatus::Failed));
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].attempts.len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn test_validate_url() {
+        let webhooks = webhooks(1);
+        assert!(webhooks
+            .validate_url("https://93.184.216.34/hook")
+            .await
+            .is_ok());
+        assert!(webhooks
+            .validate_url("http://127.0.0.1:8080/")
+            .await
+            .is_ok());
+        assert!(webhooks.validate_url("file:///etc/passwd").await.is_err());
+        assert!(webhooks.validate_url("not a url").await.is_err());
+
+        for url in [
+            "http://169.254.169.254/latest/meta-data/",
+            "http://10.0.0.1/",
+            "http://192.168.1.1/",
+            "http://localhost/",
+            "http://[::1]/",
+            "http://[::ffff:172.16.0.1]/",
+            "http://[fd00::1]/",
+        ] {
+            assert!(webhooks.validate_url(url).await.is_err(), "{url}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_private_delivery() {
+        // Hosts that are not allowed are refused when delivering too
+        let (url, received) = receiver(0);
+        let url = url.replace("127.0.0.1", "localhost");
+        let webhooks = webhooks(1);
+        webhooks.deliver(url, job()).await;
+
+        let failed = webhooks.deliveries(None, Some(DeliveryStatus::Failed));
+        assert_eq!(failed.len(), 1);
+        assert!(received.lock().unwrap().is_empty());
+    }
+}
@@ -1,20 +1,30 @@
 //! An inference worker listens for requests from the `Manager` and computes
 //! model inference in an isolated environment
 
+use crate::error::Error;
 use crate::rpc;
+use crate::rpc::batch_result;
 use crate::rpc::worker_server::{self, WorkerServer};
+use crate::telemetry;
 use crate::torch;
 
+use crate::util;
+use crate::worker_auth::WorkerToken;
 use serde::Serialize;
-use std::sync::atomic::AtomicU64;
+use std::collections::VecDeque;
 use std::sync::atomic::Ordering;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::task::JoinError;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::metadata::MetadataMap;
 use tonic::transport::Server;
-use tonic::{Request, Response};
+use tonic::{Request, Response, Status};
 use tracing::*;
 
-type Result<T> = std::result::Result<T, tonic::Status>;
+type Result<T> = std::result::Result<T, Status>;
 
 /// The current status of a worker
 #[derive(Debug, Clone, Serialize, PartialEq)]
@@ -30,6 +40,10 @@ pub enum WorkerStatus {
 
     /// In an error state
     Error,
+
+    /// Stopped. Only reported for recently stopped workers, so that their
+    /// logs can be found
+    Dead,
 }
 
 /// A worker runs as a separate process, spawned by the resource manager.
@@ -39,27 +53,260 @@ pub enum WorkerStatus {
 pub struct Worker {
     model: Arc<torch::TorchModel>,
     port: u16,
+    counters: Arc<Counters>,
+    started: Instant,
+    model_load_time: Duration,
+
+    /// Cleared once the model panics, after which its state cannot be
+    /// trusted, and the worker reports it as no longer loaded
+    model_loaded: Arc<AtomicBool>,
+}
+
+/// Number of events of a streamed inference buffered for the manager
+const STREAM_BUFFER: usize = 32;
+
+/// The requests a worker has served
+#[derive(Debug, Default)]
+struct Counters {
     reqs_served: AtomicU64,
+    failures: AtomicU64,
+    latencies: Mutex<Latencies>,
+
+    /// Unix time of the last request, or 0 if no requests have been served
+    last_request: AtomicU64,
+}
+
+impl Counters {
+    /// Record that a request was received
+    fn start(&self) {
+        self.last_request.store(util::time(), Ordering::SeqCst);
+    }
+
+    /// Record the outcome of a request: its latency if it succeeded, or
+    /// `None` if it failed
+    fn finish(&self, latency: Option<Duration>) {
+        match latency {
+            Some(latency) => {
+                self.reqs_served.fetch_add(1, Ordering::SeqCst);
+                self.latencies.lock().unwrap().record(latency.as_secs_f32());
+            }
+            None => {
+                self.failures.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Record the outcome of a batch of requests, which ran together in
+    /// `latency`. The batch counts as a single latency sample, as its
+    /// requests did not each take that long
+    fn finish_batch(&self, served: u64, failed: u64, latency: Duration) {
+        self.reqs_served.fetch_add(served, Ordering::SeqCst);
+        self.failures.fetch_add(failed, Ordering::SeqCst);
+        if served > 0 {
+            self.latencies.lock().unwrap().record(latency.as_secs_f32());
+        }
+    }
+}
+
+/// Number of recent requests that latency statistics are computed over
+const LATENCY_WINDOW: usize = 1000;
+
+/// The inference latencies of the most recent requests, in seconds
+#[derive(Debug, Default)]
+struct Latencies {
+    recent: VecDeque<f32>,
+}
+
+impl Latencies {
+    fn record(&mut self, latency: f32) {
+        if self.recent.len() >= LATENCY_WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(latency);
+    }
+
+    fn mean(&self) -> f32 {
+        if self.recent.is_empty() {
+            return 0.0;
+        }
+        self.recent.iter().sum::<f32>() / self.recent.len() as f32
+    }
+
+    /// Get the `p`th percentile latency, using the nearest-rank method
+    fn percentile(&self, p: f32) -> f32 {
+        if self.recent.is_empty() {
+            return 0.0;
+        }
+        let mut sorted = self.recent.iter().copied().collect::<Vec<_>>();
+        sorted.sort_by(f32::total_cmp);
+        let rank = (p / 100.0 * sorted.len() as f32).ceil() as usize;
+        sorted[rank.clamp(1, sorted.len()) - 1]
+    }
 }
 
 impl Worker {
-    pub fn new(model_file: &str, port: u16) -> anyhow::Result<Self> {
+    pub fn new(model_file: &str, port: u16, generation: torch::Generation) -> anyhow::Result<Self> {
+        let now = Instant::now();
+        let model = torch::TorchModel::new(model_file)?.with_generation(generation);
         Ok(Worker {
-            model: Arc::new(torch::TorchModel::new(model_file.into())?),
+            model: Arc::new(model),
             port,
-            reqs_served: AtomicU64::new(0),
+            counters: Arc::new(Counters::default()),
+            started: Instant::now(),
+            model_load_time: now.elapsed(),
+            model_loaded: Arc::new(AtomicBool::new(true)),
         })
     }
 
-    /// Start listening for requests
-    // #[tracing::instrument]
-    pub async fn start(self) -> anyhow::Result<()> {
+    /// Compute inference on this worker, and record its outcome
+    async fn compute(&self, task: rpc::InferenceTask) -> Result<torch::TimedInference> {
+        info!("worker got inference request");
+        self.counters.start();
+
+        let res = self.run(task).await.map_err(|e| {
+            warn!("worker failed to compute inference: {e}");
+            self.counters.finish(None);
+            e
+        })?;
+
+        info!("worker successfully computed inference: {res:?}");
+        self.counters.finish(Some(res.1));
+        Ok(res)
+    }
+
+    /// Compute inference on this worker in the background, sending its events
+    /// to the returned stream as it runs, and record its outcome. The
+    /// inference stops once the stream is dropped, or once `timeout` has
+    /// passed, as the deadline of the request does not cover its stream
+    fn compute_streaming(
+        &self,
+        task: rpc::InferenceTask,
+        timeout: Option<Duration>,
+    ) -> crate::error::Result<ReceiverStream<Result<rpc::InferenceEvent>>> {
+        info!("worker got streaming inference request");
+        self.counters.start();
+        let task: torch::InferenceTask = task.try_into().inspect_err(|e| {
+            warn!("worker failed to compute inference: {e}");
+            self.counters.finish(None);
+        })?;
+
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        let (model, counters) = (self.model.clone(), self.counters.clone());
+        let span = Span::current();
+        let start = Instant::now();
+        let overran = move || timeout.filter(|timeout| start.elapsed() >= *timeout);
+        let run = tokio::task::spawn_blocking(move || {
+            span.in_scope(|| {
+                let events = tx.clone();
+                let mut res = model.run_streaming(task, &mut |event| {
+                    if let Some(timeout) = overran() {
+                        return Err(Error::Timeout(timeout));
+                    }
+                    events
+                        .blocking_send(Ok(event.into()))
+                        .map_err(|_| Error::Other(anyhow::anyhow!("the stream was closed")))
+                });
+                if let (Ok(_), Some(timeout)) = (&res, overran()) {
+                    res = Err(Error::Timeout(timeout));
+                }
+                if tx.is_closed() {
+                    info!("streaming inference was abandoned");
+                    return;
+                }
+
+                let last = match res {
+                    Ok(inference) => {
+                        info!("worker successfully computed inference: {inference:?}");
+                        counters.finish(Some(inference.1));
+                        Ok(torch::InferenceEvent::Done(inference).into())
+                    }
+                    Err(e) => {
+                        warn!("worker failed to compute inference: {e}");
+                        counters.finish(None);
+                        Err(e.into())
+                    }
+                };
+                let _ = tx.blocking_send(last);
+            })
+        });
+        let model_loaded = self.model_loaded.clone();
+        tokio::spawn(async move {
+            if let Err(e) = run.await {
+                panicked(&model_loaded, e);
+            }
+        });
+        Ok(ReceiverStream::new(rx))
+    }
+
+    /// Compute inference on a batch of tasks, and record the outcome of each
+    async fn compute_tasks(
+        &self,
+        tasks: Vec<rpc::InferenceTask>,
+    ) -> Result<Vec<crate::error::Result<torch::TimedInference>>> {
+        info!("worker got a batch of {} tasks", tasks.len());
+        self.counters.start();
+
+        // Parse the tasks, and run the valid ones in one batch
+        let mut valid = vec![];
+        let parsed = tasks
+            .into_iter()
+            .map(|task| {
+                let task: torch::InferenceTask = task.try_into()?;
+                valid.push(task);
+                Ok(())
+            })
+            .collect::<Vec<crate::error::Result<()>>>();
+        let model = self.model.clone();
+        let span = Span::current();
+        let start = Instant::now();
+        let mut outputs =
+            tokio::task::spawn_blocking(move || span.in_scope(|| model.run_batch(valid)))
+                .await
+                .map_err(|e| panicked(&self.model_loaded, e))?
+                .into_iter();
+        let latency = start.elapsed();
+
+        let results = parsed
+            .into_iter()
+            .map(|res| res.and_then(|()| outputs.next().expect("every task has a result")))
+            .collect::<Vec<_>>();
+        let mut failed = 0;
+        for e in results.iter().filter_map(|res| res.as_ref().err()) {
+            warn!("worker failed to compute inference: {e}");
+            failed += 1;
+        }
+        let served = results.len() as u64 - failed;
+        self.counters.finish_batch(served, failed, latency);
+        Ok(results)
+    }
+
+    /// Run the model on a task
+    async fn run(&self, task: rpc::InferenceTask) -> Result<torch::TimedInference> {
+        // Parse input request
+        let task: torch::InferenceTask = task.try_into()?;
+        debug!("task: {:?}", task);
+
+        // Run model inference off the async runtime, so that it does not hold
+        // up other requests. A forward pass cannot be interrupted: when the
+        // deadline expires, the client gets its error, but the model still
+        // runs to completion
+        let model = self.model.clone();
+        let span = Span::current();
+        let res = tokio::task::spawn_blocking(move || span.in_scope(|| model.run(task)))
+            .await
+            .map_err(|e| panicked(&self.model_loaded, e))??;
+        Ok(res)
+    }
+
+    /// Start listening for requests, accepting only those that carry `token`
+    #[tracing::instrument(skip(self, token), fields(port = self.port))]
+    pub async fn start(self, token: WorkerToken) -> anyhow::Result<()> {
         info!(
             "starting new worker on port {} with model {:?}",
             self.port, self.model
         );
         let addr = format!("[::1]:{}", self.port).parse().unwrap();
-        let svc = WorkerServer::new(self);
+        let svc = WorkerServer::with_interceptor(self, token.verifier());
         Server::builder()
             .tcp_keepalive(Some(Duration::from_millis(1000)))
             .concurrency_limit_per_connection(32)
@@ -77,22 +324,166 @@ impl worker_server::Worker for Worker {
         &self,
         request: Request<rpc::InferenceTask>,
     ) -> Result<Response<rpc::Inference>> {
-        info!("worker got inference request");
-        // Parse input request
-        let task: torch::InferenceTask = request.into_inner().into();
-        debug!("task: {:?}", task);
-
-        // Run model inference
-        let model = self.model.clone();
-        let res = model.run(task).unwrap();
+        let span = info_span!(
+            "compute_inference",
+            request_id = telemetry::rpc_request_id(request.metadata())
+        );
+        telemetry::set_parent(&span, request.metadata());
 
-        info!("worker successfully computed inference: {res:?}");
-        self.reqs_served.fetch_add(1, Ordering::SeqCst);
+        let res = self.compute(request.into_inner()).instrument(span).await?;
         Ok(Response::new(res.into()))
     }
 
+    type ComputeInferenceStreamStream = ReceiverStream<Result<rpc::InferenceEvent>>;
+
+    /// Handle requests for inference that stream the progress of the
+    /// inference, and the text it generates, before its result
+    async fn compute_inference_stream(
+        &self,
+        request: Request<rpc::InferenceTask>,
+    ) -> Result<Response<Self::ComputeInferenceStreamStream>> {
+        let span = info_span!(
+            "compute_inference_stream",
+            request_id = telemetry::rpc_request_id(request.metadata())
+        );
+        telemetry::set_parent(&span, request.metadata());
+
+        let timeout = grpc_timeout(request.metadata());
+        let stream = span.in_scope(|| self.compute_streaming(request.into_inner(), timeout))?;
+        Ok(Response::new(stream))
+    }
+
+    /// Handle requests for inference on a batch of tasks. A task that fails
+    /// does not fail the others: its error is returned in its place
+    async fn compute_batch(
+        &self,
+        request: Request<rpc::InferenceBatch>,
+    ) -> Result<Response<rpc::BatchResults>> {
+        let span = info_span!(
+            "compute_batch",
+            request_id = telemetry::rpc_request_id(request.metadata())
+        );
+        telemetry::set_parent(&span, request.metadata());
+
+        let tasks = request.into_inner().tasks;
+        let results = self.compute_tasks(tasks).instrument(span).await?;
+        let results = results
+            .into_iter()
+            .map(|res| rpc::BatchResult {
+                result: Some(match res {
+                    Ok(inference) => batch_result::Result::Inference(inference.into()),
+                    Err(e) => batch_result::Result::Error(e.into()),
+                }),
+            })
+            .collect();
+        Ok(Response::new(rpc::BatchResults { results }))
+    }
+
     async fn get_stats(&self, _req: Request<rpc::Empty>) -> Result<Response<rpc::Stats>> {
-        let reqs_served = self.reqs_served.load(Ordering::SeqCst);
-        Ok(Response::new(rpc::Stats { reqs_served }))
+        let pid = std::process::id();
+        let counters = &self.counters;
+        let latencies = counters.latencies.lock().unwrap();
+        let last_request = counters.last_request.load(Ordering::SeqCst);
+        Ok(Response::new(rpc::Stats {
+            reqs_served: counters.reqs_served.load(Ordering::SeqCst),
+            failures: counters.failures.load(Ordering::SeqCst),
+            avg_latency: latencies.mean(),
+            p50_latency: latencies.percentile(50.0),
+            p95_latency: latencies.percentile(95.0),
+            p99_latency: latencies.percentile(99.0),
+            uptime: self.started.elapsed().as_secs_f32(),
+            model_load_time: self.model_load_time.as_secs_f32(),
+            rss: util::rss(pid).unwrap_or(0),
+            threads: util::threads(pid).unwrap_or(0),
+            last_request: (last_request > 0).then_some(last_request),
+        }))
+    }
+
+    /// A worker only starts serving once its model has been loaded, so its
+    /// model is loaded unless it has since panicked
+    async fn get_health(&self, _req: Request<rpc::Empty>) -> Result<Response<rpc::Health>> {
+        let model_loaded = self.model_loaded.load(Ordering::SeqCst);
+        Ok(Response::new(rpc::Health { model_loaded }))
+    }
+}
+
+/// Record that the model panicked while computing inference
+fn panicked(model_loaded: &AtomicBool, e: JoinError) -> Status {
+    error!("the model panicked, reporting it as unhealthy: {e}");
+    model_loaded.store(false, Ordering::SeqCst);
+    Status::internal(e.to_string())
+}
+
+/// Get the deadline the manager set on a request, from its `grpc-timeout`
+/// metadata: an integer of up to 8 digits, followed by its unit
+fn grpc_timeout(metadata: &MetadataMap) -> Option<Duration> {
+    let timeout = metadata.get("grpc-timeout")?.to_str().ok()?;
+    if timeout.len() < 2 || timeout.len() > 9 {
+        return None;
+    }
+    let (value, unit) = timeout.split_at(timeout.len() - 1);
+    let value: u64 = value.parse().ok()?;
+    let timeout = match unit {
+        "H" => Duration::from_secs(value * 60 * 60),
+        "M" => Duration::from_secs(value * 60),
+        "S" => Duration::from_secs(value),
+        "m" => Duration::from_millis(value),
+        "u" => Duration::from_micros(value),
+        "n" => Duration::from_nanos(value),
+        _ => return None,
+    };
+    Some(timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_percentiles() {
+        let mut latencies = Latencies::default();
+        assert_eq!(latencies.percentile(50.0), 0.0);
+
+        (1..=100).rev().for_each(|l| latencies.record(l as f32));
+        assert_eq!(latencies.mean(), 50.5);
+        assert_eq!(latencies.percentile(50.0), 50.0);
+        assert_eq!(latencies.percentile(99.0), 99.0);
+        assert_eq!(latencies.percentile(100.0), 100.0);
+    }
+
+    #[test]
+    fn test_batch_latency() {
+        // A batch is a single latency sample, however many requests it served
+        let counters = Counters::default();
+        counters.finish_batch(8, 2, Duration::from_secs(2));
+        counters.finish(Some(Duration::from_secs(1)));
+        assert_eq!(counters.reqs_served.load(Ordering::SeqCst), 9);
+        assert_eq!(counters.failures.load(Ordering::SeqCst), 2);
+        assert_eq!(counters.latencies.lock().unwrap().mean(), 1.5);
+
+        counters.finish_batch(0, 3, Duration::from_secs(10));
+        assert_eq!(counters.latencies.lock().unwrap().recent.len(), 2);
+    }
+
+    #[test]
+    fn test_grpc_timeout() {
+        let timeout = |value: &str| {
+            let mut metadata = MetadataMap::new();
+            metadata.insert("grpc-timeout", value.parse().unwrap());
+            grpc_timeout(&metadata)
+        };
+        assert_eq!(timeout("1500m"), Some(Duration::from_millis(1500)));
+        assert_eq!(timeout("2S"), Some(Duration::from_secs(2)));
+        assert_eq!(timeout("3H"), Some(Duration::from_secs(3 * 60 * 60)));
+        assert_eq!(timeout("100"), None);
+        assert_eq!(timeout("123456789m"), None);
+        assert_eq!(grpc_timeout(&MetadataMap::new()), None);
+    }
+
+    #[test]
+    fn test_latency_window() {
+        let mut latencies = Latencies::default();
+        (0..LATENCY_WINDOW + 10).for_each(|_| latencies.record(1.0));
+        assert_eq!(latencies.recent.len(), LATENCY_WINDOW);
     }
 }
@@ -0,0 +1,112 @@
+//! Authentication of the manager to its workers. The manager generates a
+//! random token when it starts, and hands it to each worker it spawns through
+//! the environment, so that it does not show up in the process list. Every RPC
+//! the manager sends carries the token in its metadata, and workers refuse
+//! requests without it, so that other processes cannot use them
+
+use crate::util;
+use anyhow::{anyhow, Result};
+use tonic::codegen::InterceptedService;
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::service::Interceptor;
+use tonic::transport::Channel;
+use tonic::{Request, Status};
+
+/// Environment variable a worker's token is passed in
+pub const TOKEN_ENV: &str = "AUTODEP_WORKER_TOKEN";
+
+/// Metadata key of the token in requests to a worker
+pub const TOKEN_METADATA: &str = "x-autodep-worker-token";
+
+/// A channel to a worker, whose requests carry the manager's token
+pub type WorkerChannel = InterceptedService<Channel, WorkerToken>;
+
+/// The secret shared by the manager and its workers
+#[derive(Clone)]
+pub struct WorkerToken(MetadataValue<Ascii>);
+
+impl WorkerToken {
+    /// Generate a random 256 bit token
+    pub fn generate() -> WorkerToken {
+        let token = format!("{}{}", util::random_id(), util::random_id());
+        WorkerToken(token.parse().unwrap())
+    }
+
+    /// Read the token the manager passed to this worker
+    pub fn from_env() -> Result<WorkerToken> {
+        let token = std::env::var(TOKEN_ENV)
+            .map_err(|_| anyhow!("{TOKEN_ENV} is not set, workers must be started by a manager"))?;
+        if token.is_empty() {
+            return Err(anyhow!("{TOKEN_ENV} is empty"));
+        }
+        Ok(WorkerToken(token.parse()?))
+    }
+
+    /// The token, to pass to a worker in `TOKEN_ENV`
+    pub fn secret(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+
+    /// Attach the token to the requests sent on `channel`
+    pub fn channel(&self, channel: Channel) -> WorkerChannel {
+        InterceptedService::new(channel, self.clone())
+    }
+
+    /// The interceptor a worker checks incoming requests with
+    pub fn verifier(&self) -> VerifyToken {
+        VerifyToken(self.clone())
+    }
+}
+
+impl std::fmt::Debug for WorkerToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "WorkerToken(..)")
+    }
+}
+
+/// Adds the token to outgoing requests
+impl Interceptor for WorkerToken {
+    fn call(&mut self, mut req: Request<()>) -> std::result::Result<Request<()>, Status> {
+        req.metadata_mut().insert(TOKEN_METADATA, self.0.clone());
+        Ok(req)
+    }
+}
+
+/// Rejects incoming requests that do not carry the token
+#[derive(Debug, Clone)]
+pub struct VerifyToken(WorkerToken);
+
+impl Interceptor for VerifyToken {
+    fn call(&mut self, req: Request<()>) -> std::result::Result<Request<()>, Status> {
+        let valid = req
+            .metadata()
+            .get(TOKEN_METADATA)
+            .is_some_and(|token| util::constant_time_eq(token.as_bytes(), self.0 .0.as_bytes()));
+        if !valid {
+            return Err(Status::unauthenticated("invalid worker token"));
+        }
+        Ok(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token() {
+        let mut token = WorkerToken::generate();
+        assert_eq!(token.secret().len(), 64);
+        assert_ne!(token.secret(), WorkerToken::generate().secret());
+
+        let mut verifier = token.verifier();
+        let req = token.call(Request::new(())).unwrap();
+        assert!(verifier.call(req).is_ok());
+
+        let err = verifier.call(Request::new(())).unwrap_err();
+        assert_eq!(err.code(), tonic::Code::Unauthenticated);
+
+        let req = WorkerToken::generate().call(Request::new(())).unwrap();
+        assert!(verifier.call(req).is_err());
+    }
+}
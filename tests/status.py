@@ -5,7 +5,7 @@ curr = -1
 while True:
     res = requests.get("http://localhost:9000/workers/_status")
     status = res.json()
-    count = Counter(status.values())
+    count = Counter(s['status'] for s in status.values())
     working = count['Working']
     if working != curr:
         curr = working